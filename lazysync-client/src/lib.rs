@@ -1,21 +1,50 @@
+use fs2::FileExt;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
     fs,
-    io::{BufRead, BufReader, Write},
+    hash::{BuildHasher, Hasher},
+    io::{BufRead, BufReader, Read, Write},
     net::TcpStream,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
     thread,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
+
+pub mod lazysync {
+    tonic::include_proto!("lazysync");
+}
+
+use lazysync::{
+    lazy_sync_client::LazySyncClient, ChecksumRequest, CopyProgress, CopyRequest, GetPathRequest,
+    HealthRequest, ListMountsRequest, ReadFileRequest, RealPathRequest, SearchRequest,
+    StatBatchRequest, StatRequest, SyncPathRequest, WatchInvalidationsRequest, WriteFileChunk,
 };
-use tokio::sync::oneshot;
 
 // ===== 协议结构 =====
 #[derive(Serialize)]
 pub struct Request {
     pub id: u64,
     pub path: String,
+    // 用于把前端请求和服务器日志对应起来
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+// 随机生成 16 位十六进制的 trace id
+pub fn new_trace_id() -> String {
+    format!("{:016x}", RandomState::new().build_hasher().finish())
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -27,6 +56,14 @@ pub struct FileInfo {
     pub absolute_path: String,
     pub modified: String,
     pub size: u64,
+    #[serde(default = "default_size_known")]
+    pub size_known: bool,
+    #[serde(default)]
+    pub setuid: bool,
+    #[serde(default)]
+    pub setgid: bool,
+    #[serde(default)]
+    pub sticky: bool,
 }
 
 // FileEntry 用于 cache 和 API 响应
@@ -37,8 +74,253 @@ pub struct FileEntry {
     #[serde(default)]
     pub file_type: String,
     pub size: u64,
+    // size 是否为真实的内容长度；符号链接、设备文件等为 false，UI 应显示 "—" 而不是 "0 B"
+    #[serde(default = "default_size_known")]
+    pub size_known: bool,
     pub permissions: String,
     pub modified: String,
+    // setuid / setgid / sticky 位；旧服务器和旧 cache 没有这些字段时由 normalize_entry 从权限字符串推出
+    #[serde(default)]
+    pub setuid: bool,
+    #[serde(default)]
+    pub setgid: bool,
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+// 旧服务器和旧 cache 没有 size_known 字段，按类型在 normalize_entry 里再判断
+fn default_size_known() -> bool {
+    true
+}
+
+// 两个同名条目之间的一项变化
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum EntryChange {
+    Size { old: u64, new: u64 },
+    Modified { old: String, new: String },
+    Permissions { old: String, new: String },
+    FileType { old: String, new: String },
+}
+
+impl FileEntry {
+    // other 为旧版本，返回 self 相对它的变化；只比较能反映内容/元数据变化的字段
+    // name 和由 file_type 推出来的 is_dir 不参与比较
+    pub fn changes_from(&self, other: &FileEntry) -> Vec<EntryChange> {
+        let mut changes = Vec::new();
+        if self.file_type != other.file_type {
+            changes.push(EntryChange::FileType {
+                old: other.file_type.clone(),
+                new: self.file_type.clone(),
+            });
+        }
+        if self.size != other.size {
+            changes.push(EntryChange::Size {
+                old: other.size,
+                new: self.size,
+            });
+        }
+        if self.modified != other.modified {
+            changes.push(EntryChange::Modified {
+                old: other.modified.clone(),
+                new: self.modified.clone(),
+            });
+        }
+        if self.permissions != other.permissions {
+            changes.push(EntryChange::Permissions {
+                old: other.permissions.clone(),
+                new: self.permissions.clone(),
+            });
+        }
+        changes
+    }
+
+    pub fn changed_from(&self, other: &FileEntry) -> bool {
+        !self.changes_from(other).is_empty()
+    }
+}
+
+// 服务器 health RPC 的详细结果
+#[derive(Serialize, Debug, Clone)]
+pub struct ServerHealth {
+    pub status: String,
+    pub uptime_secs: u64,
+    pub active_streams: u64,
+    pub version: String,
+    pub read_only: bool,
+    pub no_follow_symlinks: bool,
+    // 服务器进程的有效身份；只有 Linux 服务器会报告，其他为 None
+    pub identity: Option<ServerIdentity>,
+}
+
+// 服务器进程的有效 uid / gid 和附加组，用于预测权限
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServerIdentity {
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<u32>,
+}
+
+impl ServerIdentity {
+    // 按 owner / group / other 的顺序选一组权限位（与内核相同：匹配到 owner 就不再看 group），
+    // 检查其中的 w（need_exec 时还要 x）；root 总是可以
+    fn can_write(&self, info: &lazysync::FileInfo, need_exec: bool) -> bool {
+        if self.uid == 0 {
+            return true;
+        }
+        let slot = if info.uid == self.uid {
+            1
+        } else if info.gid == self.gid || self.groups.contains(&info.gid) {
+            4
+        } else {
+            7
+        };
+        let perms: Vec<char> = info.permissions.chars().collect();
+        let write = perms.get(slot + 1) == Some(&'w');
+        let exec = matches!(perms.get(slot + 2), Some('x' | 's' | 't'));
+        write && (exec || !need_exec)
+    }
+}
+
+// 带截止时间的目录列表；timed_out（超过截止时间）或 truncated（超过服务器的响应大小上限）
+// 为 true 时 entries 只是已扫描到的部分，UI 可显示“加载中”
+#[derive(Serialize, Debug, Clone)]
+pub struct PartialListing {
+    pub entries: Vec<FileEntry>,
+    pub timed_out: bool,
+    pub truncated: bool,
+    pub entries_scanned: u64,
+}
+
+// 服务器解析出的规范绝对路径；路径不存在时 exists 为 false，canonical 只做了 . / .. 的折叠
+#[derive(Serialize, Debug, Clone)]
+pub struct ResolvedPath {
+    pub canonical: String,
+    pub exists: bool,
+}
+
+// 服务器端 glob 搜索的结果，matches 为 (绝对路径, 条目)；truncated 为 true 时还有更多匹配
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchResults {
+    pub matches: Vec<(String, FileEntry)>,
+    pub truncated: bool,
+}
+
+// 服务器上的一个挂载点，用于“此电脑 / 卷”式的根视图；无法查询容量时 total_bytes 和 free_bytes 为 0
+#[derive(Serialize, Debug, Clone)]
+pub struct Mount {
+    pub device: String,
+    pub mount_point: String,
+    pub fs_type: String,
+    pub total_bytes: u64,
+    // 普通用户可用的空间
+    pub free_bytes: u64,
+    pub read_only: bool,
+}
+
+// Client::mirror 的选项，默认全部关闭
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MirrorOptions {
+    // 大小相同的文件改为比较 SHA-256，而不是 mtime（服务器需要读完整个文件）
+    pub verify_content: bool,
+    // 删除本地有、服务器上没有的文件和目录
+    pub delete_extra: bool,
+    // 只比较并给出计划，不下载也不删除
+    pub dry_run: bool,
+}
+
+// Client::get_dir 的结果。JSON/TCP 协议不报告响应是否被截断，所以这里没有 truncated；
+// 需要知道列表是否完整时用 get_path_within（走 gRPC）
+#[derive(Serialize, Debug, Clone)]
+pub struct DirListing {
+    pub path: String,
+    // 目录本身的条目信息（权限、修改时间等），父目录列表里找不到时为 None
+    pub self_info: Option<FileEntry>,
+    pub entries: Vec<FileEntry>,
+    // 直接子项中普通文件的大小之和，不递归
+    pub total_size: u64,
+    pub entry_count: usize,
+}
+
+impl DirListing {
+    fn new(path: String, self_info: Option<FileEntry>, entries: Vec<FileEntry>) -> Self {
+        let total_size = entries
+            .iter()
+            .filter(|entry| entry.file_type == "file")
+            .map(|entry| entry.size)
+            .sum();
+        Self {
+            path,
+            self_info,
+            entry_count: entries.len(),
+            entries,
+            total_size,
+        }
+    }
+}
+
+// get_path / get_dir 的结果来源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GetPathSource {
+    Cache,
+    Network,
+}
+
+// 每次 get_path / get_dir 结束时交给 ClientBuilder::on_get_path 注册的回调
+#[derive(Debug, Clone)]
+pub struct GetPathEvent {
+    pub path: String,
+    pub duration: Duration,
+    pub source: GetPathSource,
+    pub entry_count: usize,
+    pub error: Option<String>,
+}
+
+pub type GetPathHook = Arc<dyn Fn(&GetPathEvent) + Send + Sync>;
+
+// Client::cache_stats 的返回值：get_path / get_dir 命中和未命中 cache 的次数，以及 cache 中的目录数
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+// Client::snapshot_cache 的结果：内存中 cache 的完整运行状态，可序列化后由调用方自行保存，
+// 重启后用 restore_cache 恢复，与 cache 文件无关
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CacheSnapshot {
+    pub listings: CacheData,
+    // 访问顺序（用于淘汰），最近访问的在前
+    pub order: Vec<String>,
+    pub hits: u64,
+    pub misses: u64,
+    // 最新的在前
+    pub recent_paths: Vec<String>,
+}
+
+// mirror 中对单个路径的操作：下载/跳过时为远程路径，删除时为本地路径
+#[derive(Serialize, Debug, Clone)]
+pub struct MirrorAction {
+    pub path: String,
+    pub reason: String,
+}
+
+// mirror 的结果；dry_run 时是计划执行的操作，否则是已经执行的操作
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct MirrorReport {
+    pub to_download: Vec<MirrorAction>,
+    pub to_delete: Vec<MirrorAction>,
+    pub to_skip: Vec<MirrorAction>,
+    // 实际下载的字节数，dry_run 时为 0
+    pub bytes: u64,
+    pub dry_run: bool,
+}
+
+// read_text 的结果：解码后的文本和检测到的编码名（如 "UTF-8"、"UTF-16LE"、"windows-1252"）
+#[derive(Serialize, Debug, Clone)]
+pub struct DecodedText {
+    pub text: String,
+    pub encoding: String,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -48,12 +330,194 @@ pub struct Response {
     pub data: Vec<HashMap<String, Vec<FileInfo>>>,
 }
 
+// ===== 错误类型 =====
+#[derive(Debug)]
+pub enum ClientError {
+    // 底层请求失败（写请求出错、服务器返回的错误信息等）
+    Request(String),
+    // 无法建立 gRPC 连接
+    Connect(tonic::transport::Error),
+    // gRPC 调用返回错误状态
+    Rpc(tonic::Status),
+    // 等待超时
+    Timeout { path: String, elapsed: Duration },
+    // 路径在服务器上不存在
+    NotFound(String),
+    // 服务器返回的行无法解析（包含解析错误和原始内容），或响应缺少预期的内容
+    BadResponse(String),
+    // JSON 序列化或反序列化失败
+    Serde(serde_json::Error),
+    // 调用参数不合法（例如未知的文件类型）
+    InvalidArgument(String),
+    // Client 已被 close，等待中的和之后的请求都会得到这个错误
+    Closed,
+    // 等待响应时与服务器的连接断开；下次请求会按 reconnect_policy 重新连接
+    Disconnected,
+    // 本地读写失败（例如上传时读取数据源出错）
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Request(msg) => write!(f, "{}", msg),
+            ClientError::Connect(err) => write!(f, "Connect error: {}", err),
+            ClientError::Rpc(status) => write!(f, "RPC error: {}", status.message()),
+            ClientError::Timeout { path, elapsed } => {
+                write!(f, "Timeout after {:?} waiting for {}", elapsed, path)
+            }
+            ClientError::Io(err) => write!(f, "IO error: {}", err),
+            ClientError::NotFound(path) => write!(f, "Path not found: {}", path),
+            ClientError::BadResponse(msg) => write!(f, "Bad response: {}", msg),
+            ClientError::Serde(err) => write!(f, "JSON error: {}", err),
+            ClientError::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            ClientError::Closed => write!(f, "Client closed"),
+            ClientError::Disconnected => write!(f, "Connection to server lost"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Connect(err) => Some(err),
+            ClientError::Rpc(status) => Some(status),
+            ClientError::Io(err) => Some(err),
+            ClientError::Serde(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl ClientError {
+    // 同一个错误要交给多个调用方时使用（例如 stat_many 整批失败）；保留变体和错误类型，
+    // 无法复制的连接错误改为 NotConnected 的 Io 错误
+    fn duplicate(&self) -> ClientError {
+        match self {
+            ClientError::Request(msg) => ClientError::Request(msg.clone()),
+            ClientError::Connect(err) => ClientError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                err.to_string(),
+            )),
+            ClientError::Rpc(status) => ClientError::Rpc(status.clone()),
+            ClientError::Timeout { path, elapsed } => ClientError::Timeout {
+                path: path.clone(),
+                elapsed: *elapsed,
+            },
+            ClientError::NotFound(path) => ClientError::NotFound(path.clone()),
+            ClientError::BadResponse(msg) => ClientError::BadResponse(msg.clone()),
+            ClientError::Serde(err) => {
+                ClientError::Serde(serde::de::Error::custom(err.to_string()))
+            }
+            ClientError::InvalidArgument(msg) => ClientError::InvalidArgument(msg.clone()),
+            ClientError::Closed => ClientError::Closed,
+            ClientError::Disconnected => ClientError::Disconnected,
+            ClientError::Io(err) => {
+                ClientError::Io(std::io::Error::new(err.kind(), err.to_string()))
+            }
+        }
+    }
+}
+
+impl From<tonic::transport::Error> for ClientError {
+    fn from(err: tonic::transport::Error) -> Self {
+        ClientError::Connect(err)
+    }
+}
+
+impl From<tonic::Status> for ClientError {
+    fn from(status: tonic::Status) -> Self {
+        ClientError::Rpc(status)
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(err: std::io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(err: serde_json::Error) -> Self {
+        ClientError::Serde(err)
+    }
+}
+
+impl From<String> for ClientError {
+    fn from(msg: String) -> Self {
+        ClientError::Request(msg)
+    }
+}
+
 // ===== Cache 管理 =====
-const CACHE_FILE_BASENAME: &str = "cache.json";
+const CACHE_DIR_ENV: &str = "LAZYSYNC_CACHE_DIR";
+
+// 二进制 cache 文件以 CACHE_MAGIC 和小端 u32 版本号开头，后面是 bincode 编码的 CacheData
+// CacheData 的结构有变化时增加 CACHE_FORMAT_VERSION，旧版本的文件读取时直接丢弃
+const CACHE_MAGIC: &[u8; 4] = b"LZSC";
+const CACHE_FORMAT_VERSION: u32 = 1;
+
+// cache 文件的格式：Binary 更小、解析更快；Json 可以直接打开查看，便于调试
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheFormat {
+    #[default]
+    Binary,
+    Json,
+}
+
+impl CacheFormat {
+    fn file_name(self) -> &'static str {
+        match self {
+            CacheFormat::Binary => "cache.bin",
+            CacheFormat::Json => "cache.json",
+        }
+    }
+}
+
+pub type CacheData = HashMap<String, CachedListing>;
+
+// cache 中一个目录的列表和写入时间（Unix 毫秒）
+// 不能用 skip_serializing_if：bincode 按顺序读取，每个字段都必须写出
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CachedListing {
+    pub entries: Vec<FileEntry>,
+    #[serde(default)]
+    pub cached_at: Option<u64>,
+}
+
+impl CachedListing {
+    // 以当前时间作为写入时间
+    pub fn new(entries: Vec<FileEntry>) -> Self {
+        Self {
+            entries,
+            cached_at: Some(unix_millis_now()),
+        }
+    }
+
+    // 写入超过 ttl，或没有写入时间
+    pub fn is_stale(&self, ttl: Duration) -> bool {
+        self.cached_at.is_none_or(|cached_at| {
+            u128::from(unix_millis_now().saturating_sub(cached_at)) >= ttl.as_millis()
+        })
+    }
+}
 
-pub type CacheData = HashMap<String, Vec<FileEntry>>;
+fn unix_millis_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
 
+// 没有配置 cache 目录时使用：环境变量 LAZYSYNC_CACHE_DIR，否则 ~/.lazysync/cache
 fn cache_dir() -> PathBuf {
+    match std::env::var_os(CACHE_DIR_ENV) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_cache_dir(),
+    }
+}
+
+fn home_cache_dir() -> PathBuf {
     if let Ok(home) = std::env::var("HOME") {
         PathBuf::from(home).join(".lazysync").join("cache")
     } else {
@@ -83,322 +547,3659 @@ fn generate_hash() -> String {
     format!("{:x}{:x}", pid, now)
 }
 
-fn init_cache_path(is_hash: bool) -> std::io::Result<PathBuf> {
-    let dir = cache_dir();
+fn init_cache_path(dir: PathBuf, is_hash: bool, format: CacheFormat) -> std::io::Result<PathBuf> {
     fs::create_dir_all(&dir)?;
     clear_cache_dir(&dir)?;
 
     let filename = if is_hash {
-        format!("{}.{}", CACHE_FILE_BASENAME, generate_hash())
+        format!("{}.{}", format.file_name(), generate_hash())
     } else {
-        CACHE_FILE_BASENAME.to_string()
+        format.file_name().to_string()
     };
     Ok(dir.join(filename))
 }
 
+// 两种格式都能读：有 CACHE_MAGIC 的按二进制解析，否则按 JSON；
+// 文件不存在、损坏或版本不对时返回空 cache
 pub fn load_cache(cache_path: &Path) -> CacheData {
-    if cache_path.exists() {
-        if let Ok(content) = fs::read_to_string(cache_path) {
-            if let Ok(cache) = serde_json::from_str::<CacheData>(&content) {
-                return cache;
-            }
+    fs::read(cache_path)
+        .ok()
+        .and_then(|bytes| decode_cache(&bytes))
+        .unwrap_or_default()
+}
+
+fn decode_cache(bytes: &[u8]) -> Option<CacheData> {
+    use bincode::Options;
+    let Some(rest) = bytes.strip_prefix(CACHE_MAGIC) else {
+        return serde_json::from_slice(bytes).ok();
+    };
+    let (version, body) = rest.split_first_chunk::<4>()?;
+    if u32::from_le_bytes(*version) != CACHE_FORMAT_VERSION {
+        return None;
+    }
+    bincode::DefaultOptions::new()
+        .with_limit(body.len() as u64)
+        .deserialize(body)
+        .ok()
+}
+
+fn encode_cache(cache: &CacheData, format: CacheFormat) -> std::io::Result<Vec<u8>> {
+    use bincode::Options;
+    match format {
+        CacheFormat::Json => Ok(serde_json::to_vec_pretty(cache)?),
+        CacheFormat::Binary => {
+            let mut bytes = CACHE_MAGIC.to_vec();
+            bytes.extend_from_slice(&CACHE_FORMAT_VERSION.to_le_bytes());
+            bincode::DefaultOptions::new()
+                .serialize_into(&mut bytes, cache)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            Ok(bytes)
         }
     }
-    HashMap::new()
 }
 
+// 多个进程（例如 PyClient 和 rfb_client）共用一个 cache 文件时，写入前先对旁边的
+// cache.json.lock 加排他锁，f 返回后立即释放。不锁 cache.json 本身，因为它会被 rename 替换
+fn with_cache_lock<T>(
+    cache_path: &Path,
+    f: impl FnOnce() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut lock_name = cache_path.file_name().unwrap_or_default().to_os_string();
+    lock_name.push(".lock");
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(cache_path.with_file_name(lock_name))?;
+    lock.lock_exclusive()?;
+    let result = f();
+    let _ = lock.unlock();
+    result
+}
+
+// 以 JSON 格式保存
 pub fn save_cache(cache: &CacheData, cache_path: &Path) -> std::io::Result<()> {
-    let content = serde_json::to_string_pretty(cache)?;
-    fs::write(cache_path, content)?;
-    Ok(())
+    save_cache_as(cache, cache_path, CacheFormat::Json)
+}
+
+pub fn save_cache_as(
+    cache: &CacheData,
+    cache_path: &Path,
+    format: CacheFormat,
+) -> std::io::Result<()> {
+    with_cache_lock(cache_path, || write_cache_file(cache, cache_path, format))
+}
+
+// 先写到同目录下的 <文件名>.tmp.<pid> 再 rename 覆盖，进程在写入中途被杀时
+// 原来的 cache 文件保持完整；失败时删除临时文件。调用方需持有 cache 锁
+fn write_cache_file(
+    cache: &CacheData,
+    cache_path: &Path,
+    format: CacheFormat,
+) -> std::io::Result<()> {
+    let content = encode_cache(cache, format)?;
+    let mut tmp_name = cache_path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".tmp.{}", std::process::id()));
+    let tmp_path = cache_path.with_file_name(tmp_name);
+    let result = fs::write(&tmp_path, content).and_then(|_| fs::rename(&tmp_path, cache_path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+// 已有 cache 文件的格式：有 CACHE_MAGIC 的是二进制；文件不存在时按文件名（cache.bin*）判断
+fn cache_format_of(cache_path: &Path) -> CacheFormat {
+    let mut magic = [0u8; 4];
+    let is_binary = match fs::File::open(cache_path) {
+        Ok(mut file) => file.read_exact(&mut magic).is_ok() && &magic == CACHE_MAGIC,
+        Err(_) => cache_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(CacheFormat::Binary.file_name())),
+    };
+    if is_binary {
+        CacheFormat::Binary
+    } else {
+        CacheFormat::Json
+    }
+}
+
+// 从 cache 中删除一个目录的列表，返回是否真的删除了；写回时保持文件原来的格式
+pub fn invalidate_cached_path(cache_path: &Path, path: &str) -> std::io::Result<bool> {
+    with_cache_lock(cache_path, || {
+        let format = cache_format_of(cache_path);
+        let mut cache = load_cache(cache_path);
+        if cache.remove(NormalizedPath::from(path).as_str()).is_none() {
+            return Ok(false);
+        }
+        write_cache_file(&cache, cache_path, format)?;
+        Ok(true)
+    })
 }
 
+// 读取、合并、写回都在锁内完成，其他进程同时更新时不会丢失对方的改动；写回时保持文件原来的格式
 pub fn update_cache_with_response(resp: &Response, cache_path: &Path) -> std::io::Result<()> {
-    let mut cache = load_cache(cache_path);
+    with_cache_lock(cache_path, || {
+        let format = cache_format_of(cache_path);
+        let mut cache = load_cache(cache_path);
+        for (key, entries) in response_listings(resp) {
+            cache.insert(key, CachedListing::new(entries));
+        }
+        write_cache_file(&cache, cache_path, format)
+    })
+}
 
+// 一条响应要写入 cache 的 (规范化路径, 列表)
+fn response_listings(resp: &Response) -> Vec<(String, Vec<FileEntry>)> {
+    let mut listings = Vec::new();
     for dir_map in &resp.data {
         for (abs_path, entries) in dir_map {
-            let file_entries: Vec<FileEntry> = entries.iter().map(|fi| {
-                let is_dir = fi.permissions.chars().next() == Some('d');
-                normalize_entry(FileEntry {
-                    name: fi.name.clone(),
-                    is_dir,
-                    file_type: infer_file_type(&fi.file_type, &fi.permissions, is_dir),
-                    size: fi.size,
-                    permissions: fi.permissions.clone(),
-                    modified: fi.modified.clone(),
-                })
-            }).collect();
-            cache.insert(abs_path.clone(), file_entries);
+            let file_entries: Vec<FileEntry> = entries.iter().map(entry_from_info).collect();
+            listings.push((
+                NormalizedPath::from(abs_path.as_str()).into_string(),
+                file_entries,
+            ));
         }
     }
 
-    save_cache(&cache, cache_path)
+    // 服务器返回的是绝对路径，请求可能是相对路径；请求路径也存一份，之后两种形式都能命中
+    let requested = NormalizedPath::from(resp.path.as_str());
+    if !requested.as_str().is_empty() {
+        if let Some(infos) = find_response_entries(resp, &requested) {
+            let file_entries = infos.iter().map(entry_from_info).collect();
+            listings.push((requested.into_string(), file_entries));
+        }
+    }
+    listings
 }
 
-// 规范化路径：去掉末尾的 /（除非是根路径 /）
-fn normalize_path(path: &str) -> String {
-    let trimmed = path.trim();
-    if trimmed == "/" || trimmed.is_empty() {
-        trimmed.to_string()
-    } else {
-        trimmed.trim_end_matches('/').to_string()
-    }
+// 服务器 JSON 响应里的 FileInfo 转成 FileEntry
+fn entry_from_info(fi: &FileInfo) -> FileEntry {
+    let is_dir = fi.permissions.starts_with('d');
+    normalize_entry(FileEntry {
+        name: fi.name.clone(),
+        is_dir,
+        file_type: infer_file_type(&fi.file_type, &fi.permissions, is_dir),
+        size: fi.size,
+        size_known: fi.size_known,
+        permissions: fi.permissions.clone(),
+        modified: fi.modified.clone(),
+        setuid: fi.setuid,
+        setgid: fi.setgid,
+        sticky: fi.sticky,
+    })
 }
 
-fn infer_file_type(file_type: &str, permissions: &str, is_dir: bool) -> String {
-    if !file_type.is_empty() {
-        return file_type.to_string();
+// 拆成 (父目录, 名字)；根目录和不含 / 的相对路径返回 None
+fn parent_and_name(path: &str) -> Option<(&str, &str)> {
+    let (parent, name) = path.rsplit_once('/')?;
+    if name.is_empty() {
+        return None;
     }
-    if let Some(first) = permissions.chars().next() {
-        if first == 'l' {
-            return "symlink".to_string();
+    Some((if parent.is_empty() { "/" } else { parent }, name))
+}
+
+// 在响应里找到请求路径对应的目录列表
+fn find_response_entries<'a>(resp: &'a Response, path: &NormalizedPath) -> Option<&'a [FileInfo]> {
+    find_listing(resp.data.iter().flatten(), path)
+}
+
+// 在 (绝对路径, 列表) 序列里找请求路径对应的列表
+// 只做字符串比较：本地 canonicalize 查的是客户端的文件系统，而路径属于服务器
+// 相对路径请求时，服务器返回的是绝对路径，按 "/<请求路径>" 后缀匹配
+fn find_listing<'a, T: 'a>(
+    listings: impl IntoIterator<Item = (&'a String, &'a Vec<T>)>,
+    path: &NormalizedPath,
+) -> Option<&'a [T]> {
+    let relative = path
+        .as_str()
+        .strip_prefix("./")
+        .filter(|_| !path.as_str().starts_with('/'))
+        .unwrap_or(path.as_str());
+    let suffix = format!("/{}", relative);
+    let mut suffix_match = None;
+
+    for (abs_path, entries) in listings {
+        let resp_path = NormalizedPath::from(abs_path.as_str());
+        if &resp_path == path {
+            return Some(entries);
         }
-        if first == 'd' {
-            return "dir".to_string();
+        if !path.as_str().starts_with('/') && resp_path.as_str().ends_with(&suffix) {
+            suffix_match.get_or_insert(entries.as_slice());
         }
     }
-    if is_dir {
-        "dir".to_string()
+    suffix_match
+}
+
+// 恰好是一个普通的路径分量：非空，不是 . 或 ..，不含 /
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(first)), None) if first == name
+    )
+}
+
+// 规范化路径：去掉首尾空白和末尾的 /（根路径保留为 /）
+// 结果再规范化一次保持不变，因此 p 和 p/ 总是得到同一个 cache key
+fn normalize_path(path: &str) -> String {
+    let trimmed = path.trim();
+    let stripped = trimmed.trim_end_matches(|c: char| c == '/' || c.is_whitespace());
+    if stripped.is_empty() && trimmed.starts_with('/') {
+        "/".to_string()
     } else {
-        "file".to_string()
+        stripped.to_string()
     }
 }
 
-fn normalize_permissions(permissions: &str, file_type: &str) -> String {
-    if file_type != "symlink" {
-        return permissions.to_string();
+// 规范化后的路径：构造时规范化一次，之后直接作为 cache key 和请求路径使用
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NormalizedPath(String);
+
+impl NormalizedPath {
+    pub fn new(path: &str) -> Self {
+        Self(normalize_path(path))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
     }
-    let mut chars = permissions.chars();
-    let _ = chars.next();
-    let rest: String = chars.collect();
-    format!("l{}", rest)
 }
 
-fn normalize_entry(mut entry: FileEntry) -> FileEntry {
-    entry.file_type = infer_file_type(&entry.file_type, &entry.permissions, entry.is_dir);
-    entry.permissions = normalize_permissions(&entry.permissions, &entry.file_type);
-    entry.is_dir = entry.file_type == "dir" || entry.permissions.chars().next() == Some('d');
-    entry
+impl From<&str> for NormalizedPath {
+    fn from(path: &str) -> Self {
+        Self::new(path)
+    }
 }
 
-// ===== 客户端结构 =====
-pub struct Client {
-    writer: Arc<Mutex<TcpStream>>,
-    reader: Arc<Mutex<BufReader<TcpStream>>>,
-    req_id: Arc<Mutex<u64>>,
-    response_channels: Arc<Mutex<HashMap<u64, oneshot::Sender<Response>>>>,
-    receiver_handle: Option<thread::JoinHandle<()>>,
-    cache_path: Arc<PathBuf>,
+impl AsRef<str> for NormalizedPath {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
 }
 
-impl Client {
-    pub fn new(server_addr: &str) -> std::io::Result<Self> {
-        Self::new_with_cache(server_addr, false)
+impl std::fmt::Display for NormalizedPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
+}
 
-    pub fn new_with_cache(server_addr: &str, is_hash: bool) -> std::io::Result<Self> {
-        let cache_path = init_cache_path(is_hash)?;
-        let stream = TcpStream::connect(server_addr)?;
-        stream.set_nodelay(true)?;
-        
-        let writer = Arc::new(Mutex::new(stream.try_clone()?));
-        let reader = Arc::new(Mutex::new(BufReader::new(stream)));
-        let req_id = Arc::new(Mutex::new(0u64));
-        let response_channels: Arc<Mutex<HashMap<u64, oneshot::Sender<Response>>>> = 
-            Arc::new(Mutex::new(HashMap::new()));
-        let cache_path = Arc::new(cache_path);
-
-        // 启动接收线程
-        let response_channels_clone = Arc::clone(&response_channels);
-        let reader_clone = Arc::clone(&reader);
-        let cache_path_clone = Arc::clone(&cache_path);
-        let receiver_handle = thread::spawn(move || {
-            let reader = reader_clone;
-            loop {
-                let line = {
-                    let mut r = reader.lock().unwrap();
-                    let mut line = String::new();
-                    match r.read_line(&mut line) {
-                        Ok(0) => break, // EOF
-                        Ok(_) => line.trim().to_string(),
-                        Err(_) => break,
-                    }
-                };
-                
-                if line.is_empty() {
-                    continue;
-                }
+// 按 "/" 拆分路径并在字符串层面消掉 "." 和 ".."；无法消掉的前导 ".." 返回 None
+fn path_components(path: &str) -> Option<Vec<&str>> {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            _ => parts.push(part),
+        }
+    }
+    Some(parts)
+}
 
-                match serde_json::from_str::<Response>(&line) {
-                    Ok(resp) => {
-                        // 检查是否有等待的channel
-                        {
-                            let mut channels = response_channels_clone.lock().unwrap();
-                            if let Some(sender) = channels.remove(&resp.id) {
-                                let _ = sender.send(resp.clone());
-                            }
-                        }
+// 计算 target 相对于 base 的最短相对路径（纯字符串计算，不访问文件系统）
+// 一个是绝对路径、一个是相对路径，或 ".." 越过了起点时返回 None
+pub fn relative_path(base: &str, target: &str) -> Option<String> {
+    let base = normalize_path(base);
+    let target = normalize_path(target);
+    if base.starts_with('/') != target.starts_with('/') {
+        return None;
+    }
 
-                        // 更新cache
-                        if let Err(e) = update_cache_with_response(&resp, cache_path_clone.as_path()) {
-                            eprintln!("Failed to update cache: {}", e);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to parse response: {}", e);
-                    }
-                }
-            }
-        });
+    let base = path_components(&base)?;
+    let target = path_components(&target)?;
+    let common = base.iter().zip(&target).take_while(|(a, b)| a == b).count();
 
-        Ok(Self {
-            writer,
-            reader,
-            req_id,
-            response_channels,
-            receiver_handle: Some(receiver_handle),
-            cache_path,
-        })
+    let mut parts = vec![".."; base.len() - common];
+    parts.extend(&target[common..]);
+    if parts.is_empty() {
+        Some(".".to_string())
+    } else {
+        Some(parts.join("/"))
     }
+}
 
-    pub fn request_path(&self, path: &str) -> std::io::Result<()> {
-        let mut id = self.req_id.lock().unwrap();
-        *id += 1;
-        let req = Request {
-            id: *id,
-            path: path.to_string(),
-        };
+// 最近访问过的路径（已规范化、去重），最新的在前，超过容量时丢弃最旧的
+#[derive(Debug, Clone)]
+pub struct RecentPaths {
+    paths: VecDeque<String>,
+    capacity: usize,
+}
 
-        let mut writer = self.writer.lock().unwrap();
-        writeln!(writer, "{}", serde_json::to_string(&req).unwrap())?;
-        writer.flush()?;
-        Ok(())
+pub const DEFAULT_RECENT_PATHS: usize = 64;
+
+impl RecentPaths {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            paths: VecDeque::with_capacity(capacity),
+            capacity,
+        }
     }
 
-    pub async fn get_path(&self, path: &str) -> Result<Vec<FileEntry>, String> {
-        // 规范化路径：去掉末尾的 /
-        let normalized_path = normalize_path(path);
-        
-        // 1. 先检查cache
-        if let Some(entries) = {
-            let cache = load_cache(self.cache_path.as_path());
-            let normalized = normalize_path(&normalized_path);
-            cache.get(&normalized).cloned()
-        } {
-            let normalized_entries: Vec<FileEntry> = entries
-                .into_iter()
-                .map(normalize_entry)
-                .collect();
-            return Ok(normalized_entries);
+    pub fn push(&mut self, path: &str) {
+        let path = normalize_path(path);
+        if path.is_empty() || self.capacity == 0 {
+            return;
         }
+        self.paths.retain(|existing| *existing != path);
+        if self.paths.len() == self.capacity {
+            self.paths.pop_back();
+        }
+        self.paths.push_front(path);
+    }
 
-        // 2. 没有cache，发送请求并等待响应
-        let (tx, rx) = oneshot::channel();
-        let request_id = {
-            let mut id = self.req_id.lock().unwrap();
-            *id += 1;
-            let req_id = *id;
-            
-            // 注册channel
-            {
-                let mut channels = self.response_channels.lock().unwrap();
-                channels.insert(req_id, tx);
-            }
+    pub fn latest(&self) -> Option<&str> {
+        self.paths.front().map(String::as_str)
+    }
 
-            // 发送请求（使用规范化后的路径）
-            let req = Request {
-                id: req_id,
-                path: normalized_path.clone(),
+    // 最多返回 limit 个，最新的在前
+    pub fn list(&self, limit: usize) -> Vec<String> {
+        self.paths.iter().take(limit).cloned().collect()
+    }
+}
+
+impl Default for RecentPaths {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECENT_PATHS)
+    }
+}
+
+// 内存中的 cache（按规范化路径）：查询都在这里完成，cache 文件只用于持久化
+// 有改动时标记为 dirty，由后台线程稍等片刻后把整份数据写回文件，连续到达的响应只写一次
+// 目录数超过 max_entries 时淘汰最久没有访问（读或写）的目录
+struct CacheStore {
+    data: RwLock<CacheData>,
+    // 最近访问的 key 在前；与 data 一起加锁时先锁 data
+    order: Mutex<VecDeque<String>>,
+    // 服务器确认不存在的路径和确认时间（负缓存），只在内存中，不写入文件
+    missing: Mutex<HashMap<String, Instant>>,
+    max_entries: Option<usize>,
+    // false 表示不使用 cache（ClientBuilder::no_cache）：不存任何列表，也不读写文件
+    enabled: bool,
+    // cache 文件；None 时只在内存中（no_cache，或 cache 目录不可写）
+    path: Option<PathBuf>,
+    format: CacheFormat,
+    flush: Mutex<FlushState>,
+    wake: Condvar,
+}
+
+// 目录下某个子项的规范化路径
+fn child_path(dir: &str, name: &str) -> String {
+    if dir == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+// 把 key 移到最前；不在 order 中时加到最前
+fn touch(order: &mut VecDeque<String>, key: &str) {
+    if let Some(pos) = order.iter().position(|existing| existing == key) {
+        if let Some(key) = order.remove(pos) {
+            order.push_front(key);
+        }
+    } else {
+        order.push_front(key.to_string());
+    }
+}
+
+fn evict(data: &mut CacheData, order: &mut VecDeque<String>, max_entries: Option<usize>) {
+    let Some(max_entries) = max_entries else {
+        return;
+    };
+    while order.len() > max_entries {
+        if let Some(oldest) = order.pop_back() {
+            data.remove(&oldest);
+        }
+    }
+}
+
+// 文件中的数据去掉本进程删除的 key，再用内存中的数据覆盖；同一个 key 保留写入时间较新的一份
+// 超出 max_entries 时内存中的 key 优先，其余按写入时间从新到旧保留
+fn merge_cache(
+    mut on_disk: CacheData,
+    memory: &CacheData,
+    removed: &HashSet<String>,
+    max_entries: Option<usize>,
+) -> CacheData {
+    on_disk.retain(|key, _| !removed.contains(key));
+    for (key, listing) in memory {
+        let newer_on_disk = on_disk
+            .get(key)
+            .is_some_and(|disk| disk.cached_at > listing.cached_at);
+        if !newer_on_disk {
+            on_disk.insert(key.clone(), listing.clone());
+        }
+    }
+    let Some(max_entries) = max_entries else {
+        return on_disk;
+    };
+    if on_disk.len() <= max_entries {
+        return on_disk;
+    }
+    let mut others: Vec<(Option<u64>, String)> = on_disk
+        .iter()
+        .filter(|(key, _)| !memory.contains_key(*key))
+        .map(|(key, listing)| (listing.cached_at, key.clone()))
+        .collect();
+    others.sort();
+    let excess = on_disk.len() - max_entries;
+    for (_, key) in others.into_iter().take(excess) {
+        on_disk.remove(&key);
+    }
+    on_disk
+}
+
+#[derive(Default)]
+struct FlushState {
+    dirty: bool,
+    closed: bool,
+    // 上次写文件之后删除的 key；合并时文件里的这些 key 也要去掉
+    removed: HashSet<String>,
+    // 上次写文件之后整体清空或替换过，合并时不保留文件里原有的内容
+    replaced: bool,
+}
+
+// 有改动后等多久再写文件，把这段时间内的改动合并成一次写入
+const CACHE_FLUSH_DELAY: Duration = Duration::from_millis(200);
+
+// cache 中最多保留的目录数
+pub const DEFAULT_MAX_CACHE_ENTRIES: usize = 10_000;
+
+impl CacheStore {
+    fn open(
+        enabled: bool,
+        path: Option<PathBuf>,
+        format: CacheFormat,
+        max_entries: Option<usize>,
+    ) -> Self {
+        let mut data = path.as_deref().map(load_cache).unwrap_or_default();
+        // 文件里没有访问顺序，按写入时间排，最新的在前
+        let mut keys: Vec<(Option<u64>, String)> = data
+            .iter()
+            .map(|(key, listing)| (listing.cached_at, key.clone()))
+            .collect();
+        keys.sort();
+        let mut order: VecDeque<String> = keys.into_iter().rev().map(|(_, key)| key).collect();
+        evict(&mut data, &mut order, max_entries);
+        Self {
+            data: RwLock::new(data),
+            order: Mutex::new(order),
+            missing: Mutex::new(HashMap::new()),
+            max_entries,
+            enabled,
+            path,
+            format,
+            flush: Mutex::new(FlushState::default()),
+            wake: Condvar::new(),
+        }
+    }
+
+    fn get_many(&self, keys: &[&str]) -> Vec<Option<Vec<FileEntry>>> {
+        let data = self.data.read().unwrap();
+        let mut order = self.order.lock().unwrap();
+        keys.iter()
+            .map(|key| {
+                let listing = data.get(*key)?;
+                touch(&mut order, key);
+                Some(listing.entries.clone())
+            })
+            .collect()
+    }
+
+    // 写入若干目录列表并记录写入时间，超出 max_entries 时淘汰最久没访问的
+    fn insert_all(&self, listings: impl IntoIterator<Item = (String, Vec<FileEntry>)>) {
+        if !self.enabled {
+            return;
+        }
+        {
+            let mut data = self.data.write().unwrap();
+            let mut order = self.order.lock().unwrap();
+            let mut missing = self.missing.lock().unwrap();
+            for (key, entries) in listings {
+                missing.remove(&key);
+                touch(&mut order, &key);
+                data.insert(key, CachedListing::new(entries));
+            }
+            evict(&mut data, &mut order, self.max_entries);
+        }
+        self.mark_dirty();
+    }
+
+    // 返回是否真的删除了
+    fn remove(&self, key: &str) -> bool {
+        self.missing.lock().unwrap().remove(key);
+        let removed = {
+            let mut data = self.data.write().unwrap();
+            let mut order = self.order.lock().unwrap();
+            order.retain(|existing| existing != key);
+            data.remove(key).is_some()
+        };
+        if removed {
+            self.flush.lock().unwrap().removed.insert(key.to_string());
+            self.mark_dirty();
+        }
+        removed
+    }
+
+    fn clear(&self) {
+        self.missing.lock().unwrap().clear();
+        {
+            let mut data = self.data.write().unwrap();
+            let mut order = self.order.lock().unwrap();
+            data.clear();
+            order.clear();
+        }
+        self.mark_replaced();
+    }
+
+    fn len(&self) -> usize {
+        self.data.read().unwrap().len()
+    }
+
+    fn mark_missing(&self, key: &str) {
+        if self.enabled {
+            let mut missing = self.missing.lock().unwrap();
+            missing.insert(key.to_string(), Instant::now());
+        }
+    }
+
+    // 在 ttl 之内确认过不存在；过期的记录顺便删除
+    fn is_missing(&self, key: &str, ttl: Duration) -> bool {
+        let mut missing = self.missing.lock().unwrap();
+        match missing.get(key) {
+            Some(checked) if checked.elapsed() < ttl => true,
+            Some(_) => {
+                missing.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn snapshot(&self) -> (CacheData, Vec<String>) {
+        let data = self.data.read().unwrap();
+        let order = self.order.lock().unwrap();
+        (data.clone(), order.iter().cloned().collect())
+    }
+
+    // 整体替换；order 中缺少的 key 排在最后，重复和多余的忽略
+    fn restore(&self, mut listings: CacheData, order: Vec<String>) {
+        if !self.enabled {
+            return;
+        }
+        {
+            let mut data = self.data.write().unwrap();
+            let mut lru = self.order.lock().unwrap();
+            let mut seen = HashSet::new();
+            let mut restored: VecDeque<String> = order
+                .into_iter()
+                .filter(|key| listings.contains_key(key) && seen.insert(key.clone()))
+                .collect();
+            restored.extend(listings.keys().filter(|key| !seen.contains(*key)).cloned());
+            evict(&mut listings, &mut restored, self.max_entries);
+            *data = listings;
+            *lru = restored;
+        }
+        self.mark_replaced();
+    }
+
+    // 不改变访问顺序；符号链接目录不进入
+    fn subtree_size(&self, root: &str) -> u64 {
+        let data = self.data.read().unwrap();
+        let mut total = 0;
+        let mut pending = vec![root.to_string()];
+        while let Some(dir) = pending.pop() {
+            let Some(listing) = data.get(&dir) else {
+                continue;
+            };
+            for entry in &listing.entries {
+                match entry.file_type.as_str() {
+                    "file" => total += entry.size,
+                    "dir" => pending.push(child_path(&dir, &entry.name)),
+                    _ => {}
+                }
+            }
+        }
+        total
+    }
+
+    // 按写入时间判断，写入时间随 cache 文件保存，重启后仍然有效；没有写入时间的按已过期处理
+    fn is_fresh(&self, key: &str, ttl: Duration) -> bool {
+        self.data
+            .read()
+            .unwrap()
+            .get(key)
+            .is_some_and(|listing| !listing.is_stale(ttl))
+    }
+
+    fn mark_dirty(&self) {
+        self.flush.lock().unwrap().dirty = true;
+        self.wake.notify_all();
+    }
+
+    fn mark_replaced(&self) {
+        let mut state = self.flush.lock().unwrap();
+        state.replaced = true;
+        state.removed.clear();
+        state.dirty = true;
+        drop(state);
+        self.wake.notify_all();
+    }
+
+    // 后台写文件线程：等到有改动，再等 CACHE_FLUSH_DELAY 后写入；close 之后写完最后一次就退出
+    fn run_flusher(&self) {
+        loop {
+            let mut state = self.flush.lock().unwrap();
+            while !state.dirty && !state.closed {
+                state = self.wake.wait(state).unwrap();
+            }
+            if !state.closed {
+                state = self
+                    .wake
+                    .wait_timeout_while(state, CACHE_FLUSH_DELAY, |state| !state.closed)
+                    .unwrap()
+                    .0;
+            }
+            let (dirty, closed) = (state.dirty, state.closed);
+            let removed = std::mem::take(&mut state.removed);
+            let replaced = std::mem::take(&mut state.replaced);
+            state.dirty = false;
+            drop(state);
+
+            if let (true, Some(path)) = (dirty, &self.path) {
+                if let Err(e) = self.save_merged(path, &removed, replaced) {
+                    eprintln!("Failed to save cache: {}", e);
+                }
+            }
+            if closed {
+                return;
+            }
+        }
+    }
+
+    // 其他进程可能同时写同一个 cache 文件：在锁内重新读取文件，和内存中的数据合并后再写回，
+    // 不覆盖对方在这期间写入的目录
+    fn save_merged(
+        &self,
+        path: &Path,
+        removed: &HashSet<String>,
+        replaced: bool,
+    ) -> std::io::Result<()> {
+        with_cache_lock(path, || {
+            let on_disk = if replaced {
+                CacheData::new()
+            } else {
+                load_cache(path)
+            };
+            let data = self.data.read().unwrap();
+            let merged = merge_cache(on_disk, &data, removed, self.max_entries);
+            drop(data);
+            write_cache_file(&merged, path, self.format)
+        })
+    }
+
+    fn close(&self) {
+        self.flush.lock().unwrap().closed = true;
+        self.wake.notify_all();
+    }
+}
+
+// 读取文件 [offset, offset + length) 的字节并检测编码后以文本返回；length 为 0 或超过 MAX_READ_TEXT 时
+// 按 MAX_READ_TEXT 读取。Client::read_text 和 rfb_client 的 /read-text 共用
+pub async fn read_text_with(
+    rpc: &mut LazySyncClient<Channel>,
+    path: &str,
+    offset: u64,
+    length: u64,
+) -> Result<DecodedText, ClientError> {
+    let length = if length == 0 {
+        MAX_READ_TEXT
+    } else {
+        length.min(MAX_READ_TEXT)
+    };
+    let mut stream = rpc
+        .read_file(ReadFileRequest {
+            path: path.to_string(),
+            offset,
+            length,
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = stream.message().await? {
+        bytes.extend_from_slice(&chunk.data);
+    }
+    Ok(decode_text(&bytes))
+}
+
+// 把补全前缀拆成 (要列出的目录, 待匹配的最后一段)
+fn split_completion_prefix(prefix: &str) -> (String, &str) {
+    match prefix.rfind('/') {
+        Some(0) => ("/".to_string(), &prefix[1..]),
+        Some(idx) => (normalize_path(&prefix[..idx]), &prefix[idx + 1..]),
+        None => (".".to_string(), prefix),
+    }
+}
+
+// 服务器和 normalize_entry 可能给出的 file_type
+pub const FILE_TYPES: [&str; 4] = ["file", "dir", "symlink", "other"];
+
+fn infer_file_type(file_type: &str, permissions: &str, is_dir: bool) -> String {
+    if !file_type.is_empty() {
+        return file_type.to_string();
+    }
+    if let Some(first) = permissions.chars().next() {
+        if first == 'l' {
+            return "symlink".to_string();
+        }
+        if first == 'd' {
+            return "dir".to_string();
+        }
+    }
+    if is_dir {
+        "dir".to_string()
+    } else {
+        "file".to_string()
+    }
+}
+
+fn normalize_permissions(permissions: &str, file_type: &str) -> String {
+    if file_type != "symlink" {
+        return permissions.to_string();
+    }
+    let mut chars = permissions.chars();
+    let _ = chars.next();
+    let rest: String = chars.collect();
+    format!("l{}", rest)
+}
+
+// gRPC 返回的 FileInfo 转成 cache 使用的 FileEntry
+impl From<lazysync::FileInfo> for FileEntry {
+    fn from(info: lazysync::FileInfo) -> Self {
+        let is_dir = info.file_type == "dir";
+        normalize_entry(FileEntry {
+            name: info.name,
+            is_dir,
+            file_type: info.file_type,
+            size: info.size,
+            size_known: info.size_known,
+            permissions: info.permissions,
+            modified: info.modified,
+            setuid: info.setuid,
+            setgid: info.setgid,
+            sticky: info.sticky,
+        })
+    }
+}
+
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+// read_text 单次最多解码的字节数，length 为 0 或超过时按此截断
+pub const MAX_READ_TEXT: u64 = 1024 * 1024;
+
+// 按 BOM / UTF-16 特征 / UTF-8 校验检测编码并解码
+// 按字节范围读取时首尾可能切断多字节字符，这种情况仍按 UTF-8 有损解码
+pub fn decode_text(bytes: &[u8]) -> DecodedText {
+    let (encoding, bom_len) = encoding_rs::Encoding::for_bom(bytes)
+        .or_else(|| guess_utf16(bytes).map(|encoding| (encoding, 0)))
+        .unwrap_or_else(|| {
+            if is_utf8_fragment(bytes) {
+                (encoding_rs::UTF_8, 0)
+            } else {
+                (encoding_rs::WINDOWS_1252, 0)
+            }
+        });
+    let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+    DecodedText {
+        text: text.into_owned(),
+        encoding: encoding.name().to_string(),
+    }
+}
+
+// 没有 BOM 的 UTF-16：ASCII 为主的文本里，每两个字节中固定有一个是 0
+fn guess_utf16(bytes: &[u8]) -> Option<&'static encoding_rs::Encoding> {
+    let pairs = bytes.len() / 2;
+    if pairs < 2 {
+        return None;
+    }
+    let even_zeros = bytes.iter().step_by(2).filter(|&&b| b == 0).count();
+    let odd_zeros = bytes.iter().skip(1).step_by(2).filter(|&&b| b == 0).count();
+    if odd_zeros * 10 >= pairs * 9 && even_zeros == 0 {
+        Some(encoding_rs::UTF_16LE)
+    } else if even_zeros * 10 >= pairs * 9 && odd_zeros == 0 {
+        Some(encoding_rs::UTF_16BE)
+    } else {
+        None
+    }
+}
+
+// 去掉开头被截断的续字节和结尾不完整的字符后，剩余部分是否为合法 UTF-8
+fn is_utf8_fragment(bytes: &[u8]) -> bool {
+    let start = bytes
+        .iter()
+        .take(3)
+        .take_while(|&&b| b & 0xC0 == 0x80)
+        .count();
+    match std::str::from_utf8(&bytes[start..]) {
+        Ok(_) => true,
+        Err(err) => err.error_len().is_none(),
+    }
+}
+
+fn normalize_entry(mut entry: FileEntry) -> FileEntry {
+    entry.file_type = infer_file_type(&entry.file_type, &entry.permissions, entry.is_dir);
+    entry.permissions = normalize_permissions(&entry.permissions, &entry.file_type);
+    entry.is_dir = entry.file_type == "dir" || entry.permissions.starts_with('d');
+    entry.size_known &= matches!(entry.file_type.as_str(), "file" | "dir");
+    // 权限字符串按 ls 的写法：执行位上的 s/S 表示 setuid/setgid，t/T 表示 sticky
+    let perms: Vec<char> = entry.permissions.chars().collect();
+    entry.setuid |= matches!(perms.get(3), Some('s' | 'S'));
+    entry.setgid |= matches!(perms.get(6), Some('s' | 'S'));
+    entry.sticky |= matches!(perms.get(9), Some('t' | 'T'));
+    entry
+}
+
+// ===== 后台上传队列 =====
+// 一个待上传的文件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadJob {
+    pub id: u64,
+    pub local_path: PathBuf,
+    pub remote_path: String,
+}
+
+// 重试用完后仍然失败的上传，error 是最后一次的错误
+#[derive(Debug, Clone)]
+pub struct FailedUpload {
+    pub job: UploadJob,
+    pub error: String,
+}
+
+// upload_status 的返回值；pending 包括正在上传的那个
+#[derive(Debug, Clone, Default)]
+pub struct UploadQueueStatus {
+    pub pending: Vec<UploadJob>,
+    pub completed: u64,
+    pub failed: Vec<FailedUpload>,
+}
+
+// 每个文件最多尝试的次数；第 n 次失败后等待 n * UPLOAD_RETRY_BACKOFF 再试
+const UPLOAD_MAX_ATTEMPTS: u32 = 5;
+const UPLOAD_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+// durable-uploads 时默认的队列文件，放在 cache 目录旁边（cache 目录启动时会被清空）
+const UPLOAD_QUEUE_FILE: &str = "uploads.json";
+
+// cache_dir 是实际使用的 cache 目录（ClientBuilder::cache_dir、LAZYSYNC_CACHE_DIR 或默认目录）
+fn default_upload_queue_path(cache_dir: &Path) -> PathBuf {
+    cache_dir.with_file_name(UPLOAD_QUEUE_FILE)
+}
+
+#[derive(Default)]
+struct UploadQueueState {
+    pending: VecDeque<UploadJob>,
+    completed: u64,
+    failed: Vec<FailedUpload>,
+    next_id: u64,
+    worker_started: bool,
+    closed: bool,
+}
+
+// enqueue_upload 加入的文件由一个后台线程逐个上传
+// store 不为空时（durable-uploads）每次变化后把待上传列表写进这个文件，启动时从中恢复
+struct UploadQueue {
+    state: Mutex<UploadQueueState>,
+    wake: Condvar,
+    store: Option<PathBuf>,
+}
+
+impl UploadQueue {
+    fn open(store: Option<PathBuf>) -> Self {
+        let pending: VecDeque<UploadJob> = store
+            .as_deref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        let next_id = pending.iter().map(|job| job.id).max().unwrap_or(0);
+        Self {
+            state: Mutex::new(UploadQueueState {
+                pending,
+                next_id,
+                ..Default::default()
+            }),
+            wake: Condvar::new(),
+            store,
+        }
+    }
+
+    fn save(&self, state: &UploadQueueState) {
+        let Some(path) = &self.store else {
+            return;
+        };
+        let saved = serde_json::to_string(&state.pending)
+            .map_err(std::io::Error::from)
+            .and_then(|content| fs::write(path, content));
+        if let Err(e) = saved {
+            eprintln!("Failed to save upload queue: {}", e);
+        }
+    }
+
+    // 有待上传的任务且还没有后台线程时启动一个
+    fn ensure_worker(self: &Arc<Self>, state: &mut UploadQueueState, server_addr: &str) {
+        if state.worker_started || state.closed || state.pending.is_empty() {
+            return;
+        }
+        state.worker_started = true;
+        let queue = Arc::clone(self);
+        let server_addr = server_addr.to_string();
+        thread::spawn(move || queue.run_worker(&server_addr));
+    }
+
+    // 队列空时等待；close 之后不再开始新的上传，正在等待重试的任务留在队列里
+    fn run_worker(&self, server_addr: &str) {
+        let rt = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(rt) => rt,
+            Err(e) => {
+                eprintln!("Failed to start upload worker: {}", e);
+                return;
+            }
+        };
+        let mut rpc = None;
+        loop {
+            let job = {
+                let mut state = self.state.lock().unwrap();
+                while state.pending.is_empty() && !state.closed {
+                    state = self.wake.wait(state).unwrap();
+                }
+                match state.pending.front() {
+                    Some(job) if !state.closed => job.clone(),
+                    _ => return,
+                }
+            };
+
+            let mut attempt = 0;
+            let result = loop {
+                attempt += 1;
+                let result = rt.block_on(upload_file(&mut rpc, server_addr, &job));
+                if result.is_ok() || attempt == UPLOAD_MAX_ATTEMPTS {
+                    break result;
+                }
+                // 出错后重新建立连接
+                rpc = None;
+                let state = self.state.lock().unwrap();
+                let backoff = UPLOAD_RETRY_BACKOFF * attempt;
+                let (state, _) = self
+                    .wake
+                    .wait_timeout_while(state, backoff, |state| !state.closed)
+                    .unwrap();
+                if state.closed {
+                    return;
+                }
+            };
+
+            let mut state = self.state.lock().unwrap();
+            state.pending.pop_front();
+            match result {
+                Ok(_) => state.completed += 1,
+                Err(e) => state.failed.push(FailedUpload {
+                    job,
+                    error: e.to_string(),
+                }),
+            }
+            self.save(&state);
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.wake.notify_all();
+    }
+}
+
+async fn upload_file(
+    rpc: &mut Option<LazySyncClient<Channel>>,
+    server_addr: &str,
+    job: &UploadJob,
+) -> Result<u64, ClientError> {
+    let file = tokio::fs::File::open(&job.local_path).await?;
+    let client = match rpc {
+        Some(client) => client.clone(),
+        None => rpc
+            .insert(LazySyncClient::connect(format!("http://{}", server_addr)).await?)
+            .clone(),
+    };
+    upload_chunks(client, &job.remote_path, file, 0, false, true).await
+}
+
+// 从 reader 读取数据，通过 write_file 写到 remote_path 的 offset 处，返回服务器确认写入的字节数；
+// truncate 时服务器打开文件会先截断，从头重写时不留下旧文件更长的尾部
+async fn upload_chunks<R: AsyncRead + Unpin>(
+    mut rpc: LazySyncClient<Channel>,
+    remote_path: &str,
+    mut reader: R,
+    offset: u64,
+    exclusive: bool,
+    truncate: bool,
+) -> Result<u64, ClientError> {
+    let (tx, rx) = mpsc::channel(4);
+    let mut path = NormalizedPath::from(remote_path).into_string();
+
+    let feed = async move {
+        let mut offset = offset;
+        loop {
+            let mut data = vec![0u8; WRITE_CHUNK_SIZE];
+            let n = reader.read(&mut data).await?;
+            data.truncate(n);
+            let eof = n == 0;
+            // 服务器只看第一个 chunk 的 path
+            let chunk = WriteFileChunk {
+                path: std::mem::take(&mut path),
+                offset,
+                data,
+                eof,
+                exclusive,
+                truncate,
+            };
+            offset += n as u64;
+            // 服务器提前结束时发送会失败，错误由 RPC 结果返回
+            if tx.send(chunk).await.is_err() || eof {
+                break;
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let (fed, resp) = tokio::join!(feed, rpc.write_file(ReceiverStream::new(rx)));
+    fed?;
+    Ok(resp?.into_inner().bytes_written)
+}
+
+// 订阅服务器推送的失效事件：服务器上的写操作改动某个目录后推送它的路径，后台任务把它从 cache 删除，
+// 下次 get_path 会重新获取；服务器报告丢失了事件时清空整个 cache。返回时订阅已经生效
+async fn subscribe_invalidations(
+    mut rpc: LazySyncClient<Channel>,
+    cache: Arc<CacheStore>,
+    root: &str,
+) -> Result<tokio::task::JoinHandle<()>, ClientError> {
+    let root = if root.is_empty() {
+        String::new()
+    } else {
+        NormalizedPath::from(root).into_string()
+    };
+    let mut stream = rpc
+        .watch_invalidations(WatchInvalidationsRequest { root })
+        .await?
+        .into_inner();
+    Ok(tokio::spawn(async move {
+        while let Ok(Some(event)) = stream.message().await {
+            if event.lagged {
+                cache.clear();
+            } else {
+                cache.remove(NormalizedPath::from(event.path.as_str()).as_str());
+            }
+        }
+    }))
+}
+
+// ===== 文件内容 cache =====
+// prefetch_file 下载的文件放在 cache 文件旁边的 <cache 文件名>.content 目录中，文件名为远程路径的 SHA-256；
+// 同名的 .json 记录下载时服务器上的大小和 mtime（validator），两者都没变时才认为内容有效
+fn content_cache_dir(cache_path: &Path) -> PathBuf {
+    let mut name = cache_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".content");
+    cache_path.with_file_name(name)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ContentValidator {
+    size: u64,
+    modified_unix: i64,
+}
+
+impl ContentValidator {
+    fn of(info: &lazysync::FileInfo) -> Self {
+        Self {
+            size: info.size,
+            modified_unix: info.modified_unix,
+        }
+    }
+}
+
+// (内容文件, validator 文件)
+fn content_paths(dir: &Path, remote: &str) -> (PathBuf, PathBuf) {
+    let key = format!("{:x}", Sha256::digest(remote.as_bytes()));
+    (dir.join(&key), dir.join(format!("{}.json", key)))
+}
+
+// 服务器上的文件信息和 cache 中的 validator 一致时返回内容文件
+fn valid_content(dir: &Path, remote: &str, info: &lazysync::FileInfo) -> Option<PathBuf> {
+    let (data, meta) = content_paths(dir, remote);
+    let saved: ContentValidator = serde_json::from_slice(&fs::read(meta).ok()?).ok()?;
+    (saved == ContentValidator::of(info) && data.is_file()).then_some(data)
+}
+
+// 不存在或是目录时返回错误
+async fn stat_file(
+    rpc: &mut LazySyncClient<Channel>,
+    remote: &str,
+) -> Result<lazysync::FileInfo, ClientError> {
+    let resp = rpc
+        .stat(StatRequest {
+            path: remote.to_string(),
+        })
+        .await?
+        .into_inner();
+    match resp.info.filter(|_| resp.exists) {
+        Some(info) if info.file_type == "dir" => Err(ClientError::InvalidArgument(format!(
+            "Is a directory: {}",
+            remote
+        ))),
+        Some(info) => Ok(info),
+        None => Err(ClientError::NotFound(remote.to_string())),
+    }
+}
+
+// 已有有效内容时直接返回，否则下载到临时文件再改名；先删掉旧的 validator，
+// 中途失败或被取消时不会留下看起来有效的内容
+async fn prefetch_content(
+    mut rpc: LazySyncClient<Channel>,
+    dir: PathBuf,
+    remote: String,
+    in_flight: Option<Arc<tokio::sync::Semaphore>>,
+) -> Result<PathBuf, ClientError> {
+    let info = stat_file(&mut rpc, &remote).await?;
+    if let Some(data) = valid_content(&dir, &remote, &info) {
+        return Ok(data);
+    }
+    let _permit = match in_flight {
+        Some(in_flight) => in_flight.acquire_owned().await.ok(),
+        None => None,
+    };
+
+    let (data, meta) = content_paths(&dir, &remote);
+    tokio::fs::create_dir_all(&dir).await?;
+    match tokio::fs::remove_file(&meta).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut stream = rpc
+        .read_file(ReadFileRequest {
+            path: remote.clone(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+    let partial = data.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&partial).await?;
+    while let Some(chunk) = stream.message().await? {
+        file.write_all(&chunk.data).await?;
+    }
+    file.flush().await?;
+    drop(file);
+    tokio::fs::rename(&partial, &data).await?;
+    let validator =
+        serde_json::to_vec(&ContentValidator::of(&info)).map_err(std::io::Error::from)?;
+    tokio::fs::write(&meta, validator).await?;
+    Ok(data)
+}
+
+// 从内容 cache 的本地副本按 WRITE_CHUNK_SIZE 分块发送 [offset, offset + length)，length 为 0 表示到末尾
+async fn send_local_range(
+    data: &Path,
+    offset: u64,
+    length: u64,
+    tx: &mpsc::Sender<Result<Vec<u8>, ClientError>>,
+) -> Result<(), ClientError> {
+    use tokio::io::AsyncSeekExt;
+
+    let mut file = tokio::fs::File::open(data).await?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut remaining = if length == 0 { u64::MAX } else { length };
+    while remaining > 0 {
+        let mut chunk = vec![0u8; (WRITE_CHUNK_SIZE as u64).min(remaining) as usize];
+        let n = file.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        chunk.truncate(n);
+        remaining -= n as u64;
+        if tx.send(Ok(chunk)).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Client::prefetch_file 返回的句柄；drop 不会取消，下载在后台继续
+pub struct PrefetchHandle {
+    path: String,
+    task: tokio::task::JoinHandle<Result<PathBuf, ClientError>>,
+}
+
+impl PrefetchHandle {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    // 停止下载；已经下载完的内容保留在 cache 中
+    pub fn cancel(&self) {
+        self.task.abort();
+    }
+
+    // 等待完成，返回本地内容文件的路径
+    pub async fn wait(self) -> Result<PathBuf, ClientError> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) if e.is_cancelled() => Err(ClientError::Request(format!(
+                "Prefetch cancelled: {}",
+                self.path
+            ))),
+            Err(e) => Err(ClientError::Request(e.to_string())),
+        }
+    }
+}
+
+// ===== 客户端结构 =====
+// mirror 比较用的条目，只填 changes_from 关心的字段：服务器按它自己的时区格式化 modified，
+// 本地无法重现同样的字符串，所以这里用 Unix 秒数；权限只比较 0o777 部分，
+// setuid 等特殊位不会被复制到本地
+fn mirror_entry(size: u64, modified_unix: Option<i64>, mode: Option<u32>) -> FileEntry {
+    FileEntry {
+        name: String::new(),
+        is_dir: false,
+        file_type: "file".to_string(),
+        size,
+        size_known: true,
+        permissions: mode
+            .map(|mode| format!("-{}", mode_string(mode & 0o777)))
+            .unwrap_or_default(),
+        modified: modified_unix
+            .map(|secs| secs.to_string())
+            .unwrap_or_default(),
+        setuid: false,
+        setgid: false,
+        sticky: false,
+    }
+}
+
+fn mirror_reason(change: &EntryChange) -> &'static str {
+    match change {
+        EntryChange::Size { .. } => "size differs",
+        EntryChange::Modified { .. } => "mtime differs",
+        EntryChange::Permissions { .. } => "permissions differ",
+        EntryChange::FileType { .. } => "type differs",
+    }
+}
+
+// ls 风格的九位权限字符串，执行位上的 s/S、t/T 表示 setuid/setgid/sticky
+fn mode_string(mode: u32) -> String {
+    let mut result = String::with_capacity(9);
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        result.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        result.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        result.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    result
+}
+
+// mode_string 的反向：解析服务器返回的十位权限字符串（第一位是类型，忽略）
+fn parse_mode_string(permissions: &str) -> Option<u32> {
+    let chars: Vec<char> = permissions.chars().skip(1).collect();
+    if chars.len() != 9 {
+        return None;
+    }
+    let mut mode = 0;
+    for (index, c) in chars.into_iter().enumerate() {
+        let bit = 0o400 >> index;
+        let special = [0o4000, 0o2000, 0o1000][index / 3];
+        mode |= match (index % 3, c) {
+            (_, '-') => 0,
+            (0, 'r') | (1, 'w') | (2, 'x') => bit,
+            (2, 's' | 't') => bit | special,
+            (2, 'S' | 'T') => special,
+            _ => return None,
+        };
+    }
+    Some(mode)
+}
+
+#[cfg(unix)]
+fn local_mode(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn local_mode(_meta: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+fn unix_mtime(meta: &fs::Metadata) -> Option<i64> {
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(modified.as_secs() as i64)
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; WRITE_CHUNK_SIZE];
+    loop {
+        let n = std::io::Read::read(&mut file, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+type ResponseChannels = Arc<Mutex<HashMap<u64, oneshot::Sender<Result<Response, ClientError>>>>>;
+// 最近的响应解析错误，最旧的在前
+type ParseErrors = Arc<Mutex<VecDeque<String>>>;
+
+// 最多保留的解析错误条数
+const MAX_PARSE_ERRORS: usize = 32;
+// 解析错误里保留的原始行长度（字符数）
+const MAX_RAW_IN_ERROR: usize = 256;
+
+// 空闲检查的最长间隔
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// 已发出、还在等待响应的目录请求
+struct PendingDir {
+    request_id: u64,
+    rx: oneshot::Receiver<Result<Response, ClientError>>,
+    started: Instant,
+}
+
+// 接收线程与写端共享的连接状态
+struct LinkState {
+    // 接收线程退出（服务器断开或被关闭）后为 false
+    alive: AtomicBool,
+    // 主动关闭（空闲超时、close、重新连接前）时设置，接收线程退出时不再让等待中的请求失败
+    closing: AtomicBool,
+    // 在这条连接上发出的最大请求 id；断开时只让这些请求失败，已经改用新连接的请求不受影响
+    last_sent: AtomicU64,
+}
+
+// 一条 TCP 连接：写端 + 接收线程
+struct Connection {
+    writer: TcpStream,
+    receiver: thread::JoinHandle<()>,
+    state: Arc<LinkState>,
+}
+
+impl Connection {
+    // 连接服务器并启动接收线程：响应交给等待中的 channel，同时更新 cache
+    fn open(
+        server_addr: &str,
+        response_channels: &ResponseChannels,
+        cache: &Arc<CacheStore>,
+        parse_errors: &ParseErrors,
+    ) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(server_addr)?;
+        stream.set_nodelay(true)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let response_channels = Arc::clone(response_channels);
+        let cache = Arc::clone(cache);
+        let parse_errors = Arc::clone(parse_errors);
+        let state = Arc::new(LinkState {
+            alive: AtomicBool::new(true),
+            closing: AtomicBool::new(false),
+            last_sent: AtomicU64::new(0),
+        });
+        let link = Arc::clone(&state);
+
+        let receiver = thread::spawn(move || {
+            // socket 被关闭（EOF）或出错时线程退出
+            for line in reader.lines().map_while(Result::ok) {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+
+                match serde_json::from_str::<Response>(line) {
+                    Ok(resp) => {
+                        // 先更新cache再唤醒等待的请求，get_path 返回后紧接着的查询一定能命中
+                        cache.insert_all(response_listings(&resp));
+
+                        // 检查是否有等待的channel
+                        let mut channels = response_channels.lock().unwrap();
+                        if let Some(sender) = channels.remove(&resp.id) {
+                            let _ = sender.send(Ok(resp));
+                        }
+                    }
+                    Err(e) => {
+                        let raw: String = line.chars().take(MAX_RAW_IN_ERROR).collect();
+                        let message = format!("{} (raw: {})", e, raw);
+                        eprintln!("Failed to parse response: {}", message);
+                        {
+                            let mut errors = parse_errors.lock().unwrap();
+                            if errors.len() == MAX_PARSE_ERRORS {
+                                errors.pop_front();
+                            }
+                            errors.push_back(message.clone());
+                        }
+
+                        // 让等待的请求立即失败而不是超时：能取到 id 就用它，否则算在最近发出的请求上
+                        let id = serde_json::from_str::<serde_json::Value>(line)
+                            .ok()
+                            .and_then(|value| value.get("id")?.as_u64());
+                        let mut channels = response_channels.lock().unwrap();
+                        let id = id.or_else(|| channels.keys().max().copied());
+                        if let Some(sender) = id.and_then(|id| channels.remove(&id)) {
+                            let _ = sender.send(Err(ClientError::BadResponse(message)));
+                        }
+                    }
+                }
+            }
+
+            // 服务器断开（例如重启）：这条连接上还没收到响应的请求不会再有响应，立即失败而不是等到超时
+            link.alive.store(false, Ordering::SeqCst);
+            if !link.closing.load(Ordering::SeqCst) {
+                let last_sent = link.last_sent.load(Ordering::SeqCst);
+                let mut channels = response_channels.lock().unwrap();
+                let lost: Vec<u64> = channels
+                    .keys()
+                    .copied()
+                    .filter(|id| *id <= last_sent)
+                    .collect();
+                for id in lost {
+                    if let Some(sender) = channels.remove(&id) {
+                        let _ = sender.send(Err(ClientError::Disconnected));
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            writer: stream,
+            receiver,
+            state,
+        })
+    }
+
+    fn send_line(&mut self, id: u64, line: &str) -> std::io::Result<()> {
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()?;
+        self.state.last_sent.fetch_max(id, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn is_alive(&self) -> bool {
+        self.state.alive.load(Ordering::SeqCst)
+    }
+
+    // 关闭 socket 并等待接收线程退出：shutdown 之后阻塞中的读取立即返回 EOF
+    fn close(self) {
+        self.state.closing.store(true, Ordering::SeqCst);
+        let _ = self.writer.shutdown(std::net::Shutdown::Both);
+        let _ = self.receiver.join();
+    }
+}
+
+// 等待服务器响应的默认超时
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+// 不存在的路径默认记住多久
+pub const DEFAULT_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(5);
+
+// connect_when_ready 探测 health 的间隔，从 READY_PROBE_INITIAL 开始每次翻倍，最多 READY_PROBE_MAX
+const READY_PROBE_INITIAL: Duration = Duration::from_millis(50);
+const READY_PROBE_MAX: Duration = Duration::from_secs(1);
+
+// new_with_cache 的选项；需要更多设置时用 Client::builder()
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    // 等待服务器响应的超时，默认 5 秒；None 或 Duration::ZERO 表示一直等待
+    pub timeout: Option<Duration>,
+    // cache 中的列表写入超过这个时间后视为未命中，默认 None 永不过期
+    pub cache_ttl: Option<Duration>,
+    // cache 中最多保留的目录数，超出时淘汰最久没有访问的；None 表示不限制
+    pub max_cache_entries: Option<usize>,
+    // cache 文件所在目录，None 时使用环境变量 LAZYSYNC_CACHE_DIR，都没有时为 ~/.lazysync/cache
+    pub cache_dir: Option<PathBuf>,
+    // cache 文件的格式，默认 CacheFormat::Binary；调试时可改为 Json
+    pub cache_format: CacheFormat,
+    // 服务器确认不存在的路径在这段时间内直接返回 NotFound，不再请求；默认 5 秒，None 表示不记录
+    pub negative_cache_ttl: Option<Duration>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Some(DEFAULT_RESPONSE_TIMEOUT),
+            cache_ttl: None,
+            max_cache_entries: Some(DEFAULT_MAX_CACHE_ENTRIES),
+            cache_dir: None,
+            cache_format: CacheFormat::default(),
+            negative_cache_ttl: Some(DEFAULT_NEGATIVE_CACHE_TTL),
+        }
+    }
+}
+
+// 连接断开（或被空闲超时关闭）后重新连接时，连接失败最多再重试 max_retries 次，每次之前等待 backoff
+// 默认不重试，与之前只重新连接一次的行为一致
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReconnectPolicy {
+    pub max_retries: u32,
+    pub backoff: Duration,
+}
+
+// Client 的配置；Client::new / new_with_cache 是它的简写
+//
+//     let client = Client::builder()
+//         .timeout(Duration::from_secs(30))
+//         .cache_dir("/tmp/lazysync-cache")
+//         .connect("127.0.0.1:9000")?;
+#[derive(Clone)]
+pub struct ClientBuilder {
+    timeout: Option<Duration>,
+    cache_dir: Option<PathBuf>,
+    hashed_cache: bool,
+    reconnect_policy: ReconnectPolicy,
+    concurrency_limit: Option<usize>,
+    cache_ttl: Option<Duration>,
+    max_cache_entries: Option<usize>,
+    get_path_hook: Option<GetPathHook>,
+    upload_queue_path: Option<PathBuf>,
+    no_cache: bool,
+    cache_format: CacheFormat,
+    negative_cache_ttl: Option<Duration>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        Self {
+            timeout: Some(DEFAULT_RESPONSE_TIMEOUT),
+            cache_dir: None,
+            hashed_cache: false,
+            reconnect_policy: ReconnectPolicy::default(),
+            concurrency_limit: None,
+            cache_ttl: None,
+            max_cache_entries: Some(DEFAULT_MAX_CACHE_ENTRIES),
+            get_path_hook: None,
+            upload_queue_path: None,
+            no_cache: false,
+            cache_format: CacheFormat::default(),
+            negative_cache_ttl: Some(DEFAULT_NEGATIVE_CACHE_TTL),
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 等待服务器响应的超时，默认 5 秒；Duration::ZERO 表示一直等待
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout).filter(|timeout| !timeout.is_zero());
+        self
+    }
+
+    pub fn config(mut self, config: ClientConfig) -> Self {
+        self.timeout = config.timeout.filter(|timeout| !timeout.is_zero());
+        self.cache_ttl = config.cache_ttl;
+        self.max_cache_entries = config.max_cache_entries;
+        self.cache_dir = config.cache_dir;
+        self.cache_format = config.cache_format;
+        self.negative_cache_ttl = config.negative_cache_ttl.filter(|ttl| !ttl.is_zero());
+        self
+    }
+
+    // cache 文件所在目录，默认为环境变量 LAZYSYNC_CACHE_DIR，没有设置时为 ~/.lazysync/cache；
+    // 不存在时自动创建，启动时会清空其中的文件
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    // cache 文件的格式，默认 CacheFormat::Binary（cache.bin）；CacheFormat::Json 写成可读的 cache.json
+    pub fn cache_format(mut self, format: CacheFormat) -> Self {
+        self.cache_format = format;
+        self
+    }
+
+    // 完全不使用 cache：get_path 每次都向服务器请求，不创建、不清空也不写 cache 目录，
+    // 适合测试和总是要最新数据的场景
+    pub fn no_cache(mut self) -> Self {
+        self.no_cache = true;
+        self
+    }
+
+    // cache 文件名带上随机后缀，多个客户端共用一个目录时互不覆盖
+    pub fn hashed_cache(mut self, hashed: bool) -> Self {
+        self.hashed_cache = hashed;
+        self
+    }
+
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    // 同时等待响应的请求数上限，超出的请求排队；默认不限制
+    pub fn concurrency_limit(mut self, limit: usize) -> Self {
+        self.concurrency_limit = Some(limit.max(1));
+        self
+    }
+
+    // cache 中的列表超过这个时间后视为未命中，重新向服务器请求；默认永不过期
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    // 服务器确认不存在的路径在 ttl 内再次请求时直接返回 ClientError::NotFound，
+    // 默认 DEFAULT_NEGATIVE_CACHE_TTL；Duration::ZERO 表示不记录
+    pub fn negative_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_cache_ttl = Some(ttl).filter(|ttl| !ttl.is_zero());
+        self
+    }
+
+    // cache 中最多保留的目录数，默认 DEFAULT_MAX_CACHE_ENTRIES；超出时淘汰最久没有访问的目录
+    pub fn max_cache_entries(mut self, max_entries: usize) -> Self {
+        self.max_cache_entries = Some(max_entries);
+        self
+    }
+
+    // 每次 get_path / get_dir 结束时调用，参数包含耗时、来源（cache 或网络）、条目数和错误，
+    // 用于自己统计延迟分布；在调用线程上同步执行，回调应尽快返回
+    pub fn on_get_path(mut self, hook: impl Fn(&GetPathEvent) + Send + Sync + 'static) -> Self {
+        self.get_path_hook = Some(Arc::new(hook));
+        self
+    }
+
+    // 保存上传队列的文件，默认 ~/.lazysync/uploads.json；多个客户端不要共用同一个文件
+    #[cfg(feature = "durable-uploads")]
+    pub fn upload_queue_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.upload_queue_path = Some(path.into());
+        self
+    }
+
+    // 先反复探测服务器的 health，直到有响应或超过 timeout，再建立连接；
+    // 用于服务器和客户端同时启动、服务器还没开始监听的情况。超时返回 ClientError::Timeout
+    pub async fn connect_when_ready(
+        self,
+        server_addr: &str,
+        timeout: Duration,
+    ) -> Result<Client, ClientError> {
+        let started = Instant::now();
+        let mut interval = READY_PROBE_INITIAL;
+        loop {
+            let remaining = timeout.saturating_sub(started.elapsed());
+            let probe = async {
+                let mut rpc = LazySyncClient::connect(format!("http://{}", server_addr)).await?;
+                rpc.health(HealthRequest {}).await?;
+                Ok::<_, ClientError>(())
+            };
+            if let Ok(Ok(())) = tokio::time::timeout(remaining, probe).await {
+                return Ok(self.connect(server_addr)?);
+            }
+            let remaining = timeout.saturating_sub(started.elapsed());
+            if remaining.is_zero() {
+                return Err(ClientError::Timeout {
+                    path: server_addr.to_string(),
+                    elapsed: started.elapsed(),
+                });
+            }
+            tokio::time::sleep(interval.min(remaining)).await;
+            interval = (interval * 2).min(READY_PROBE_MAX);
+        }
+    }
+
+    // 打开 cache 并启动写文件的后台线程；no_cache 时没有后台线程
+    // cache 目录建不起来或清不掉（例如 $HOME 只读）时不算错误，只打印警告，cache 只留在内存中
+    fn open_cache(&self) -> (Arc<CacheStore>, Option<thread::JoinHandle<()>>) {
+        let cache_path = if self.no_cache {
+            None
+        } else {
+            let cache_dir = self.cache_dir.clone().unwrap_or_else(cache_dir);
+            match init_cache_path(cache_dir.clone(), self.hashed_cache, self.cache_format) {
+                Ok(path) => Some(path),
+                Err(e) => {
+                    eprintln!(
+                        "Warning: cache directory {} is not usable ({}), keeping the cache in memory only",
+                        cache_dir.display(),
+                        e
+                    );
+                    None
+                }
+            }
+        };
+        let cache = Arc::new(CacheStore::open(
+            !self.no_cache,
+            cache_path,
+            self.cache_format,
+            self.max_cache_entries,
+        ));
+        let flusher = cache.path.is_some().then(|| {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || cache.run_flusher())
+        });
+        (cache, flusher)
+    }
+
+    // 只走 gRPC 的客户端，见 GrpcClient；只使用 cache 相关的设置和 timeout
+    pub fn connect_grpc(self, server_addr: &str) -> std::io::Result<GrpcClient> {
+        let (cache, flusher) = self.open_cache();
+        Ok(GrpcClient {
+            server_addr: server_addr.to_string(),
+            rpc: tokio::sync::Mutex::new(None),
+            cache,
+            cache_flusher: Mutex::new(flusher),
+            timeout: self.timeout,
+            cache_ttl: self.cache_ttl,
+        })
+    }
+
+    pub fn connect(self, server_addr: &str) -> std::io::Result<Client> {
+        let (cache, flusher) = self.open_cache();
+        let upload_store = cfg!(feature = "durable-uploads").then(|| {
+            self.upload_queue_path.unwrap_or_else(|| {
+                default_upload_queue_path(&self.cache_dir.clone().unwrap_or_else(cache_dir))
+            })
+        });
+        let uploads = Arc::new(UploadQueue::open(upload_store));
+        // 上次没上传完的任务
+        uploads.ensure_worker(&mut uploads.state.lock().unwrap(), server_addr);
+        let client = Client {
+            conn: Arc::new(Mutex::new(None)),
+            last_used: Arc::new(Mutex::new(Instant::now())),
+            recent: Mutex::new(RecentPaths::default()),
+            req_id: Arc::new(Mutex::new(0u64)),
+            response_channels: Arc::new(Mutex::new(HashMap::new())),
+            parse_errors: Arc::new(Mutex::new(VecDeque::new())),
+            cache,
+            cache_flusher: Mutex::new(flusher),
+            uploads,
+            server_addr: server_addr.to_string(),
+            rpc: tokio::sync::Mutex::new(None),
+            local_hashes: Mutex::new(HashMap::new()),
+            timeout: self.timeout,
+            reconnect_policy: self.reconnect_policy,
+            in_flight: self
+                .concurrency_limit
+                .map(|limit| Arc::new(tokio::sync::Semaphore::new(limit))),
+            cache_ttl: self.cache_ttl,
+            negative_cache_ttl: self.negative_cache_ttl,
+            get_path_hook: self.get_path_hook,
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            since_tokens: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        };
+        let conn = client.connect()?;
+        *client.conn.lock().unwrap() = Some(conn);
+        Ok(client)
+    }
+}
+
+pub struct Client {
+    // TCP 连接；空闲超时关闭后为 None，下次请求时重新连接
+    conn: Arc<Mutex<Option<Connection>>>,
+    last_used: Arc<Mutex<Instant>>,
+    // get_path 请求过的路径，供“最近位置”使用
+    recent: Mutex<RecentPaths>,
+    req_id: Arc<Mutex<u64>>,
+    response_channels: ResponseChannels,
+    parse_errors: ParseErrors,
+    cache: Arc<CacheStore>,
+    // 把 cache 写回文件的后台线程，close 时等它写完最后一次
+    cache_flusher: Mutex<Option<thread::JoinHandle<()>>>,
+    uploads: Arc<UploadQueue>,
+    server_addr: String,
+    // gRPC 连接在第一次调用时建立，之后复用
+    rpc: tokio::sync::Mutex<Option<LazySyncClient<Channel>>>,
+    // mirror 算过的本地文件哈希，mtime 变了才重新计算
+    local_hashes: Mutex<HashMap<PathBuf, (SystemTime, String)>>,
+    // None 表示一直等待响应
+    timeout: Option<Duration>,
+    reconnect_policy: ReconnectPolicy,
+    // concurrency_limit 设置时限制同时等待响应的请求数
+    in_flight: Option<Arc<tokio::sync::Semaphore>>,
+    cache_ttl: Option<Duration>,
+    negative_cache_ttl: Option<Duration>,
+    get_path_hook: Option<GetPathHook>,
+    // get_path / get_dir 命中和未命中 cache 的次数
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    // get_path_within 最近一次完整结果的 since_token，按路径保存，只在内存里
+    since_tokens: Mutex<HashMap<String, String>>,
+    // close 之后不再重新连接
+    closed: AtomicBool,
+}
+
+impl Client {
+    pub fn new(server_addr: &str) -> std::io::Result<Self> {
+        Self::builder().connect(server_addr)
+    }
+
+    pub fn new_with_cache(
+        server_addr: &str,
+        is_hash: bool,
+        config: ClientConfig,
+    ) -> std::io::Result<Self> {
+        Self::builder()
+            .hashed_cache(is_hash)
+            .config(config)
+            .connect(server_addr)
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    // 等服务器可用后再连接，见 ClientBuilder::connect_when_ready
+    pub async fn connect_when_ready(
+        server_addr: &str,
+        timeout: Duration,
+    ) -> Result<Self, ClientError> {
+        Self::builder()
+            .connect_when_ready(server_addr, timeout)
+            .await
+    }
+
+    // 开启空闲超时（默认关闭）：超过 timeout 没有 get_path / request_path 且没有等待中的请求时，
+    // 主动关闭 TCP 连接和接收线程；下次请求时自动重新连接
+    pub fn with_idle_timeout(self, timeout: Duration) -> Self {
+        let conn = Arc::downgrade(&self.conn);
+        let last_used = Arc::clone(&self.last_used);
+        let response_channels = Arc::clone(&self.response_channels);
+        thread::spawn(move || loop {
+            thread::sleep(IDLE_CHECK_INTERVAL.min(timeout));
+            // Client 已释放
+            let Some(conn) = conn.upgrade() else {
+                break;
+            };
+            let idle = last_used.lock().unwrap().elapsed() >= timeout;
+            let waiting = !response_channels.lock().unwrap().is_empty();
+            if idle && !waiting {
+                if let Some(open) = conn.lock().unwrap().take() {
+                    open.close();
+                }
+            }
+        });
+        self
+    }
+
+    // 按 key 取 cache 中的目录列表
+    fn cached_listings(&self, keys: &[&str]) -> Vec<Option<Vec<FileEntry>>> {
+        self.cache.get_many(keys)
+    }
+
+    // 发送一行请求；连接已被关闭（空闲超时或服务器断开）时重新连接后再发
+    fn send_request(&self, req: &Request) -> std::io::Result<()> {
+        let line = serde_json::to_string(req)?;
+        let mut conn = self.conn.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "client closed",
+            ));
+        }
+        *self.last_used.lock().unwrap() = Instant::now();
+
+        // 接收线程已退出说明服务器断开了，不再往这条连接上写
+        if let Some(open) = conn.as_mut() {
+            if open.is_alive() && open.send_line(req.id, &line).is_ok() {
+                return Ok(());
+            }
+            if let Some(broken) = conn.take() {
+                broken.close();
+            }
+        }
+
+        let mut fresh = self.connect()?;
+        fresh.send_line(req.id, &line)?;
+        *conn = Some(fresh);
+        Ok(())
+    }
+
+    // 按 reconnect_policy 建立连接，失败时等待 backoff 后重试
+    fn connect(&self) -> std::io::Result<Connection> {
+        let mut retries = 0;
+        loop {
+            match Connection::open(
+                &self.server_addr,
+                &self.response_channels,
+                &self.cache,
+                &self.parse_errors,
+            ) {
+                Ok(conn) => return Ok(conn),
+                Err(e) if retries >= self.reconnect_policy.max_retries => return Err(e),
+                Err(_) => {
+                    retries += 1;
+                    thread::sleep(self.reconnect_policy.backoff);
+                }
+            }
+        }
+    }
+
+    async fn rpc(&self) -> Result<LazySyncClient<Channel>, ClientError> {
+        let mut rpc = self.rpc.lock().await;
+        if let Some(client) = rpc.as_ref() {
+            return Ok(client.clone());
+        }
+        let client = LazySyncClient::connect(format!("http://{}", self.server_addr)).await?;
+        *rpc = Some(client.clone());
+        Ok(client)
+    }
+
+    // 只查询单个路径的信息，不列出父目录；不存在时返回 None。结果和 get_path 一样经过 normalize_entry
+    pub async fn stat(&self, path: &str) -> Result<Option<FileEntry>, ClientError> {
+        let mut rpc = self.rpc().await?;
+        let resp = rpc
+            .stat(StatRequest {
+                path: NormalizedPath::from(path).into_string(),
+            })
+            .await?
+            .into_inner();
+        Ok(resp.info.filter(|_| resp.exists).map(FileEntry::from))
+    }
+
+    // 轮询 stat，直到路径存在且满足 predicate（例如达到某个大小、mtime 变化），或超时
+    pub async fn wait_for<F>(
+        &self,
+        path: &str,
+        predicate: F,
+        timeout: Duration,
+    ) -> Result<FileEntry, ClientError>
+    where
+        F: Fn(&FileEntry) -> bool,
+    {
+        let started = tokio::time::Instant::now();
+        loop {
+            if let Some(entry) = self.stat(path).await? {
+                if predicate(&entry) {
+                    return Ok(entry);
+                }
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                return Err(ClientError::Timeout {
+                    path: path.to_string(),
+                    elapsed,
+                });
+            }
+            tokio::time::sleep(WAIT_POLL_INTERVAL.min(timeout - elapsed)).await;
+        }
+    }
+
+    pub async fn wait_for_exists(
+        &self,
+        path: &str,
+        timeout: Duration,
+    ) -> Result<FileEntry, ClientError> {
+        self.wait_for(path, |_| true, timeout).await
+    }
+
+    // 服务器 health RPC 返回的状态，正常时为 "ok"
+    pub async fn health(&self) -> Result<String, ClientError> {
+        self.health_detailed().await.map(|health| health.status)
+    }
+
+    // 服务器有响应且状态为 "ok" 时返回 Ok(())；适合在正式请求前或重新连接时做存活检查
+    pub async fn ping(&self) -> Result<(), ClientError> {
+        match self.health().await? {
+            status if status == "ok" => Ok(()),
+            status => Err(ClientError::Request(format!("Server unhealthy: {}", status))),
+        }
+    }
+
+    pub async fn health_detailed(&self) -> Result<ServerHealth, ClientError> {
+        let mut rpc = self.rpc().await?;
+        let resp = rpc.health(HealthRequest {}).await?.into_inner();
+        Ok(ServerHealth {
+            status: resp.status,
+            uptime_secs: resp.uptime_secs,
+            active_streams: resp.active_streams,
+            version: resp.version,
+            read_only: resp.read_only,
+            no_follow_symlinks: resp.no_follow_symlinks,
+            identity: resp.identity_known.then_some(ServerIdentity {
+                uid: resp.uid,
+                gid: resp.gid,
+                groups: resp.groups,
+            }),
+        })
+    }
+
+    // 预测服务器能否写 path：存在时看它自己的权限位（符号链接看目标），不存在时看父目录能否创建文件；
+    // 服务器只读时为 false。只按权限位和服务器的 uid / gid 推断，ACL、只读挂载、
+    // 不可变属性等都不考虑，结果仅供启用 / 禁用 UI 上的写操作参考，实际写入仍可能失败
+    pub async fn can_write(&self, path: &str) -> Result<bool, ClientError> {
+        let health = self.health_detailed().await?;
+        if health.read_only {
+            return Ok(false);
+        }
+        let Some(identity) = health.identity else {
+            return Err(ClientError::Request(
+                "Server does not report its uid/gid".to_string(),
+            ));
+        };
+
+        let path = NormalizedPath::from(path);
+        let rpc = self.rpc().await?;
+        let stat = |path: String| {
+            let mut rpc = rpc.clone();
+            async move { Ok::<_, ClientError>(rpc.stat(StatRequest { path }).await?.into_inner()) }
+        };
+        let resp = stat(path.to_string()).await?;
+        let (resp, need_exec) = if resp.exists {
+            (resp, false)
+        } else {
+            let Some((parent, _)) = parent_and_name(path.as_str()) else {
+                return Err(ClientError::NotFound(path.to_string()));
+            };
+            let parent = stat(parent.to_string()).await?;
+            if !parent.exists {
+                return Err(ClientError::NotFound(path.to_string()));
+            }
+            (parent, true)
+        };
+        let resp = match resp.info.as_ref() {
+            Some(info) if info.file_type == "symlink" && !resp.resolved_path.is_empty() => {
+                stat(resp.resolved_path.clone()).await?
+            }
+            _ => resp,
+        };
+        let Some(info) = resp.info.filter(|_| resp.exists) else {
+            return Ok(false);
+        };
+        // 新建目录下的文件需要目录的 w 和 x；写已存在的目录本身（在其中创建）同样需要 x
+        let need_exec = need_exec || info.file_type == "dir";
+        Ok(identity.can_write(&info, need_exec))
+    }
+
+    // 从任意 AsyncRead 读取数据并通过 write_file 上传到 remote_path 的 offset 处
+    // 数据源读完后发送 eof，返回服务器确认写入的字节数
+    pub async fn write_stream<R: AsyncRead + Unpin>(
+        &self,
+        remote_path: &str,
+        reader: R,
+        offset: u64,
+    ) -> Result<u64, ClientError> {
+        self.write_chunks(remote_path, reader, offset, false, false)
+            .await
+    }
+
+    // 同 write_stream，但只在 remote_path 不存在时创建并写入；已存在时返回 AlreadyExists 的 Rpc 错误，
+    // 可用于“不存在才上传”和简单的锁文件。中途失败时服务器会删掉已创建的文件
+    pub async fn write_stream_exclusive<R: AsyncRead + Unpin>(
+        &self,
+        remote_path: &str,
+        reader: R,
+    ) -> Result<u64, ClientError> {
+        self.write_chunks(remote_path, reader, 0, true, false).await
+    }
+
+    // 把 data 的全部内容从头写到 path，已存在的文件先截断（空的 data 也会发送 eof，服务器会创建空文件），
+    // 返回服务器确认写入的字节数；结束后从 cache 删除父目录的列表，下次 get_path 能看到新的大小
+    pub async fn write_file<R: AsyncRead + Unpin>(
+        &self,
+        path: &str,
+        data: R,
+    ) -> Result<u64, ClientError> {
+        let path = NormalizedPath::from(path);
+        let written = self.write_chunks(path.as_str(), data, 0, false, true).await;
+        if let Some((parent, _)) = parent_and_name(path.as_str()) {
+            self.invalidate(parent);
+        }
+        written
+    }
+
+    // 让服务器 fsync 路径（recursive 时包括其下所有文件和目录），返回同步的文件数；
+    // 适合先快速写完一批文件，最后统一落盘一次
+    pub async fn sync_path(&self, path: &str, recursive: bool) -> Result<u64, ClientError> {
+        let mut rpc = self.rpc().await?;
+        let resp = rpc
+            .sync_path(SyncPathRequest {
+                path: NormalizedPath::from(path).into_string(),
+                recursive,
+                stay_on_filesystem: false,
+            })
+            .await?
+            .into_inner();
+        Ok(resp.files_synced)
+    }
+
+    // 服务器上的挂载点，按 /proc/mounts 的顺序；include_pseudo 为 false 时不包括 proc、sysfs、
+    // tmpfs 等没有实际存储的文件系统。只支持 Linux 服务器
+    pub async fn list_mounts(&self, include_pseudo: bool) -> Result<Vec<Mount>, ClientError> {
+        let mut rpc = self.rpc().await?;
+        let resp = rpc
+            .list_mounts(ListMountsRequest { include_pseudo })
+            .await?
+            .into_inner();
+        Ok(resp
+            .mounts
+            .into_iter()
+            .map(|mount| Mount {
+                device: mount.device,
+                mount_point: mount.mount_point,
+                fs_type: mount.fs_type,
+                total_bytes: mount.total_bytes,
+                free_bytes: mount.free_bytes,
+                read_only: mount.read_only,
+            })
+            .collect())
+    }
+
+    // 在服务器上按 glob 搜索 root 下的路径，pattern 相对于 root，例如 "src/**/*.rs"；
+    // * ? [...] 不跨越 /，** 匹配任意层目录。max_results 为 0 时使用服务器默认值
+    pub async fn search(
+        &self,
+        root: &str,
+        pattern: &str,
+        max_results: u32,
+    ) -> Result<SearchResults, ClientError> {
+        let mut rpc = self.rpc().await?;
+        let resp = rpc
+            .search(SearchRequest {
+                root: NormalizedPath::from(root).into_string(),
+                pattern: pattern.to_string(),
+                max_results,
+                stay_on_filesystem: false,
+            })
+            .await?
+            .into_inner();
+        let matches = resp
+            .matches
+            .into_iter()
+            .map(|info| (info.absolute_path.clone(), FileEntry::from(info)))
+            .collect();
+        Ok(SearchResults {
+            matches,
+            truncated: resp.truncated,
+        })
+    }
+
+    async fn write_chunks<R: AsyncRead + Unpin>(
+        &self,
+        remote_path: &str,
+        reader: R,
+        offset: u64,
+        exclusive: bool,
+        truncate: bool,
+    ) -> Result<u64, ClientError> {
+        let rpc = self.rpc().await?;
+        upload_chunks(rpc, remote_path, reader, offset, exclusive, truncate).await
+    }
+
+    // 把本地文件加入后台上传队列后立即返回任务 id；后台线程按加入顺序逐个用 write_file 上传，
+    // 失败时重试，结果通过 upload_status 查看。开启 durable-uploads feature 时队列保存在文件里，
+    // 重启后继续上传没完成的任务
+    pub fn enqueue_upload(&self, local_path: impl Into<PathBuf>, remote_path: &str) -> u64 {
+        let mut state = self.uploads.state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.pending.push_back(UploadJob {
+            id,
+            local_path: local_path.into(),
+            remote_path: NormalizedPath::from(remote_path).into_string(),
+        });
+        self.uploads.save(&state);
+        self.uploads.ensure_worker(&mut state, &self.server_addr);
+        self.uploads.wake.notify_all();
+        id
+    }
+
+    pub fn upload_status(&self) -> UploadQueueStatus {
+        let state = self.uploads.state.lock().unwrap();
+        UploadQueueStatus {
+            pending: state.pending.iter().cloned().collect(),
+            completed: state.completed,
+            failed: state.failed.clone(),
+        }
+    }
+
+    // 在服务器上把 from 复制到 to（目录需要 recursive），每条进度消息调用一次 on_progress，
+    // 返回复制的总字节数。结束后（包括中途失败）从 cache 删除 to 及其父目录的列表，
+    // 下次 get_path 会重新获取；中途失败时返回服务器给出的错误
+    pub async fn copy(
+        &self,
+        from: &str,
+        to: &str,
+        recursive: bool,
+        mut on_progress: impl FnMut(&CopyProgress),
+    ) -> Result<u64, ClientError> {
+        let mut rpc = self.rpc().await?;
+        let to = NormalizedPath::from(to);
+        let mut stream = rpc
+            .copy_path(CopyRequest {
+                from: NormalizedPath::from(from).into_string(),
+                to: to.to_string(),
+                recursive,
+                dereference: false,
+            })
+            .await?
+            .into_inner();
+
+        let mut last = None;
+        while let Some(progress) = stream.message().await? {
+            on_progress(&progress);
+            if progress.done {
+                last = Some(progress);
+                break;
+            }
+        }
+
+        self.invalidate(to.as_str());
+        if let Some((parent, _)) = parent_and_name(to.as_str()) {
+            self.invalidate(parent);
+        }
+        match last {
+            Some(progress) if progress.error.is_empty() => Ok(progress.bytes_copied),
+            Some(progress) => Err(ClientError::Request(progress.error)),
+            None => Err(ClientError::Request(
+                "copy stream ended before completion".to_string(),
+            )),
+        }
+    }
+
+    // 不关心进度时使用的 copy
+    pub async fn copy_path(
+        &self,
+        from: &str,
+        to: &str,
+        recursive: bool,
+    ) -> Result<u64, ClientError> {
+        self.copy(from, to, recursive, |_| {}).await
+    }
+
+    // 在后台把文件下载到内容 cache（受 concurrency_limit 限制），之后 cached_file 可以直接拿到本地副本；
+    // cache 中已有大小和 mtime 都没变的副本时不再下载。no_cache 时句柄返回错误。需要在 tokio 运行时中调用
+    pub async fn prefetch_file(&self, path: &str) -> PrefetchHandle {
+        let remote = NormalizedPath::from(path).into_string();
+        let dir = self.cache.path.as_deref().map(content_cache_dir);
+        let rpc = self.rpc().await;
+        let in_flight = self.in_flight.clone();
+        let task_path = remote.clone();
+        let task = tokio::spawn(async move {
+            let Some(dir) = dir else {
+                return Err(ClientError::Request(
+                    "Content cache is disabled (no_cache)".to_string(),
+                ));
+            };
+            prefetch_content(rpc?, dir, task_path, in_flight).await
+        });
+        PrefetchHandle { path: remote, task }
+    }
+
+    // 对每个路径调用 prefetch_file，句柄顺序与 paths 相同；同时下载的数量仍受 concurrency_limit 限制
+    pub async fn prefetch_files(&self, paths: &[&str]) -> Vec<PrefetchHandle> {
+        let mut handles = Vec::with_capacity(paths.len());
+        for path in paths {
+            handles.push(self.prefetch_file(path).await);
+        }
+        handles
+    }
+
+    // 内容 cache 中 path 的本地副本；会 stat 一次服务器确认文件没有变化，没有副本或已过期时返回 None
+    pub async fn cached_file(&self, path: &str) -> Result<Option<PathBuf>, ClientError> {
+        let Some(dir) = self.cache.path.as_deref().map(content_cache_dir) else {
+            return Ok(None);
+        };
+        let remote = NormalizedPath::from(path).into_string();
+        let mut rpc = self.rpc().await?;
+        let info = stat_file(&mut rpc, &remote).await?;
+        Ok(valid_content(&dir, &remote, &info))
+    }
+
+    // 从 cache 中删除一个目录的列表（路径按 get_path 的方式规范化），下次 get_path 会重新向服务器请求
+    // 返回之前是否缓存了它；cache 文件由后台线程稍后写入，close 时一定写完
+    pub fn invalidate(&self, path: &str) -> bool {
+        self.cache.remove(NormalizedPath::from(path).as_str())
+    }
+
+    // 清空 cache 中的所有目录列表，cache 文件保留（内容为空）
+    pub fn invalidate_all(&self) {
+        self.cache.clear();
+    }
+
+    // 让服务器在 root 之下（为空时为所有路径）的目录被它的 RPC 改动后推送失效事件，收到后从 cache 删除该目录，
+    // 长时间运行的客户端不用靠 TTL 猜测 cache 是否过期。服务器之外的改动不会推送；
+    // 返回的任务在连接断开时结束，abort 它可以提前取消订阅
+    pub async fn subscribe_invalidations(
+        &self,
+        root: &str,
+    ) -> Result<tokio::task::JoinHandle<()>, ClientError> {
+        let rpc = self.rpc().await?;
+        subscribe_invalidations(rpc, Arc::clone(&self.cache), root).await
+    }
+
+    // 读取 [offset, offset + length) 字节并解码为文本；length 为 0 表示读到 MAX_READ_TEXT 为止
+    pub async fn read_text(
+        &self,
+        path: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<DecodedText, ClientError> {
+        let mut rpc = self.rpc().await?;
+        read_text_with(
+            &mut rpc,
+            NormalizedPath::from(path).as_str(),
+            offset,
+            length,
+        )
+        .await
+    }
+
+    // 流式读取 [offset, offset + length)，按顺序产出服务器发来的每个 chunk；length 为 0 表示读到文件末尾
+    // 收到 eof 的 chunk 后结束，服务器返回错误状态时产出一个错误后结束
+    // 内容 cache 中有 prefetch_file 下载的有效副本时直接从本地读取
+    pub async fn read_file(
+        &self,
+        path: &str,
+        offset: u64,
+        length: u64,
+    ) -> impl tokio_stream::Stream<Item = Result<Vec<u8>, ClientError>> {
+        let remote = NormalizedPath::from(path).into_string();
+        let dir = self.cache.path.as_deref().map(content_cache_dir);
+        let rpc = self.rpc().await;
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            let sent = async {
+                let mut rpc = rpc?;
+                if let Some(dir) = dir.filter(|dir| content_paths(dir, &remote).1.exists()) {
+                    let info = stat_file(&mut rpc, &remote).await?;
+                    if let Some(data) = valid_content(&dir, &remote, &info) {
+                        return send_local_range(&data, offset, length, &tx).await;
+                    }
+                }
+                let mut stream = rpc
+                    .read_file(ReadFileRequest {
+                        path: remote.clone(),
+                        offset,
+                        length,
+                        ..Default::default()
+                    })
+                    .await?
+                    .into_inner();
+                while let Some(chunk) = stream.message().await? {
+                    if !chunk.data.is_empty() && tx.send(Ok(chunk.data)).await.is_err() {
+                        break;
+                    }
+                    if chunk.eof {
+                        break;
+                    }
+                }
+                Ok::<_, ClientError>(())
+            };
+            if let Err(e) = sent.await {
+                let _ = tx.send(Err(e)).await;
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+
+    // 把 read_file 的结果全部读进内存，适合小文件
+    pub async fn read_file_to_vec(
+        &self,
+        path: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, ClientError> {
+        use tokio_stream::StreamExt;
+
+        let stream = self.read_file(path, offset, length).await;
+        tokio::pin!(stream);
+        let mut bytes = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            bytes.extend_from_slice(&chunk?);
+        }
+        Ok(bytes)
+    }
+
+    // 通过 gRPC 列目录，服务器超过 deadline 后停止扫描并返回已有部分
+    // 只有完整的结果才写入 cache
+    // cache 里有这个目录时带上上次的 since_token，服务器回复 unchanged 时直接用 cache
+    pub async fn get_path_within(
+        &self,
+        path: &str,
+        deadline: Duration,
+    ) -> Result<PartialListing, ClientError> {
+        let path = NormalizedPath::from(path);
+        let cached = self.cached_listings(&[path.as_str()]).pop().flatten();
+        let since_token = match &cached {
+            Some(_) => self.since_tokens.lock().unwrap().get(path.as_str()).cloned(),
+            None => None,
+        };
+        let mut rpc = self.rpc().await?;
+        let resp = rpc
+            .get_path(GetPathRequest {
+                path: path.to_string(),
+                deadline_ms: deadline.as_millis().max(1) as u64,
+                trace_id: new_trace_id(),
+                since_token: since_token.unwrap_or_default(),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+
+        if let (true, Some(entries)) = (resp.unchanged, cached) {
+            // 重新写入以刷新 cached_at，cache_ttl 从现在重新计算
+            self.cache
+                .insert_all(vec![(path.into_string(), entries.clone())]);
+            return Ok(PartialListing {
+                entries,
+                timed_out: false,
+                truncated: false,
+                entries_scanned: resp.entries_scanned,
+            });
+        }
+        if !resp.since_token.is_empty() {
+            self.since_tokens
+                .lock()
+                .unwrap()
+                .insert(path.to_string(), resp.since_token.clone());
+        }
+
+        let listings = resp
+            .entries
+            .into_iter()
+            .map(|listing| {
+                let entries: Vec<FileEntry> =
+                    listing.entries.into_iter().map(FileEntry::from).collect();
+                (listing.absolute_path, entries)
+            })
+            .collect::<Vec<_>>();
+        let found = find_listing(listings.iter().map(|(k, v)| (k, v)), &path)
+            .map(|entries| entries.to_vec());
+
+        if !resp.timed_out && !resp.truncated {
+            let mut stored: Vec<(String, Vec<FileEntry>)> = listings
+                .into_iter()
+                .map(|(abs_path, listing)| {
+                    let key = NormalizedPath::from(abs_path.as_str()).into_string();
+                    (key, listing)
+                })
+                .collect();
+            if let Some(entries) = &found {
+                stored.push((path.into_string(), entries.clone()));
+            }
+            self.cache.insert_all(stored);
+        }
+
+        Ok(PartialListing {
+            entries: found.unwrap_or_default(),
+            timed_out: resp.timed_out,
+            truncated: resp.truncated,
+            entries_scanned: resp.entries_scanned,
+        })
+    }
+
+    // 由服务器解析路径（相对路径、符号链接），得到可以作为 cache key 的规范形式
+    pub async fn realpath(&self, path: &str) -> Result<ResolvedPath, ClientError> {
+        let mut rpc = self.rpc().await?;
+        let resp = rpc
+            .real_path(RealPathRequest {
+                path: NormalizedPath::from(path).into_string(),
+            })
+            .await?
+            .into_inner();
+        Ok(ResolvedPath {
+            canonical: resp.canonical,
+            exists: resp.exists,
+        })
+    }
+
+    // 逐层列出 root 下的整棵目录树并写入 cache，之后在其中任意位置 get_path 都能命中
+    // max_depth 为 0 时只缓存 root 本身；不进入符号链接目录；请求逐个串行发送
+    // 返回写入 cache 的目录数
+    pub async fn cache_subtree(&self, root: &str, max_depth: usize) -> Result<usize, ClientError> {
+        let mut rpc = self.rpc().await?;
+        let permit = match &self.in_flight {
+            Some(in_flight) => in_flight.acquire().await.ok(),
+            None => None,
+        };
+        let stat = rpc
+            .stat(StatRequest {
+                path: NormalizedPath::from(root).into_string(),
+            })
+            .await?
+            .into_inner();
+        let root = match stat.info {
+            Some(info) if stat.exists => NormalizedPath::from(info.absolute_path.as_str()),
+            _ => return Err(ClientError::NotFound(root.to_string())),
+        };
+        drop(permit);
+
+        // 目录相对 root 的层数，不在 root 下或超过 max_depth 时为 None
+        let depth_of = |dir: &str| match relative_path(root.as_str(), dir)?.as_str() {
+            "." => Some(0),
+            rel if rel.starts_with("..") => None,
+            rel => Some(rel.split('/').count()).filter(|&depth| depth <= max_depth),
+        };
+
+        // 服务器一次返回目录本身和各子目录的列表，已拿到的目录不再重复请求
+        let mut listings: HashMap<String, Vec<FileEntry>> = HashMap::new();
+        let mut pending = VecDeque::from([root.to_string()]);
+        while let Some(dir) = pending.pop_front() {
+            if !listings.contains_key(&dir) {
+                // 和 fetch_dir 一样受 concurrency_limit 限制，每个请求持有一个许可
+                let _permit = match &self.in_flight {
+                    Some(in_flight) => in_flight.acquire().await.ok(),
+                    None => None,
+                };
+                let resp = rpc
+                    .get_path(GetPathRequest {
+                        path: dir.clone(),
+                        trace_id: new_trace_id(),
+                        ..Default::default()
+                    })
+                    .await?
+                    .into_inner();
+                let mut dirs = resp.entries;
+                // 被截断时最后一个列表不完整
+                if resp.truncated {
+                    dirs.pop();
+                }
+                for listing in dirs {
+                    let key = NormalizedPath::from(listing.absolute_path.as_str()).into_string();
+                    if depth_of(&key).is_some() {
+                        let entries = listing.entries.into_iter().map(FileEntry::from).collect();
+                        listings.entry(key).or_insert(entries);
+                    }
+                }
+            }
+
+            let Some(entries) = listings.get(&dir) else {
+                continue;
+            };
+            for entry in entries.iter().filter(|entry| entry.file_type == "dir") {
+                let child = child_path(&dir, &entry.name);
+                if depth_of(&child).is_some() {
+                    pending.push_back(child);
+                }
+            }
+        }
+
+        let cached = listings.len();
+        self.cache.insert_all(listings);
+        Ok(cached)
+    }
+
+    // 只用本地 cache 计算 root 下所有普通文件的大小之和，不发任何请求：
+    // cache 里没有的目录（没浏览过或已被淘汰）直接跳过，所以结果只反映已缓存的部分，可能偏小
+    // 适合显示“已浏览约 X”；需要完整结果时先 cache_subtree
+    pub fn cached_subtree_size(&self, root: &str) -> u64 {
+        self.cache.subtree_size(NormalizedPath::from(root).as_str())
+    }
+
+    // 把远程目录树同步到 local_root：只下载普通文件，不进入符号链接目录
+    // 默认按 FileEntry::changes_from（大小、mtime、权限）判断是否需要下载，下载时一并恢复 mtime
+    // 和权限；verify_content 时大小相同的文件改为比较
+    // 服务器算出的 SHA-256 和本地哈希，只在内容真正不同时下载
+    // dry_run 与真正执行走同一套比较逻辑，只是不下载、不删除
+    pub async fn mirror(
+        &self,
+        remote_root: &str,
+        local_root: &Path,
+        options: MirrorOptions,
+    ) -> Result<MirrorReport, ClientError> {
+        let mut rpc = self.rpc().await?;
+        let mut report = MirrorReport {
+            dry_run: options.dry_run,
+            ..Default::default()
+        };
+        let mut pending =
+            VecDeque::from([(NormalizedPath::from(remote_root), local_root.to_path_buf())]);
+
+        while let Some((dir, local_dir)) = pending.pop_front() {
+            if !options.dry_run {
+                tokio::fs::create_dir_all(&local_dir).await?;
+            }
+            let resp = rpc
+                .get_path(GetPathRequest {
+                    path: dir.to_string(),
+                    trace_id: new_trace_id(),
+                    ..Default::default()
+                })
+                .await?
+                .into_inner();
+            let mut listings = resp
+                .entries
+                .into_iter()
+                .map(|listing| (listing.absolute_path, listing.entries))
+                .collect::<Vec<_>>();
+            // 被截断时最后一个列表不完整，不能拿来同步，更不能据此删除
+            if resp.truncated {
+                listings.pop();
+            }
+            let Some(entries) = find_listing(listings.iter().map(|(k, v)| (k, v)), &dir) else {
+                return Err(ClientError::NotFound(dir.to_string()));
             };
 
-            let mut writer = self.writer.lock().map_err(|e| format!("Lock error: {}", e))?;
-            writeln!(writer, "{}", serde_json::to_string(&req).unwrap())
-                .map_err(|e| format!("Write error: {}", e))?;
-            writer.flush().map_err(|e| format!("Flush error: {}", e))?;
-
-            req_id
-        };
-
-        // 等待响应（最多等待5秒）
-        match tokio::time::timeout(Duration::from_secs(5), rx).await {
-            Ok(Ok(resp)) => {
-                // 从响应数据中查找请求的路径
-                let request_path_buf = std::path::PathBuf::from(&normalized_path);
-                let canonical_request_path = request_path_buf.canonicalize()
-                    .unwrap_or_else(|_| request_path_buf.clone())
-                    .display()
-                    .to_string();
-                
-                let mut found_entries: Vec<FileEntry> = Vec::new();
-                
-                for dir_map in &resp.data {
-                    for (abs_path, file_infos) in dir_map {
-                        let abs_path_buf = std::path::PathBuf::from(abs_path);
-                        let normalized_resp_path = abs_path_buf.canonicalize()
-                            .unwrap_or_else(|_| abs_path_buf.clone())
-                            .display()
-                            .to_string();
-                        
-                        // 规范化响应路径用于比较
-                        let normalized_abs_path = normalize_path(abs_path);
-                        
-                        if abs_path == &normalized_path 
-                            || normalized_abs_path == normalized_path
-                            || abs_path == &canonical_request_path 
-                            || normalized_resp_path == canonical_request_path 
-                            || normalized_resp_path == normalized_path {
-                            found_entries = file_infos.iter().map(|fi| {
-                                let is_dir = fi.permissions.chars().next() == Some('d');
-                                normalize_entry(FileEntry {
-                                    name: fi.name.clone(),
-                                    is_dir,
-                                    file_type: infer_file_type(&fi.file_type, &fi.permissions, is_dir),
-                                    size: fi.size,
-                                    permissions: fi.permissions.clone(),
-                                    modified: fi.modified.clone(),
-                                })
-                            }).collect();
-                            break;
+            for info in entries {
+                // 名字来自服务器，拼到本地路径前必须确认只是一个普通的路径分量
+                if !is_plain_name(&info.name) {
+                    return Err(ClientError::BadResponse(format!(
+                        "unsafe entry name {:?} in {}",
+                        info.name, dir
+                    )));
+                }
+                let remote = if dir.as_str() == "/" {
+                    format!("/{}", info.name)
+                } else {
+                    format!("{}/{}", dir, info.name)
+                };
+                let local = local_dir.join(&info.name);
+                match info.file_type.as_str() {
+                    "dir" => pending.push_back((NormalizedPath::from(remote.as_str()), local)),
+                    "file" => {
+                        let (download, reason) = self
+                            .compare_for_mirror(&mut rpc, &remote, &local, info, options)
+                            .await?;
+                        let action = MirrorAction {
+                            path: remote,
+                            reason: reason.to_string(),
+                        };
+                        if !download {
+                            report.to_skip.push(action);
+                            continue;
                         }
+                        if !options.dry_run {
+                            report.bytes +=
+                                self.download(&mut rpc, &action.path, &local, info).await?;
+                        }
+                        report.to_download.push(action);
                     }
-                    if !found_entries.is_empty() {
-                        break;
+                    _ => {}
+                }
+            }
+
+            if options.delete_extra {
+                let names: HashSet<&str> = entries.iter().map(|info| info.name.as_str()).collect();
+                let Ok(mut local_entries) = tokio::fs::read_dir(&local_dir).await else {
+                    continue;
+                };
+                while let Some(local_entry) = local_entries.next_entry().await? {
+                    let name = local_entry.file_name();
+                    if names.contains(name.to_string_lossy().as_ref()) {
+                        continue;
+                    }
+                    let path = local_entry.path();
+                    if !options.dry_run {
+                        if local_entry.file_type().await?.is_dir() {
+                            tokio::fs::remove_dir_all(&path).await?;
+                        } else {
+                            tokio::fs::remove_file(&path).await?;
+                        }
+                    }
+                    report.to_delete.push(MirrorAction {
+                        path: path.display().to_string(),
+                        reason: "not on server".to_string(),
+                    });
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    // 返回 (是否需要下载, 原因)
+    async fn compare_for_mirror(
+        &self,
+        rpc: &mut LazySyncClient<Channel>,
+        remote: &str,
+        local: &Path,
+        info: &lazysync::FileInfo,
+        options: MirrorOptions,
+    ) -> Result<(bool, &'static str), ClientError> {
+        let Ok(meta) = tokio::fs::metadata(local).await else {
+            return Ok((true, "missing locally"));
+        };
+        if !meta.is_file() {
+            return Ok((true, "not a file locally"));
+        }
+        // 任何一边拿不到模式位（非 Unix 客户端、无法解析的权限字符串）时不比较权限
+        let (remote_mode, local_mode) =
+            match (parse_mode_string(&info.permissions), local_mode(&meta)) {
+                (Some(remote), Some(local)) => (Some(remote), Some(local)),
+                _ => (None, None),
+            };
+        let remote_entry = mirror_entry(info.size, Some(info.modified_unix), remote_mode);
+        let local_entry = mirror_entry(meta.len(), unix_mtime(&meta), local_mode);
+        let changes = remote_entry.changes_from(&local_entry);
+        // 大小不同时内容一定不同，不必让服务器算哈希
+        if let Some(change @ EntryChange::Size { .. }) = changes.first() {
+            return Ok((true, mirror_reason(change)));
+        }
+        if !options.verify_content {
+            return Ok(match changes.first() {
+                Some(change) => (true, mirror_reason(change)),
+                None => (false, "size, mtime and permissions match"),
+            });
+        }
+
+        let remote_hash = rpc
+            .checksum(ChecksumRequest {
+                path: remote.to_string(),
+            })
+            .await?
+            .into_inner()
+            .sha256;
+        Ok(if self.local_hash(local, &meta).await? == remote_hash {
+            (false, "content matches")
+        } else {
+            (true, "content differs")
+        })
+    }
+
+    async fn local_hash(&self, local: &Path, meta: &fs::Metadata) -> Result<String, ClientError> {
+        let modified = meta.modified()?;
+        if let Some((at, hash)) = self.local_hashes.lock().unwrap().get(local) {
+            if *at == modified {
+                return Ok(hash.clone());
+            }
+        }
+
+        let path = local.to_path_buf();
+        let hash = tokio::task::spawn_blocking(move || sha256_file(&path))
+            .await
+            .map_err(std::io::Error::other)??;
+        self.local_hashes
+            .lock()
+            .unwrap()
+            .insert(local.to_path_buf(), (modified, hash.clone()));
+        Ok(hash)
+    }
+
+    // 下载整个文件，mtime 设为远程的值，供下次按 mtime 比较；边写边算哈希存入本地哈希缓存
+    // 先写到同目录下的 .<文件名>.tmp.<pid>，完成后再 rename 覆盖，中途失败时本地原文件保持不变
+    async fn download(
+        &self,
+        rpc: &mut LazySyncClient<Channel>,
+        remote: &str,
+        local: &Path,
+        info: &lazysync::FileInfo,
+    ) -> Result<u64, ClientError> {
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(local.file_name().unwrap_or_default());
+        tmp_name.push(format!(".tmp.{}", std::process::id()));
+        let tmp = local.with_file_name(tmp_name);
+        let result = async {
+            let done = self.download_to(rpc, remote, &tmp, info).await?;
+            tokio::fs::rename(&tmp, local).await?;
+            Ok::<_, ClientError>(done)
+        }
+        .await;
+        let (written, hash) = match result {
+            Ok(done) => done,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&tmp).await;
+                return Err(e);
+            }
+        };
+        let modified = tokio::fs::metadata(local).await?.modified()?;
+        self.local_hashes
+            .lock()
+            .unwrap()
+            .insert(local.to_path_buf(), (modified, hash));
+        Ok(written)
+    }
+
+    // 下载到 path 并设置 mtime 和权限，返回 (字节数, SHA-256)
+    async fn download_to(
+        &self,
+        rpc: &mut LazySyncClient<Channel>,
+        remote: &str,
+        path: &Path,
+        info: &lazysync::FileInfo,
+    ) -> Result<(u64, String), ClientError> {
+        let mut stream = rpc
+            .read_file(ReadFileRequest {
+                path: remote.to_string(),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut hasher = Sha256::new();
+        let mut written = 0u64;
+        while let Some(chunk) = stream.message().await? {
+            file.write_all(&chunk.data).await?;
+            hasher.update(&chunk.data);
+            written += chunk.data.len() as u64;
+        }
+        file.flush().await?;
+
+        let file = file.into_std().await;
+        if let Ok(secs) = u64::try_from(info.modified_unix) {
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(secs))?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = parse_mode_string(&info.permissions) {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        Ok((written, format!("{:x}", hasher.finalize())))
+    }
+
+    // cache 中有（未过期的）父目录列表时用列表回答：找到条目为 Some(Some(..))，列表里没有为 Some(None)
+    fn cached_stat(&self, path: &NormalizedPath) -> Option<Option<FileEntry>> {
+        let (parent, name) = parent_and_name(path.as_str())?;
+        if let Some(ttl) = self.cache_ttl {
+            if !self.cache.is_fresh(parent, ttl) {
+                return None;
+            }
+        }
+        let siblings = self.cached_listings(&[parent]).pop().flatten()?;
+        Some(
+            siblings
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .map(normalize_entry),
+        )
+    }
+
+    // 一次解析多个路径的信息，结果顺序与 paths 相同，不存在时为 Ok(None)；每个路径单独成功或失败
+    // 父目录列表在 cache 中的路径直接在本地回答，其余的合在一次 StatBatch 请求里，
+    // 适合增量同步前比较大量路径
+    pub async fn stat_many(&self, paths: &[&str]) -> Vec<Result<Option<FileEntry>, ClientError>> {
+        let paths: Vec<NormalizedPath> = paths
+            .iter()
+            .map(|path| NormalizedPath::from(*path))
+            .collect();
+        let mut results: Vec<Option<Result<Option<FileEntry>, ClientError>>> = paths
+            .iter()
+            .map(|path| self.cached_stat(path).map(Ok))
+            .collect();
+        let remote: Vec<usize> = (0..paths.len()).filter(|&i| results[i].is_none()).collect();
+        if remote.is_empty() {
+            return results.into_iter().flatten().collect();
+        }
+
+        let request = StatBatchRequest {
+            paths: remote.iter().map(|&i| paths[i].to_string()).collect(),
+        };
+        let resp = async {
+            let mut rpc = self.rpc().await?;
+            Ok::<_, ClientError>(rpc.stat_batch(request).await?.into_inner())
+        }
+        .await;
+        match resp {
+            Ok(resp) => {
+                let mut returned = resp.results.into_iter();
+                for &i in &remote {
+                    results[i] = Some(match returned.next() {
+                        Some(result) if !result.error.is_empty() => {
+                            Err(ClientError::Request(result.error))
+                        }
+                        Some(lazysync::StatBatchResult {
+                            stat: Some(stat), ..
+                        }) => Ok(stat.info.filter(|_| stat.exists).map(FileEntry::from)),
+                        _ => Err(ClientError::BadResponse(format!(
+                            "stat_batch returned no result for {}",
+                            paths[i]
+                        ))),
+                    });
+                }
+            }
+            // 整个请求失败时每个待查询的路径都得到这个错误
+            Err(e) => {
+                if let Some((&first, rest)) = remote.split_first() {
+                    for &i in rest {
+                        results[i] = Some(Err(e.duplicate()));
                     }
+                    results[first] = Some(Err(e));
                 }
-                
-                Ok(found_entries)
             }
+        }
+        results.into_iter().flatten().collect()
+    }
+
+    // 判断路径是否为目录（含指向目录的符号链接），用于决定条目能否进入
+    // 优先用 cache 中父目录列表里的条目信息，没有或是符号链接时再 stat
+    pub async fn is_dir(&self, path: &str) -> Result<bool, ClientError> {
+        let path = NormalizedPath::from(path);
+        let parent = parent_and_name(path.as_str());
+        let mut keys = vec![path.as_str()];
+        keys.extend(parent.map(|(parent, _)| parent));
+        let mut listings = self.cached_listings(&keys).into_iter();
+        if listings.next().flatten().is_some() {
+            return Ok(true);
+        }
+        let cached = listings
+            .next()
+            .flatten()
+            .zip(parent)
+            .and_then(|(siblings, (_, name))| {
+                siblings.into_iter().find(|entry| entry.name == name)
+            });
+        if let Some(entry) = cached.filter(|entry| entry.file_type != "symlink") {
+            return Ok(entry.is_dir);
+        }
+
+        let mut rpc = self.rpc().await?;
+        let resp = rpc
+            .stat(StatRequest {
+                path: path.to_string(),
+            })
+            .await?
+            .into_inner();
+        let Some(info) = resp.info.filter(|_| resp.exists) else {
+            return Err(ClientError::NotFound(path.into_string()));
+        };
+        if info.file_type != "symlink" {
+            return Ok(info.file_type == "dir");
+        }
+        // 符号链接：看它指向的目标；断链视为不是目录
+        if resp.resolution_broken || resp.resolved_path.is_empty() {
+            return Ok(false);
+        }
+        Ok(self
+            .stat(&resp.resolved_path)
+            .await?
+            .is_some_and(|entry| entry.is_dir))
+    }
+
+    // 打开远程文件做随机读取：stat 一次记下大小，之后每次 read_at 发一个带范围的 read_file
+    pub async fn open(&self, path: &str) -> Result<RemoteFile, ClientError> {
+        let Some(entry) = self.stat(path).await? else {
+            return Err(ClientError::NotFound(path.to_string()));
+        };
+        if entry.is_dir {
+            return Err(ClientError::Request(format!("Is a directory: {}", path)));
+        }
+        Ok(RemoteFile {
+            rpc: self.rpc().await?,
+            path: NormalizedPath::from(path).into_string(),
+            size: entry.size,
+            read_ahead: 0,
+            buffer: Vec::new(),
+            buffer_offset: 0,
+        })
+    }
+
+    pub fn request_path(&self, path: &str) -> std::io::Result<()> {
+        let mut id = self.req_id.lock().unwrap();
+        *id += 1;
+        let req = Request {
+            id: *id,
+            path: NormalizedPath::from(path).into_string(),
+            trace_id: Some(new_trace_id()),
+        };
+
+        self.send_request(&req)
+    }
+
+    pub async fn get_path(&self, path: &str) -> Result<Vec<FileEntry>, ClientError> {
+        self.get_path_traced(path, &new_trace_id()).await
+    }
+
+    // 同 get_path，但使用调用方（例如 HTTP 前端）给出的 trace id，服务器日志里会带上它
+    pub async fn get_path_traced(
+        &self,
+        path: &str,
+        trace_id: &str,
+    ) -> Result<Vec<FileEntry>, ClientError> {
+        self.get_dir_traced(path, trace_id)
+            .await
+            .map(|dir| dir.entries)
+    }
+
+    // 只返回 file_type 在 types 中的条目（例如 ["dir", "symlink"]）；完整列表照常进入 cache
+    pub async fn get_path_by_type(
+        &self,
+        path: &str,
+        types: &[&str],
+    ) -> Result<Vec<FileEntry>, ClientError> {
+        if let Some(unknown) = types.iter().find(|t| !FILE_TYPES.contains(t)) {
+            return Err(ClientError::InvalidArgument(format!(
+                "unknown file type {:?}, expected one of {:?}",
+                unknown, FILE_TYPES
+            )));
+        }
+        let dir = self.get_dir(path).await?;
+        Ok(dir
+            .entries
+            .into_iter()
+            .filter(|entry| types.contains(&entry.file_type.as_str()))
+            .collect())
+    }
+
+    // 目录列表连同目录本身的信息（来自父目录列表）和汇总，供 UI 显示标题、“N 项，X 字节”等
+    pub async fn get_dir(&self, path: &str) -> Result<DirListing, ClientError> {
+        self.get_dir_traced(path, &new_trace_id()).await
+    }
+
+    async fn get_dir_traced(&self, path: &str, trace_id: &str) -> Result<DirListing, ClientError> {
+        // 规范化路径：去掉末尾的 /
+        let path = NormalizedPath::from(path);
+        self.recent.lock().unwrap().push(path.as_str());
+
+        // 1. 先检查cache，2. 没有cache，发送请求并等待响应
+        let started = Instant::now();
+        let (result, source) = match self.cached_lookup(&path) {
+            Some(cached) => (cached, GetPathSource::Cache),
+            None => {
+                let fetched = self.fetch_dir(&path, trace_id).await;
+                (fetched, GetPathSource::Network)
+            }
+        };
+        self.record_get_path(&path, started, source, &result);
+        result
+    }
+
+    // 一次取多个目录：cache 命中（包括已知不存在）的直接返回，其余的请求先全部发出再一起等待，
+    // 总耗时接近最慢的一个而不是所有请求之和；每个路径的结果互不影响，顺序与 paths 相同
+    pub async fn get_paths(&self, paths: &[&str]) -> Vec<Result<Vec<FileEntry>, ClientError>> {
+        let trace_id = new_trace_id();
+        let started = Instant::now();
+        let paths: Vec<NormalizedPath> = paths.iter().map(|&p| NormalizedPath::from(p)).collect();
+        let mut results: Vec<Option<Result<DirListing, ClientError>>> = Vec::new();
+        // 需要走网络的路径在 paths 中的下标
+        let mut misses = Vec::new();
+        for (i, path) in paths.iter().enumerate() {
+            self.recent.lock().unwrap().push(path.as_str());
+            let cached = self.cached_lookup(path);
+            if let Some(cached) = &cached {
+                self.record_get_path(path, started, GetPathSource::Cache, cached);
+            } else {
+                misses.push(i);
+            }
+            results.push(cached);
+        }
+
+        // 有并发上限时，拿不到许可就先等最早发出的请求，避免自己持有的许可把自己卡住
+        let mut fetched = Vec::new();
+        let mut pending = VecDeque::new();
+        for i in misses {
+            let permit = match &self.in_flight {
+                Some(in_flight) => loop {
+                    match in_flight.try_acquire() {
+                        Ok(permit) => break Some(permit),
+                        Err(_) => match pending.pop_front() {
+                            Some((i, request, _permit)) => {
+                                let result = self.await_dir_response(&paths[i], request).await;
+                                fetched.push((i, result));
+                            }
+                            None => break in_flight.acquire().await.ok(),
+                        },
+                    }
+                },
+                None => None,
+            };
+            match self.send_dir_request(&paths[i], &trace_id) {
+                Ok(request) => pending.push_back((i, request, permit)),
+                Err(e) => fetched.push((i, Err(ClientError::Io(e)))),
+            }
+        }
+        // 所有请求都已发出，依次等待即可，等待期间其余响应照常到达
+        for (i, request, _permit) in pending {
+            let result = self.await_dir_response(&paths[i], request).await;
+            fetched.push((i, result));
+        }
+        for (i, result) in fetched {
+            self.record_get_path(&paths[i], started, GetPathSource::Network, &result);
+            results[i] = Some(result);
+        }
+
+        let mut listings = Vec::with_capacity(results.len());
+        for result in results.into_iter().flatten() {
+            listings.push(result.map(|dir| dir.entries));
+        }
+        listings
+    }
+
+    // cache 中的结果：已知不存在时是 NotFound，没有（或已过期）时返回 None
+    fn cached_lookup(&self, path: &NormalizedPath) -> Option<Result<DirListing, ClientError>> {
+        let known_missing = self
+            .negative_cache_ttl
+            .is_some_and(|ttl| self.cache.is_missing(path.as_str(), ttl));
+        if known_missing {
+            return Some(Err(ClientError::NotFound(path.to_string())));
+        }
+        self.cached_dir(path).map(Ok)
+    }
+
+    // 记录一次 get_path：负缓存、命中计数和 get_path_hook
+    fn record_get_path(
+        &self,
+        path: &NormalizedPath,
+        started: Instant,
+        source: GetPathSource,
+        result: &Result<DirListing, ClientError>,
+    ) {
+        if let (Err(ClientError::NotFound(_)), GetPathSource::Network, Some(_)) =
+            (result, source, self.negative_cache_ttl)
+        {
+            self.cache.mark_missing(path.as_str());
+        }
+        let counter = match source {
+            GetPathSource::Cache => &self.cache_hits,
+            GetPathSource::Network => &self.cache_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(hook) = &self.get_path_hook {
+            hook(&GetPathEvent {
+                path: path.to_string(),
+                duration: started.elapsed(),
+                source,
+                entry_count: result.as_ref().map_or(0, |dir| dir.entry_count),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+        }
+    }
+
+    fn cached_dir(&self, path: &NormalizedPath) -> Option<DirListing> {
+        let parent = parent_and_name(path.as_str());
+        let mut keys = vec![path.as_str()];
+        keys.extend(parent.map(|(parent, _)| parent));
+        let mut listings = self.cached_listings(&keys).into_iter();
+        let fresh = match self.cache_ttl {
+            Some(ttl) => self.cache.is_fresh(path.as_str(), ttl),
+            None => true,
+        };
+        let entries = listings.next().flatten().filter(|_| fresh)?;
+        let self_info = listings
+            .next()
+            .flatten()
+            .zip(parent)
+            .and_then(|(siblings, (_, name))| {
+                siblings.into_iter().find(|entry| entry.name == name)
+            });
+        let normalized_entries: Vec<FileEntry> = entries.into_iter().map(normalize_entry).collect();
+        Some(DirListing::new(
+            path.to_string(),
+            self_info.map(normalize_entry),
+            normalized_entries,
+        ))
+    }
+
+    async fn fetch_dir(
+        &self,
+        path: &NormalizedPath,
+        trace_id: &str,
+    ) -> Result<DirListing, ClientError> {
+        let _permit = match &self.in_flight {
+            Some(in_flight) => in_flight.acquire().await.ok(),
+            None => None,
+        };
+        let pending = self
+            .send_dir_request(path, trace_id)
+            .map_err(ClientError::Io)?;
+        self.await_dir_response(path, pending).await
+    }
+
+    // 注册 oneshot channel 并发出请求，不等待响应；和 await_dir_response 分开，
+    // 这样 get_paths 可以先把所有请求发出去再一起等
+    fn send_dir_request(
+        &self,
+        path: &NormalizedPath,
+        trace_id: &str,
+    ) -> std::io::Result<PendingDir> {
+        let (tx, rx) = oneshot::channel();
+        let mut id = self.req_id.lock().unwrap();
+        *id += 1;
+        let req_id = *id;
+
+        // 注册channel
+        {
+            let mut channels = self.response_channels.lock().unwrap();
+            channels.insert(req_id, tx);
+        }
+
+        // 发送请求（使用规范化后的路径）
+        let req = Request {
+            id: req_id,
+            path: path.to_string(),
+            trace_id: Some(trace_id.to_string()),
+        };
+
+        if let Err(e) = self.send_request(&req) {
+            self.response_channels.lock().unwrap().remove(&req_id);
+            return Err(e);
+        }
+
+        Ok(PendingDir {
+            request_id: req_id,
+            rx,
+            started: Instant::now(),
+        })
+    }
+
+    async fn await_dir_response(
+        &self,
+        path: &NormalizedPath,
+        pending: PendingDir,
+    ) -> Result<DirListing, ClientError> {
+        let PendingDir {
+            request_id,
+            rx,
+            started,
+        } = pending;
+        let parent = parent_and_name(path.as_str());
+
+        // 等待响应（最多等待 timeout，默认 5 秒；未设置时一直等待），从发出请求时算起
+        let received = match self.timeout {
+            Some(timeout) => tokio::time::timeout_at((started + timeout).into(), rx).await,
+            None => Ok(rx.await),
+        };
+        match received {
+            Ok(Ok(Ok(resp))) => {
+                // 从响应数据中查找请求的路径（空目录也算找到）；响应里没有这个路径说明它不存在
+                let Some(infos) = find_response_entries(&resp, path) else {
+                    return Err(ClientError::NotFound(path.to_string()));
+                };
+                let entries = infos.iter().map(entry_from_info).collect();
+                let self_info = parent.and_then(|(parent, name)| {
+                    find_response_entries(&resp, &NormalizedPath::from(parent))?
+                        .iter()
+                        .find(|info| info.name == name)
+                        .map(entry_from_info)
+                });
+                Ok(DirListing::new(path.to_string(), self_info, entries))
+            }
+            Ok(Ok(Err(e))) => Err(e),
             Ok(Err(_)) => {
                 let mut channels = self.response_channels.lock().unwrap();
                 channels.remove(&request_id);
-                Err("Channel error".to_string())
+                Err(ClientError::Request("Channel error".to_string()))
             }
             Err(_) => {
                 let mut channels = self.response_channels.lock().unwrap();
                 channels.remove(&request_id);
-                Err("Timeout waiting for response".to_string())
+                Err(ClientError::Timeout {
+                    path: path.to_string(),
+                    elapsed: started.elapsed(),
+                })
+            }
+        }
+    }
+
+    // 关闭 TCP 连接并等待接收线程退出，等待中的请求立即以 ClientError::Closed 失败；
+    // 之后的请求不会再重新连接。可以重复调用，Drop 时也会自动调用
+    pub fn close(&self) {
+        let mut conn = self.conn.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        let pending: Vec<_> = self.response_channels.lock().unwrap().drain().collect();
+        for (_, sender) in pending {
+            let _ = sender.send(Err(ClientError::Closed));
+        }
+        if let Some(open) = conn.take() {
+            open.close();
+        }
+        drop(conn);
+
+        // 后台上传线程不等：做完当前这次尝试就退出，没完成的任务留在队列（文件）里
+        self.uploads.close();
+
+        // 等后台线程把最后的改动写进 cache 文件
+        self.cache.close();
+        if let Some(flusher) = self.cache_flusher.lock().unwrap().take() {
+            let _ = flusher.join();
+        }
+    }
+
+    // 当前是否有可用的连接。服务器断开或空闲超时关闭后为 false，下次请求时会重新连接
+    pub fn is_connected(&self) -> bool {
+        !self.closed.load(Ordering::SeqCst)
+            && self
+                .conn
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(Connection::is_alive)
+    }
+
+    // 最近的响应解析错误（最多 32 条），最旧的在前，每条包含解析错误和截断后的原始行
+    pub fn last_errors(&self) -> Vec<String> {
+        self.parse_errors.lock().unwrap().iter().cloned().collect()
+    }
+
+    // cache 中当前的目录数
+    pub fn cache_len(&self) -> usize {
+        self.cache.len()
+    }
+
+    // 用于调整 cache_ttl / max_cache_entries：命中次数、未命中（向服务器请求）次数和当前目录数
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.cache_hits.load(Ordering::Relaxed),
+            misses: self.cache_misses.load(Ordering::Relaxed),
+            entries: self.cache.len(),
+        }
+    }
+
+    // 命中和未命中次数清零，不影响 cache 内容
+    pub fn reset_cache_stats(&self) {
+        self.cache_hits.store(0, Ordering::Relaxed);
+        self.cache_misses.store(0, Ordering::Relaxed);
+    }
+
+    // 保存内存中 cache 的运行状态：目录列表、访问顺序、命中统计和最近路径
+    pub fn snapshot_cache(&self) -> CacheSnapshot {
+        let (listings, order) = self.cache.snapshot();
+        CacheSnapshot {
+            listings,
+            order,
+            hits: self.cache_hits.load(Ordering::Relaxed),
+            misses: self.cache_misses.load(Ordering::Relaxed),
+            recent_paths: self.recent_paths(usize::MAX),
+        }
+    }
+
+    // 用 snapshot_cache 的结果替换当前状态；目录数超过 max_cache_entries 时淘汰最久没访问的，
+    // 之后照常写回 cache 文件。no_cache 时只恢复统计和最近路径
+    pub fn restore_cache(&self, snapshot: CacheSnapshot) {
+        self.cache.restore(snapshot.listings, snapshot.order);
+        self.cache_hits.store(snapshot.hits, Ordering::Relaxed);
+        self.cache_misses.store(snapshot.misses, Ordering::Relaxed);
+        let mut recent = self.recent.lock().unwrap();
+        *recent = RecentPaths::new(recent.capacity);
+        for path in snapshot.recent_paths.iter().rev() {
+            recent.push(path);
+        }
+    }
+
+    // 最近通过 get_path 访问的不同路径，最新的在前，最多 limit 个
+    pub fn recent_paths(&self, limit: usize) -> Vec<String> {
+        self.recent.lock().unwrap().list(limit)
+    }
+
+    // 路径补全：列出前缀所在目录，返回匹配最后一段的子项名，目录追加 /
+    pub async fn complete(&self, prefix: &str) -> Result<Vec<String>, ClientError> {
+        let (dir, partial) = split_completion_prefix(prefix.trim_start());
+        let entries = match self.get_dir(&dir).await {
+            Ok(listing) => listing.entries,
+            // 前缀所在目录不存在：没有可补全的项
+            Err(ClientError::NotFound(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        // 与 shell 一致：只有前缀以 . 开头时才补全隐藏文件
+        let show_hidden = partial.starts_with('.');
+        let mut matches: Vec<String> = entries
+            .iter()
+            .filter(|entry| entry.name.starts_with(partial))
+            .filter(|entry| show_hidden || !entry.name.starts_with('.'))
+            .map(|entry| {
+                if entry.is_dir {
+                    format!("{}/", entry.name)
+                } else {
+                    entry.name.clone()
+                }
+            })
+            .collect();
+        matches.sort();
+        Ok(matches)
+    }
+}
+
+// Client::open 返回的远程文件句柄，大小在打开时确定
+pub struct RemoteFile {
+    rpc: LazySyncClient<Channel>,
+    path: String,
+    size: u64,
+    read_ahead: u64,
+    // 上一次读取（含预读部分）的数据，命中时不再请求服务器
+    buffer: Vec<u8>,
+    buffer_offset: u64,
+}
+
+impl RemoteFile {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // 每次向服务器请求时多读 bytes 字节，适合顺序小块读取；默认为 0
+    pub fn with_read_ahead(mut self, bytes: u64) -> Self {
+        self.read_ahead = bytes;
+        self
+    }
+
+    // 读取 [offset, offset + len)，超出文件大小的部分被截掉，offset 在文件末尾之后时返回空
+    pub async fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, ClientError> {
+        let end = offset.saturating_add(len).min(self.size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+
+        let buffered_end = self.buffer_offset + self.buffer.len() as u64;
+        if offset < self.buffer_offset || end > buffered_end {
+            let length = (end - offset)
+                .saturating_add(self.read_ahead)
+                .min(self.size - offset);
+            let mut stream = self
+                .rpc
+                .read_file(ReadFileRequest {
+                    path: self.path.clone(),
+                    offset,
+                    length,
+                    ..Default::default()
+                })
+                .await?
+                .into_inner();
+            let mut bytes = Vec::with_capacity(length as usize);
+            while let Some(chunk) = stream.message().await? {
+                bytes.extend_from_slice(&chunk.data);
             }
+            self.buffer = bytes;
+            self.buffer_offset = offset;
         }
+
+        // 文件在打开后变短时，服务器返回的数据可能少于请求
+        let start = (offset - self.buffer_offset) as usize;
+        let stop = ((end - self.buffer_offset) as usize).min(self.buffer.len());
+        Ok(self.buffer.get(start..stop).unwrap_or_default().to_vec())
     }
+
+    // 服务器端不保存打开的句柄，关闭只释放本地缓冲
+    pub fn close(self) {}
 }
 
 impl Drop for Client {
     fn drop(&mut self) {
-        // 清理资源
-        if let Some(handle) = self.receiver_handle.take() {
-            // 注意：这里无法优雅地停止接收线程，因为它在等待读取
-            // 在实际应用中，可能需要添加关闭标志
-            drop(handle);
+        // 空闲检查线程发现 Client 已释放后也会退出
+        self.close();
+    }
+}
+
+// ===== gRPC 客户端 =====
+// 通过服务器的 gRPC 服务（GetPath RPC）列目录，不使用 JSON-over-TCP 连接；
+// cache 与 Client 相同：先查 cache，未命中时请求，完整的结果写入 cache
+pub struct GrpcClient {
+    server_addr: String,
+    // gRPC 连接在第一次调用时建立，之后复用
+    rpc: tokio::sync::Mutex<Option<LazySyncClient<Channel>>>,
+    cache: Arc<CacheStore>,
+    cache_flusher: Mutex<Option<thread::JoinHandle<()>>>,
+    // None 表示一直等待响应
+    timeout: Option<Duration>,
+    cache_ttl: Option<Duration>,
+}
+
+impl GrpcClient {
+    pub fn new(server_addr: &str) -> std::io::Result<Self> {
+        ClientBuilder::new().connect_grpc(server_addr)
+    }
+
+    pub fn new_with_cache(
+        server_addr: &str,
+        is_hash: bool,
+        config: ClientConfig,
+    ) -> std::io::Result<Self> {
+        ClientBuilder::new()
+            .hashed_cache(is_hash)
+            .config(config)
+            .connect_grpc(server_addr)
+    }
+
+    async fn rpc(&self) -> Result<LazySyncClient<Channel>, ClientError> {
+        let mut rpc = self.rpc.lock().await;
+        if let Some(client) = rpc.as_ref() {
+            return Ok(client.clone());
+        }
+        let client = LazySyncClient::connect(format!("http://{}", self.server_addr)).await?;
+        *rpc = Some(client.clone());
+        Ok(client)
+    }
+
+    pub async fn get_path(&self, path: &str) -> Result<Vec<FileEntry>, ClientError> {
+        self.get_path_traced(path, &new_trace_id()).await
+    }
+
+    // 同 get_path，但使用调用方给出的 trace id，服务器日志里会带上它
+    pub async fn get_path_traced(
+        &self,
+        path: &str,
+        trace_id: &str,
+    ) -> Result<Vec<FileEntry>, ClientError> {
+        let path = NormalizedPath::from(path);
+        let fresh = match self.cache_ttl {
+            Some(ttl) => self.cache.is_fresh(path.as_str(), ttl),
+            None => true,
+        };
+        if let Some(entries) = self.cache.get_many(&[path.as_str()]).pop().flatten() {
+            if fresh {
+                return Ok(entries.into_iter().map(normalize_entry).collect());
+            }
+        }
+
+        let started = Instant::now();
+        let fetched = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.fetch(&path, trace_id)).await,
+            None => Ok(self.fetch(&path, trace_id).await),
+        };
+        let Ok(fetched) = fetched else {
+            return Err(ClientError::Timeout {
+                path: path.to_string(),
+                elapsed: started.elapsed(),
+            });
+        };
+        fetched
+    }
+
+    async fn fetch(
+        &self,
+        path: &NormalizedPath,
+        trace_id: &str,
+    ) -> Result<Vec<FileEntry>, ClientError> {
+        let mut rpc = self.rpc().await?;
+        let resp = rpc
+            .get_path(GetPathRequest {
+                path: path.to_string(),
+                trace_id: trace_id.to_string(),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+
+        let listings: Vec<(String, Vec<FileEntry>)> = resp
+            .entries
+            .into_iter()
+            .map(|listing| {
+                let key = NormalizedPath::from(listing.absolute_path.as_str()).into_string();
+                let entries = listing.entries.into_iter().map(FileEntry::from).collect();
+                (key, entries)
+            })
+            .collect();
+        // 响应里没有这个路径说明它不存在
+        let Some(found) = find_listing(listings.iter().map(|(k, v)| (k, v)), path)
+            .map(|entries| entries.to_vec())
+        else {
+            return Err(ClientError::NotFound(path.to_string()));
+        };
+
+        // 被截断的结果不完整，不写入 cache
+        if !resp.truncated && !resp.timed_out {
+            let mut stored = listings;
+            stored.push((path.to_string(), found.clone()));
+            self.cache.insert_all(stored);
+        }
+        Ok(found)
+    }
+
+    // cache 中的目录数
+    pub fn cache_len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn invalidate(&self, path: &str) -> bool {
+        self.cache.remove(NormalizedPath::from(path).as_str())
+    }
+
+    // 同 Client::subscribe_invalidations
+    pub async fn subscribe_invalidations(
+        &self,
+        root: &str,
+    ) -> Result<tokio::task::JoinHandle<()>, ClientError> {
+        let rpc = self.rpc().await?;
+        subscribe_invalidations(rpc, Arc::clone(&self.cache), root).await
+    }
+
+    // 等后台线程把最后的改动写进 cache 文件
+    pub fn close(&self) {
+        self.cache.close();
+        if let Some(flusher) = self.cache_flusher.lock().unwrap().take() {
+            let _ = flusher.join();
         }
     }
 }
 
+impl Drop for GrpcClient {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
 // ===== Python 绑定 =====
 #[cfg(feature = "python")]
 use pyo3::prelude::*;
 #[cfg(feature = "python")]
-use pyo3::types::PyDict;
+use pyo3::types::{PyDict, PyType};
+
+// 路径不存在时抛出，是 FileNotFoundError 的子类
+#[cfg(feature = "python")]
+pyo3::create_exception!(
+    rfb_client,
+    NotFoundError,
+    pyo3::exceptions::PyFileNotFoundError
+);
+
+// NotFound 抛出 NotFoundError，InvalidArgument 抛出 ValueError，其余抛出 RuntimeError
+#[cfg(feature = "python")]
+impl From<ClientError> for PyErr {
+    fn from(e: ClientError) -> Self {
+        match e {
+            ClientError::NotFound(_) => NotFoundError::new_err(e.to_string()),
+            ClientError::InvalidArgument(_) => {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(e.to_string())
+            }
+            _ => PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()),
+        }
+    }
+}
 
 #[cfg(feature = "python")]
 #[pyclass]
@@ -411,17 +4212,68 @@ pub struct PyClient {
 #[pymethods]
 impl PyClient {
     #[new]
-    fn new(server_addr: &str, is_hash: Option<bool>) -> PyResult<Self> {
+    #[pyo3(signature = (
+        server_addr, is_hash=None, idle_timeout=None, timeout=None, cache_ttl=None, cache_dir=None
+    ))]
+    fn new(
+        server_addr: &str,
+        is_hash: Option<bool>,
+        idle_timeout: Option<f64>,
+        timeout: Option<f64>,
+        cache_ttl: Option<f64>,
+        cache_dir: Option<PathBuf>,
+    ) -> PyResult<Self> {
+        // timeout 省略时为 5 秒，0 表示一直等待
+        let config = ClientConfig {
+            timeout: timeout.map_or(Some(DEFAULT_RESPONSE_TIMEOUT), |secs| {
+                Some(Duration::from_secs_f64(secs.max(0.0)))
+            }),
+            cache_ttl: cache_ttl.map(|secs| Duration::from_secs_f64(secs.max(0.0))),
+            cache_dir,
+            ..Default::default()
+        };
         let rt = tokio::runtime::Runtime::new()
             .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(
                 format!("Failed to create runtime: {}", e)
             ))?;
         
-        let client = Client::new_with_cache(server_addr, is_hash.unwrap_or(false))
+        let client = Client::new_with_cache(server_addr, is_hash.unwrap_or(false), config)
             .map_err(|e| PyErr::new::<pyo3::exceptions::PyIOError, _>(
                 format!("Failed to connect to server: {}", e)
             ))?;
+        let client = match idle_timeout {
+            Some(secs) if secs > 0.0 => client.with_idle_timeout(Duration::from_secs_f64(secs)),
+            _ => client,
+        };
+
+        Ok(Self { client, rt })
+    }
 
+    // 等服务器的 health 有响应后再连接，超过 timeout 秒抛出 TimeoutError
+    #[classmethod]
+    #[pyo3(signature = (server_addr, timeout=30.0, is_hash=None))]
+    fn connect_when_ready(
+        _cls: &Bound<'_, PyType>,
+        server_addr: &str,
+        timeout: f64,
+        is_hash: Option<bool>,
+    ) -> PyResult<Self> {
+        let rt = tokio::runtime::Runtime::new().map_err(|e| {
+            PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(format!(
+                "Failed to create runtime: {}",
+                e
+            ))
+        })?;
+        let builder = Client::builder().hashed_cache(is_hash.unwrap_or(false));
+        let timeout = Duration::from_secs_f64(timeout.max(0.0));
+        let client = rt
+            .block_on(builder.connect_when_ready(server_addr, timeout))
+            .map_err(|e| match e {
+                ClientError::Timeout { .. } => {
+                    PyErr::new::<pyo3::exceptions::PyTimeoutError, _>(e.to_string())
+                }
+                _ => e.into(),
+            })?;
         Ok(Self { client, rt })
     }
 
@@ -433,27 +4285,943 @@ impl PyClient {
     }
 
     fn get_path(&self, path: &str) -> PyResult<Vec<PyObject>> {
-        let entries = self.rt.block_on(self.client.get_path(path))
-            .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e))?;
-        
+        let dir = self.rt.block_on(self.client.get_dir(path))?;
+
+        Python::with_gil(|py| {
+            dir.entries
+                .iter()
+                .map(|entry| entry_to_dict(py, entry))
+                .collect()
+        })
+    }
+
+    fn get_path_optional(&self, path: &str) -> PyResult<Option<Vec<PyObject>>> {
+        match self.get_path(path) {
+            Ok(entries) => Ok(Some(entries)),
+            Err(e) if Python::with_gil(|py| e.is_instance_of::<NotFoundError>(py)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_path_by_type(&self, path: &str, types: Vec<String>) -> PyResult<Vec<PyObject>> {
+        let types: Vec<&str> = types.iter().map(String::as_str).collect();
+        let entries = self
+            .rt
+            .block_on(self.client.get_path_by_type(path, &types))
+            .map_err(PyErr::from)?;
+
+        Python::with_gil(|py| {
+            entries
+                .iter()
+                .map(|entry| entry_to_dict(py, entry))
+                .collect()
+        })
+    }
+
+    fn get_dir(&self, path: &str) -> PyResult<PyObject> {
+        let dir = self
+            .rt
+            .block_on(self.client.get_dir(path))
+            .map_err(PyErr::from)?;
+
+        Python::with_gil(|py| {
+            let entries = dir
+                .entries
+                .iter()
+                .map(|entry| entry_to_dict(py, entry))
+                .collect::<PyResult<Vec<_>>>()?;
+            let self_info = dir
+                .self_info
+                .as_ref()
+                .map(|entry| entry_to_dict(py, entry))
+                .transpose()?;
+            let dict = PyDict::new(py);
+            dict.set_item("path", dir.path)?;
+            dict.set_item("self_info", self_info)?;
+            dict.set_item("entries", entries)?;
+            dict.set_item("total_size", dir.total_size)?;
+            dict.set_item("entry_count", dir.entry_count)?;
+            Ok(dict.to_object(py))
+        })
+    }
+
+    fn get_path_within(&self, path: &str, deadline_ms: u64) -> PyResult<PyObject> {
+        let listing = self
+            .rt
+            .block_on(
+                self.client
+                    .get_path_within(path, Duration::from_millis(deadline_ms)),
+            )
+            .map_err(PyErr::from)?;
+
+        Python::with_gil(|py| {
+            let entries = listing
+                .entries
+                .iter()
+                .map(|entry| entry_to_dict(py, entry))
+                .collect::<PyResult<Vec<_>>>()?;
+            let dict = PyDict::new(py);
+            dict.set_item("entries", entries)?;
+            dict.set_item("timed_out", listing.timed_out)?;
+            dict.set_item("truncated", listing.truncated)?;
+            dict.set_item("entries_scanned", listing.entries_scanned)?;
+            Ok(dict.to_object(py))
+        })
+    }
+
+    #[pyo3(signature = (include_pseudo=false))]
+    fn list_mounts(&self, include_pseudo: bool) -> PyResult<Vec<PyObject>> {
+        let mounts = self.rt.block_on(self.client.list_mounts(include_pseudo))?;
+        Python::with_gil(|py| {
+            mounts
+                .into_iter()
+                .map(|mount| {
+                    let dict = PyDict::new(py);
+                    dict.set_item("device", mount.device)?;
+                    dict.set_item("mount_point", mount.mount_point)?;
+                    dict.set_item("fs_type", mount.fs_type)?;
+                    dict.set_item("total_bytes", mount.total_bytes)?;
+                    dict.set_item("free_bytes", mount.free_bytes)?;
+                    dict.set_item("read_only", mount.read_only)?;
+                    Ok(dict.to_object(py))
+                })
+                .collect()
+        })
+    }
+
+    fn health_detailed(&self) -> PyResult<PyObject> {
+        let health = self
+            .rt
+            .block_on(self.client.health_detailed())
+            .map_err(PyErr::from)?;
+
+        Python::with_gil(|py| {
+            let dict = PyDict::new(py);
+            dict.set_item("status", health.status)?;
+            dict.set_item("uptime_secs", health.uptime_secs)?;
+            dict.set_item("active_streams", health.active_streams)?;
+            dict.set_item("version", health.version)?;
+            dict.set_item("read_only", health.read_only)?;
+            dict.set_item("no_follow_symlinks", health.no_follow_symlinks)?;
+            let identity = health.identity.as_ref();
+            dict.set_item("uid", identity.map(|identity| identity.uid))?;
+            dict.set_item("gid", identity.map(|identity| identity.gid))?;
+            dict.set_item("groups", identity.map(|identity| identity.groups.clone()))?;
+            Ok(dict.to_object(py))
+        })
+    }
+
+    fn complete(&self, prefix: &str) -> PyResult<Vec<String>> {
+        self.rt
+            .block_on(self.client.complete(prefix))
+            .map_err(PyErr::from)
+    }
+
+    #[pyo3(signature = (limit=20))]
+    fn recent_paths(&self, limit: usize) -> Vec<String> {
+        self.client.recent_paths(limit)
+    }
+
+    fn cache_subtree(&self, root: &str, max_depth: usize) -> PyResult<usize> {
+        self.rt
+            .block_on(self.client.cache_subtree(root, max_depth))
+            .map_err(PyErr::from)
+    }
+
+    fn realpath(&self, path: &str) -> PyResult<(String, bool)> {
+        let resolved = self
+            .rt
+            .block_on(self.client.realpath(path))
+            .map_err(PyErr::from)?;
+        Ok((resolved.canonical, resolved.exists))
+    }
+
+    #[pyo3(signature = (remote_root, local_root, verify_content=false, delete_extra=false, dry_run=false))]
+    fn mirror(
+        &self,
+        remote_root: &str,
+        local_root: &str,
+        verify_content: bool,
+        delete_extra: bool,
+        dry_run: bool,
+    ) -> PyResult<PyObject> {
+        let options = MirrorOptions {
+            verify_content,
+            delete_extra,
+            dry_run,
+        };
+        let report = self
+            .rt
+            .block_on(
+                self.client
+                    .mirror(remote_root, Path::new(local_root), options),
+            )
+            .map_err(PyErr::from)?;
+
+        Python::with_gil(|py| {
+            let actions = |actions: &[MirrorAction]| -> PyResult<Vec<PyObject>> {
+                actions
+                    .iter()
+                    .map(|action| {
+                        let dict = PyDict::new(py);
+                        dict.set_item("path", action.path.clone())?;
+                        dict.set_item("reason", action.reason.clone())?;
+                        Ok(dict.to_object(py))
+                    })
+                    .collect()
+            };
+            let dict = PyDict::new(py);
+            dict.set_item("to_download", actions(&report.to_download)?)?;
+            dict.set_item("to_delete", actions(&report.to_delete)?)?;
+            dict.set_item("to_skip", actions(&report.to_skip)?)?;
+            dict.set_item("bytes", report.bytes)?;
+            dict.set_item("dry_run", report.dry_run)?;
+            Ok(dict.to_object(py))
+        })
+    }
+
+    fn last_errors(&self) -> Vec<String> {
+        self.client.last_errors()
+    }
+
+    fn close(&self) {
+        self.client.close()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
+    fn cache_len(&self) -> usize {
+        self.client.cache_len()
+    }
+
+    fn cache_stats(&self) -> PyResult<PyObject> {
+        let stats = self.client.cache_stats();
         Python::with_gil(|py| {
-            entries.iter().map(|entry| {
-                let dict = PyDict::new(py);
-                dict.set_item("name", entry.name.clone())?;
-                dict.set_item("is_dir", entry.is_dir)?;
-                dict.set_item("type", entry.file_type.clone())?;
-                dict.set_item("size", entry.size)?;
-                dict.set_item("permissions", entry.permissions.clone())?;
-                dict.set_item("modified", entry.modified.clone())?;
-                Ok(dict.to_object(py))
-            }).collect()
+            let dict = PyDict::new(py);
+            dict.set_item("hits", stats.hits)?;
+            dict.set_item("misses", stats.misses)?;
+            dict.set_item("entries", stats.entries)?;
+            Ok(dict.to_object(py))
         })
     }
+
+    fn reset_cache_stats(&self) {
+        self.client.reset_cache_stats()
+    }
+
+    fn snapshot_cache(&self) -> PyResult<String> {
+        serde_json::to_string(&self.client.snapshot_cache())
+            .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))
+    }
+
+    fn restore_cache(&self, snapshot: &str) -> PyResult<()> {
+        let snapshot = serde_json::from_str(snapshot)
+            .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(e.to_string()))?;
+        self.client.restore_cache(snapshot);
+        Ok(())
+    }
+
+    fn invalidate(&self, path: &str) -> bool {
+        self.client.invalidate(path)
+    }
+
+    fn invalidate_all(&self) {
+        self.client.invalidate_all()
+    }
+
+    fn cached_subtree_size(&self, root: &str) -> u64 {
+        self.client.cached_subtree_size(root)
+    }
+
+    // 结果与 paths 一一对应：条目 dict，不存在为 None，这个路径出错时为异常对象（不抛出）
+    fn stat_many(&self, py: Python, paths: Vec<String>) -> PyResult<Vec<PyObject>> {
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        let results = self.rt.block_on(self.client.stat_many(&paths));
+        results
+            .into_iter()
+            .map(|result| match result {
+                Ok(Some(entry)) => entry_to_dict(py, &entry),
+                Ok(None) => Ok(py.None()),
+                Err(e) => Ok(PyErr::from(e).into_py(py)),
+            })
+            .collect()
+    }
+
+    fn can_write(&self, path: &str) -> PyResult<bool> {
+        Ok(self.rt.block_on(self.client.can_write(path))?)
+    }
+
+    fn is_dir(&self, path: &str) -> PyResult<bool> {
+        Ok(self.rt.block_on(self.client.is_dir(path))?)
+    }
+
+    #[pyo3(signature = (path, offset=0, length=0))]
+    fn read_text(&self, path: &str, offset: u64, length: u64) -> PyResult<(String, String)> {
+        let decoded = self
+            .rt
+            .block_on(self.client.read_text(path, offset, length))
+            .map_err(PyErr::from)?;
+        Ok((decoded.text, decoded.encoding))
+    }
+}
+
+#[cfg(feature = "python")]
+fn entry_to_dict(py: Python, entry: &FileEntry) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("name", entry.name.clone())?;
+    dict.set_item("is_dir", entry.is_dir)?;
+    dict.set_item("type", entry.file_type.clone())?;
+    dict.set_item("size", entry.size)?;
+    dict.set_item("size_known", entry.size_known)?;
+    dict.set_item("permissions", entry.permissions.clone())?;
+    dict.set_item("modified", entry.modified.clone())?;
+    dict.set_item("setuid", entry.setuid)?;
+    dict.set_item("setgid", entry.setgid)?;
+    dict.set_item("sticky", entry.sticky)?;
+    Ok(dict.to_object(py))
 }
 
 #[cfg(feature = "python")]
 #[pymodule]
-fn rfb_client(_py: Python, m: &PyModule) -> PyResult<()> {
+fn rfb_client(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyClient>()?;
+    m.add("NotFoundError", py.get_type_bound::<NotFoundError>())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn entry(size: u64, modified: &str, permissions: &str) -> FileEntry {
+        FileEntry {
+            name: "file".to_string(),
+            is_dir: false,
+            file_type: "file".to_string(),
+            size,
+            size_known: true,
+            permissions: permissions.to_string(),
+            modified: modified.to_string(),
+            setuid: false,
+            setgid: false,
+            sticky: false,
+        }
+    }
+
+    #[test]
+    fn changes_from_reports_only_the_field_that_changed() {
+        let old = entry(10, "2024-01-01 00:00:00", "-rw-r--r--");
+        assert!(!old.changed_from(&old.clone()));
+
+        let resized = entry(20, "2024-01-01 00:00:00", "-rw-r--r--");
+        assert_eq!(
+            resized.changes_from(&old),
+            [EntryChange::Size { old: 10, new: 20 }]
+        );
+
+        let touched = entry(10, "2024-01-02 00:00:00", "-rw-r--r--");
+        assert_eq!(
+            touched.changes_from(&old),
+            [EntryChange::Modified {
+                old: "2024-01-01 00:00:00".to_string(),
+                new: "2024-01-02 00:00:00".to_string(),
+            }]
+        );
+
+        let chmodded = entry(10, "2024-01-01 00:00:00", "-rwxr-xr-x");
+        assert_eq!(
+            chmodded.changes_from(&old),
+            [EntryChange::Permissions {
+                old: "-rw-r--r--".to_string(),
+                new: "-rwxr-xr-x".to_string(),
+            }]
+        );
+        assert!(chmodded.changed_from(&old));
+
+        // The name is not part of the comparison.
+        let mut renamed = old.clone();
+        renamed.name = "other".to_string();
+        assert!(!renamed.changed_from(&old));
+    }
+
+    #[test]
+    fn mirror_compares_unix_times_and_plain_mode_bits() {
+        let base = mirror_entry(10, Some(1_700_000_000), Some(0o644));
+        assert!(!mirror_entry(10, Some(1_700_000_000), Some(0o644)).changed_from(&base));
+        assert_eq!(
+            mirror_reason(
+                &mirror_entry(11, Some(1_700_000_000), Some(0o644)).changes_from(&base)[0]
+            ),
+            "size differs"
+        );
+        assert_eq!(
+            mirror_reason(
+                &mirror_entry(10, Some(1_700_000_001), Some(0o644)).changes_from(&base)[0]
+            ),
+            "mtime differs"
+        );
+        assert_eq!(
+            mirror_reason(
+                &mirror_entry(10, Some(1_700_000_000), Some(0o600)).changes_from(&base)[0]
+            ),
+            "permissions differ"
+        );
+        // setuid and friends are never copied, so they must not count as a change.
+        assert!(!mirror_entry(10, Some(1_700_000_000), Some(0o4644)).changed_from(&base));
+    }
+
+    #[test]
+    fn mode_strings_round_trip() {
+        for mode in [0o000, 0o644, 0o755, 0o4755, 0o2710, 0o1777, 0o4644, 0o7000] {
+            let permissions = format!("-{}", mode_string(mode));
+            assert_eq!(
+                parse_mode_string(&permissions),
+                Some(mode),
+                "{}",
+                permissions
+            );
+        }
+        assert_eq!(mode_string(0o4755), "rwsr-xr-x");
+        assert_eq!(mode_string(0o1644), "rw-r--r-T");
+        assert_eq!(parse_mode_string("drwxr-xr-x"), Some(0o755));
+        assert_eq!(parse_mode_string("-rw-r--r"), None);
+        assert_eq!(parse_mode_string("-rw-r--r-q"), None);
+    }
+
+    fn flushed_store(path: &Path) -> (Arc<CacheStore>, thread::JoinHandle<()>) {
+        let cache = Arc::new(CacheStore::open(
+            true,
+            Some(path.to_path_buf()),
+            CacheFormat::Json,
+            None,
+        ));
+        let flusher = {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || cache.run_flusher())
+        };
+        (cache, flusher)
+    }
+
+    #[test]
+    fn concurrent_flushers_keep_each_others_listings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    // Each store stands in for a separate process sharing the file.
+                    let (cache, flusher) = flushed_store(&path);
+                    for batch in 0..10 {
+                        cache.insert_all(
+                            (0..5)
+                                .map(|i| (format!("/w{}/d{}", writer, batch * 5 + i), Vec::new())),
+                        );
+                        thread::sleep(Duration::from_millis(30));
+                    }
+                    cache.close();
+                    flusher.join().unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let on_disk = load_cache(&path);
+        assert_eq!(on_disk.len(), 8 * 50);
+        for writer in 0..8 {
+            for i in 0..50 {
+                assert!(on_disk.contains_key(&format!("/w{}/d{}", writer, i)));
+            }
+        }
+    }
+
+    #[test]
+    fn flush_merges_without_resurrecting_removed_listings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        let (first, first_flusher) = flushed_store(&path);
+        first.insert_all([
+            ("/a".to_string(), Vec::new()),
+            ("/b".to_string(), Vec::new()),
+        ]);
+        first.close();
+        first_flusher.join().unwrap();
+
+        // Opened before /c exists, so the second store only knows /a and /b.
+        let (second, second_flusher) = flushed_store(&path);
+        let (third, third_flusher) = flushed_store(&path);
+        third.insert_all([("/c".to_string(), Vec::new())]);
+        third.close();
+        third_flusher.join().unwrap();
+        assert!(second.remove("/a"));
+        second.close();
+        second_flusher.join().unwrap();
+
+        let mut keys: Vec<_> = load_cache(&path).into_keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["/b", "/c"]);
+    }
+
+    #[test]
+    fn client_errors_keep_their_variant_when_shared() {
+        let err: ClientError = serde_json::from_str::<Response>("{").unwrap_err().into();
+        assert!(matches!(err.duplicate(), ClientError::Serde(_)));
+        assert!(std::error::Error::source(&err).is_some());
+
+        let status = ClientError::Rpc(tonic::Status::permission_denied("outside root"));
+        match status.duplicate() {
+            ClientError::Rpc(status) => {
+                assert_eq!(status.code(), tonic::Code::PermissionDenied)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let io = ClientError::Io(std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+        match io.duplicate() {
+            ClientError::Io(err) => assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cache_file_updates_keep_the_binary_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CacheFormat::Binary.file_name());
+        save_cache_as(&CacheData::new(), &path, CacheFormat::Binary).unwrap();
+        let resp = Response {
+            id: 1,
+            path: "/srv".to_string(),
+            data: vec![HashMap::from([("/srv".to_string(), Vec::new())])],
+        };
+
+        update_cache_with_response(&resp, &path).unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(CACHE_MAGIC));
+        assert!(load_cache(&path).contains_key("/srv"));
+
+        assert!(invalidate_cached_path(&path, "/srv/").unwrap());
+        assert!(fs::read(&path).unwrap().starts_with(CACHE_MAGIC));
+        assert!(load_cache(&path).is_empty());
+
+        let fresh = dir
+            .path()
+            .join(format!("{}.1234", CacheFormat::Binary.file_name()));
+        update_cache_with_response(&resp, &fresh).unwrap();
+        assert!(fs::read(&fresh).unwrap().starts_with(CACHE_MAGIC));
+    }
+
+    #[test]
+    fn upload_queue_sits_next_to_the_configured_cache_dir() {
+        assert_eq!(
+            default_upload_queue_path(Path::new("/tmp/lazysync/cache")),
+            Path::new("/tmp/lazysync/uploads.json")
+        );
+        assert_eq!(
+            default_upload_queue_path(Path::new("/srv/app-cache")),
+            Path::new("/srv/uploads.json")
+        );
+    }
+
+    #[test]
+    fn mirror_accepts_only_plain_entry_names() {
+        for name in ["file", "a.b", ".hidden", "..x", "with space"] {
+            assert!(is_plain_name(name), "{}", name);
+        }
+        for name in ["", ".", "..", "a/b", "../x", "/etc", "a/", "./a"] {
+            assert!(!is_plain_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn relative_path_handles_nested_sibling_and_unrelated_paths() {
+        assert_eq!(
+            relative_path("/srv/data", "/srv/data/a/b").as_deref(),
+            Some("a/b")
+        );
+        assert_eq!(
+            relative_path("/srv/data/", "/srv/data/a/").as_deref(),
+            Some("a")
+        );
+        assert_eq!(
+            relative_path("/srv/data/a/b", "/srv/data").as_deref(),
+            Some("../..")
+        );
+        assert_eq!(
+            relative_path("/srv/a", "/srv/b/c").as_deref(),
+            Some("../b/c")
+        );
+        assert_eq!(
+            relative_path("/srv/a/../b", "/srv/b/x").as_deref(),
+            Some("x")
+        );
+        assert_eq!(relative_path("/srv", "/srv/").as_deref(), Some("."));
+        assert_eq!(relative_path("/", "/etc").as_deref(), Some("etc"));
+        assert_eq!(
+            relative_path("docs", "src/lib.rs").as_deref(),
+            Some("../src/lib.rs")
+        );
+        // One absolute and one relative path share no root.
+        assert_eq!(relative_path("/srv", "srv"), None);
+        assert_eq!(relative_path("src", "/src"), None);
+        // `..` climbing above the root cannot be expressed.
+        assert_eq!(relative_path("/srv", "/../etc"), None);
+    }
+
+    // A JSON-over-TCP server that resolves relative requests under /srv, like a
+    // server started in /srv would, and answers each with a single file.
+    fn mock_server() -> (String, Arc<AtomicU64>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming().map_while(Result::ok) {
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || {
+                    let mut writer = stream.try_clone().unwrap();
+                    for line in BufReader::new(stream).lines().map_while(Result::ok) {
+                        let req: serde_json::Value = serde_json::from_str(&line).unwrap();
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        let path = req["path"].as_str().unwrap();
+                        let dir = if path.starts_with('/') {
+                            path.to_string()
+                        } else {
+                            format!("/srv/{}", path)
+                        };
+                        let file = serde_json::json!({
+                            "name": "file.txt",
+                            "file_type": "file",
+                            "permissions": "-rw-r--r--",
+                            "absolute_path": format!("{}/file.txt", dir),
+                            "modified": "2024-01-01 00:00:00",
+                            "size": 3,
+                        });
+                        let resp = serde_json::json!({
+                            "id": req["id"],
+                            "path": path,
+                            "data": [{ dir: [file] }],
+                        });
+                        if writeln!(writer, "{}", resp).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn relative_request_is_cached_under_both_paths() {
+        let (addr, requests) = mock_server();
+        let dir = tempfile::tempdir().unwrap();
+        let client = Client::builder()
+            .cache_dir(dir.path())
+            .connect(&addr)
+            .unwrap();
+
+        let entries = client.get_path("docs/").await.unwrap();
+        assert_eq!(entries[0].name, "file.txt");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        assert_eq!(client.get_path("docs").await.unwrap()[0].name, "file.txt");
+        assert_eq!(
+            client.get_path("/srv/docs/").await.unwrap()[0].name,
+            "file.txt"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(client.cache_stats().hits, 2);
+    }
+
+    #[tokio::test]
+    async fn cache_ttl_refetches_listings_written_too_long_ago() {
+        let (addr, requests) = mock_server();
+        let dir = tempfile::tempdir().unwrap();
+        let old = CachedListing {
+            entries: vec![entry(1, "2020-01-01 00:00:00", "-rw-r--r--")],
+            cached_at: Some(unix_millis_now() - 3_600_000),
+        };
+        let snapshot = CacheSnapshot {
+            listings: HashMap::from([("/srv/old".to_string(), old)]),
+            ..Default::default()
+        };
+
+        // Without a TTL the hour-old listing is still served from the cache.
+        let client = Client::builder()
+            .cache_dir(dir.path())
+            .connect(&addr)
+            .unwrap();
+        client.restore_cache(snapshot.clone());
+        assert_eq!(client.get_path("/srv/old").await.unwrap()[0].name, "file");
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+        client.close();
+
+        let client = Client::builder()
+            .cache_dir(dir.path())
+            .cache_ttl(Duration::from_secs(60))
+            .connect(&addr)
+            .unwrap();
+        client.restore_cache(snapshot);
+        assert_eq!(
+            client.get_path("/srv/old").await.unwrap()[0].name,
+            "file.txt"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // The refetched listing is fresh, so the next lookup is a hit.
+        client.get_path("/srv/old").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn invalidated_paths_are_fetched_again() {
+        let (addr, requests) = mock_server();
+        let dir = tempfile::tempdir().unwrap();
+        let client = Client::builder()
+            .cache_dir(dir.path())
+            .connect(&addr)
+            .unwrap();
+
+        client.get_path("/srv/a").await.unwrap();
+        client.get_path("/srv/b").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        assert!(client.invalidate("/srv/a/"));
+        assert!(!client.invalidate("/srv/a"));
+        client.get_path("/srv/a").await.unwrap();
+        client.get_path("/srv/b").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        client.invalidate_all();
+        assert_eq!(client.cache_len(), 0);
+        client.get_path("/srv/b").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        client.invalidate_all();
+        client.close();
+        let cache_file = dir.path().join(CacheFormat::Binary.file_name());
+        assert!(cache_file.exists());
+        assert!(load_cache(&cache_file).is_empty());
+    }
+
+    #[test]
+    fn corrupt_cache_files_load_empty_and_are_replaced_cleanly() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = dir.path().join("cache.json");
+        let binary = dir.path().join("cache.bin");
+        // A save killed halfway through: cut-off JSON, and a binary header with a partial body.
+        fs::write(&json, br#"{"/srv": {"entries": [{"name": "a""#).unwrap();
+        let mut bytes = CACHE_MAGIC.to_vec();
+        bytes.extend_from_slice(&CACHE_FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&[7, 0, 0]);
+        fs::write(&binary, bytes).unwrap();
+
+        for (path, format) in [(&json, CacheFormat::Json), (&binary, CacheFormat::Binary)] {
+            assert!(load_cache(path).is_empty());
+            let (cache, flusher) = flushed_store(path);
+            assert_eq!(cache.len(), 0);
+            cache.insert_all([("/srv".to_string(), Vec::new())]);
+            cache.close();
+            flusher.join().unwrap();
+            assert!(load_cache(path).contains_key("/srv"));
+
+            save_cache_as(&load_cache(path), path, format).unwrap();
+            assert!(load_cache(path).contains_key("/srv"));
+        }
+        let leftovers: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.contains(".tmp."))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+
+    #[tokio::test]
+    async fn no_cache_always_asks_the_server_and_writes_nothing() {
+        let (addr, requests) = mock_server();
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join("cache");
+        let client = Client::builder()
+            .cache_dir(&cache_dir)
+            .no_cache()
+            .connect(&addr)
+            .unwrap();
+
+        client.get_path("/srv/a").await.unwrap();
+        client.get_path("/srv/a").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(client.cache_len(), 0);
+        client.close();
+        assert!(!cache_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn cache_snapshot_round_trips_through_json() {
+        let (addr, requests) = mock_server();
+        let dir = tempfile::tempdir().unwrap();
+        let client = Client::builder()
+            .cache_dir(dir.path())
+            .connect(&addr)
+            .unwrap();
+        client.get_path("/srv/a").await.unwrap();
+        client.get_path("/srv/b").await.unwrap();
+        client.get_path("/srv/a").await.unwrap();
+        let saved = serde_json::to_string(&client.snapshot_cache()).unwrap();
+        client.close();
+
+        let restored_dir = tempfile::tempdir().unwrap();
+        let client = Client::builder()
+            .cache_dir(restored_dir.path())
+            .connect(&addr)
+            .unwrap();
+        client.restore_cache(serde_json::from_str(&saved).unwrap());
+        let stats = client.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 2));
+        assert_eq!(client.recent_paths(10), ["/srv/a", "/srv/b"]);
+        let snapshot = client.snapshot_cache();
+        assert_eq!(snapshot.order, ["/srv/a", "/srv/b"]);
+
+        client.get_path("/srv/b").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn unusable_cache_dir_falls_back_to_memory() {
+        let (addr, requests) = mock_server();
+        let dir = tempfile::tempdir().unwrap();
+        // Even root cannot create a directory below a regular file.
+        let blocker = dir.path().join("not-a-dir");
+        fs::write(&blocker, b"").unwrap();
+        let client = Client::builder()
+            .cache_dir(blocker.join("cache"))
+            .connect(&addr)
+            .unwrap();
+
+        assert_eq!(client.get_path("/srv/a").await.unwrap()[0].name, "file.txt");
+        client.get_path("/srv/a").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(client.cache_len(), 1);
+        client.close();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    // Paths with doubled and trailing slashes, `.` and `..`, and stray whitespace.
+    fn any_path() -> impl Strategy<Value = String> {
+        "[ ]?/?(([a-z]{1,3}|\\.|\\.\\.| [a-z])/{1,2}){0,4}[a-z.]{0,3}/{0,2}[ ]?"
+    }
+
+    // Absolute paths the way the server reports them: canonical, no trailing slash.
+    fn server_path() -> impl Strategy<Value = String> {
+        proptest::collection::vec("[a-z][a-z0-9._-]{0,5}", 0..5)
+            .prop_map(|parts| format!("/{}", parts.join("/")))
+    }
+
+    proptest! {
+        #[test]
+        fn normalize_path_is_idempotent(path in any_path()) {
+            let once = normalize_path(&path);
+            prop_assert_eq!(normalize_path(&once), once);
+        }
+
+        #[test]
+        fn trailing_slash_gives_the_same_cache_key(path in any_path()) {
+            let key = NormalizedPath::from(path.as_str());
+            prop_assume!(!key.as_str().is_empty());
+            prop_assert_eq!(&NormalizedPath::from(format!("{}/", path).as_str()), &key);
+            prop_assert_eq!(&NormalizedPath::from(format!("{}//", key).as_str()), &key);
+        }
+
+        #[test]
+        fn response_paths_are_always_found(
+            server in server_path(),
+            other in server_path(),
+            slashes in "/{0,2}",
+            relative_depth in 0usize..5,
+        ) {
+            let resp = Response {
+                id: 1,
+                path: server.clone(),
+                data: vec![
+                    HashMap::from([(other.clone(), Vec::new())]),
+                    HashMap::from([(server.clone(), Vec::new())]),
+                ],
+            };
+            let requested = format!("{}{}", server, slashes);
+            prop_assert!(find_response_entries(&resp, &NormalizedPath::from(requested.as_str())).is_some());
+
+            // A relative request the server resolved against its working directory.
+            let parts: Vec<&str> = server.split('/').filter(|part| !part.is_empty()).collect();
+            if relative_depth > 0 && relative_depth <= parts.len() {
+                let relative = parts[parts.len() - relative_depth..].join("/");
+                for form in [relative.clone(), format!("./{}", relative), format!("{}/", relative)] {
+                    let found = find_response_entries(&resp, &NormalizedPath::from(form.as_str()));
+                    prop_assert!(found.is_some(), "{} not found in {:?}", form, resp.data);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unparsable_responses_fail_with_the_raw_line() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for _ in BufReader::new(stream).lines().map_while(Result::ok) {
+                writeln!(writer, r#"{{"id": 1, "path": "/srv", "data": "oops"}}"#).unwrap();
+            }
+        });
+        let client = Client::builder().no_cache().connect(&addr).unwrap();
+
+        match client.get_path("/srv").await {
+            Err(ClientError::BadResponse(message)) => {
+                assert!(message.contains(r#""data": "oops""#))
+            }
+            other => panic!("unexpected {:?}", other.map(|entries| entries.len())),
+        }
+        assert_eq!(client.last_errors().len(), 1);
+    }
+
+    #[test]
+    fn normalize_entry_reads_special_bits_from_permissions() {
+        let cases = [
+            ("-rwsr-xr-x", (true, false, false)),
+            ("-rwSr--r--", (true, false, false)),
+            ("-rwxr-sr-x", (false, true, false)),
+            ("-rw-r-Sr--", (false, true, false)),
+            ("drwxrwxrwt", (false, false, true)),
+            ("drwxrwxrwT", (false, false, true)),
+            ("-rwsr-sr-t", (true, true, true)),
+            ("-rwSr-Sr-T", (true, true, true)),
+            ("-rwxr-xr-x", (false, false, false)),
+        ];
+        for (permissions, flags) in cases {
+            let mut raw = entry(1, "2024-01-01 00:00:00", permissions);
+            raw.file_type = String::new();
+            let entry = normalize_entry(raw);
+            let expected_type = if permissions.starts_with('d') {
+                "dir"
+            } else {
+                "file"
+            };
+            assert_eq!(entry.file_type, expected_type, "{}", permissions);
+            assert_eq!(
+                entry.is_dir,
+                permissions.starts_with('d'),
+                "{}",
+                permissions
+            );
+            assert_eq!(entry.permissions, permissions);
+            assert_eq!(
+                (entry.setuid, entry.setgid, entry.sticky),
+                flags,
+                "{}",
+                permissions
+            );
+        }
+    }
+}
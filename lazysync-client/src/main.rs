@@ -1,14 +1,29 @@
 use axum::{
-    extract::Json,
-    http::StatusCode,
-    response::Json as ResponseJson,
-    routing::post,
+    body::Body,
+    extract::{Json, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json as ResponseJson},
+    routing::{get, post},
     Router,
 };
-use rfb_client::{Client, FileEntry, Response, get_path_from_cache, update_cache_with_response};
+use rfb_client::{
+    lazysync::{lazy_sync_client::LazySyncClient, GetPathRequest, StatRequest},
+    new_trace_id, read_text_with, ClientError, FileEntry, RecentPaths, Request, Response,
+};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
-use tokio::sync::oneshot;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Channel, Endpoint};
 use tower::ServiceBuilder;
 use tower_http::cors::CorsLayer;
 
@@ -32,9 +47,68 @@ struct GetPathResponse {
     from_cache: bool,
 }
 
+#[derive(Deserialize)]
+struct ExportQuery {
+    path: String,
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[derive(Deserialize)]
+struct ReadTextQuery {
+    path: String,
+    #[serde(default)]
+    offset: u64,
+    #[serde(default)]
+    length: u64,
+}
+
+// /export 每行输出一个条目：绝对路径 + FileEntry 字段
+#[derive(Serialize)]
+struct ExportLine {
+    path: String,
+    #[serde(flatten)]
+    entry: FileEntry,
+}
+
+type AppState = (
+    Arc<Mutex<RecentPaths>>,
+    Arc<Mutex<u64>>,
+    Arc<Mutex<TcpStream>>,
+    Arc<Mutex<HashMap<u64, oneshot::Sender<Response>>>>,
+);
+
+// 调用方可以通过该请求头传入 trace id，响应里会原样带回
+const TRACE_ID_HEADER: &str = "x-trace-id";
+
+fn trace_id_from(headers: &HeaderMap) -> String {
+    headers
+        .get(TRACE_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(new_trace_id)
+}
+
 // ===== Cache 管理 =====
 const CACHE_FILE: &str = "cache.json";
 
+// 服务器地址，可以用环境变量 LAZYSYNC_SERVER 覆盖。/request、/get 的 JSON/TCP 连接和
+// /export、/read-text 的 gRPC 连接都发往这个地址；rfb_server 只提供 gRPC，
+// JSON/TCP 协议需要在这个地址上另有服务端
+const SERVER_ADDR_ENV: &str = "LAZYSYNC_SERVER";
+const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:9000";
+
+fn server_addr() -> String {
+    match std::env::var(SERVER_ADDR_ENV) {
+        Ok(addr) if !addr.trim().is_empty() => addr.trim().to_string(),
+        _ => DEFAULT_SERVER_ADDR.to_string(),
+    }
+}
+
+// /export 每次请求的条目数；按名字排序分页，服务器因响应大小截断时从下一个 offset 继续
+const EXPORT_PAGE_SIZE: u32 = 1000;
+
 type CacheData = HashMap<String, Vec<FileEntry>>;
 
 fn load_cache() -> CacheData {
@@ -63,13 +137,18 @@ fn update_cache_with_response(resp: &Response) -> std::io::Result<()> {
             // 将FileInfo转换为FileEntry格式（用于cache兼容性）
             // 使用权限字符串的第一个字符判断是否为目录（'d'表示目录）
             let file_entries: Vec<FileEntry> = entries.iter().map(|fi| {
-                let is_dir = fi.permissions.chars().next() == Some('d');
+                let is_dir = fi.permissions.starts_with('d');
                 FileEntry {
                     name: fi.name.clone(),
                     is_dir,
+                    file_type: fi.file_type.clone(),
                     size: fi.size,
+                    size_known: fi.size_known,
                     permissions: fi.permissions.clone(),
                     modified: fi.modified.clone(),
+                    setuid: fi.setuid,
+                    setgid: fi.setgid,
+                    sticky: fi.sticky,
                 }
             }).collect();
             cache.insert(abs_path.clone(), file_entries);
@@ -88,7 +167,8 @@ fn get_path_from_cache(path: &str) -> Option<Vec<FileEntry>> {
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     // 连接TCP服务器
-    let stream = TcpStream::connect("127.0.0.1:9000")?;
+    let server_addr = server_addr();
+    let stream = TcpStream::connect(&server_addr)?;
     stream.set_nodelay(true)?;
     println!("Connected to server.");
 
@@ -96,7 +176,7 @@ async fn main() -> std::io::Result<()> {
     let reader = BufReader::new(stream);
 
     // 共享状态
-    let recent = Arc::new(Mutex::new(Option::<String>::None));
+    let recent = Arc::new(Mutex::new(RecentPaths::default()));
     let req_id = Arc::new(Mutex::new(0u64));
     let writer_mutex = Arc::new(Mutex::new(writer));
     let response_channels: Arc<Mutex<HashMap<u64, oneshot::Sender<Response>>>> = Arc::new(Mutex::new(HashMap::new()));
@@ -105,7 +185,7 @@ async fn main() -> std::io::Result<()> {
     {
         let response_channels_clone = Arc::clone(&response_channels);
         thread::spawn(move || {
-            for line in reader.lines().flatten() {
+            for line in reader.lines().map_while(Result::ok) {
                 // 打印收到的原始响应
                 println!("=== Received raw response ===");
                 println!("{}", line);
@@ -153,7 +233,7 @@ async fn main() -> std::io::Result<()> {
             thread::sleep(Duration::from_secs(3));
             let path_opt: Option<String> = {
                 let r = recent.lock().unwrap();
-                r.clone()
+                r.latest().map(str::to_string)
             };
 
             if let Some(path) = path_opt {
@@ -162,6 +242,7 @@ async fn main() -> std::io::Result<()> {
                 let req = Request {
                     id: *id,
                     path: path.clone(),
+                    trace_id: Some(new_trace_id()),
                 };
                 if let Ok(mut w) = writer_mutex.lock() {
                     writeln!(w, "{}", serde_json::to_string(&req).unwrap()).ok();
@@ -170,10 +251,20 @@ async fn main() -> std::io::Result<()> {
         });
     }
 
+    // gRPC 连接（/export、/read-text 使用），第一次请求时才真正建立
+    let endpoint = Endpoint::from_shared(format!("http://{}", server_addr))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let rpc = LazySyncClient::new(endpoint.connect_lazy());
+    let rpc_routes = Router::new()
+        .route("/export", get(handle_export))
+        .route("/read-text", get(handle_read_text))
+        .with_state(rpc);
+
     // 创建HTTP服务器
     let app = Router::new()
         .route("/request", post(handle_request))
         .route("/get", post(handle_get))
+        .merge(rpc_routes)
         .layer(
             ServiceBuilder::new()
                 .layer(CorsLayer::permissive())
@@ -192,24 +283,19 @@ async fn main() -> std::io::Result<()> {
 
 // HTTP处理函数
 async fn handle_request(
-    axum::extract::State((recent, req_id, writer_mutex, _)): axum::extract::State<(
-        Arc<Mutex<Option<String>>>,
-        Arc<Mutex<u64>>,
-        Arc<Mutex<TcpStream>>,
-        Arc<Mutex<HashMap<u64, oneshot::Sender<Response>>>>,
-    )>,
+    axum::extract::State((recent, req_id, writer_mutex, _)): axum::extract::State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<PathRequest>,
-) -> Result<ResponseJson<PathResponse>, StatusCode> {
+) -> Result<impl IntoResponse, StatusCode> {
     let path = payload.path.trim().to_string();
     if path.is_empty() {
         return Err(StatusCode::BAD_REQUEST);
     }
+    let trace_id = trace_id_from(&headers);
+    println!("[{}] /request {}", trace_id, path);
 
-    // 更新最近路径（只保留最新的）
-    {
-        let mut r = recent.lock().unwrap();
-        *r = Some(path.clone());
-    }
+    // 更新最近路径
+    recent.lock().unwrap().push(&path);
 
     // 发送请求
     let mut id = req_id.lock().unwrap();
@@ -217,15 +303,19 @@ async fn handle_request(
     let req = Request {
         id: *id,
         path: path.clone(),
+        trace_id: Some(trace_id.clone()),
     };
 
     if let Ok(mut writer) = writer_mutex.lock() {
         if writeln!(writer, "{}", serde_json::to_string(&req).unwrap()).is_ok() {
             writer.flush().ok();
-            Ok(ResponseJson(PathResponse {
-                success: true,
-                message: format!("Request sent for path: {}", path),
-            }))
+            Ok((
+                [(TRACE_ID_HEADER, trace_id)],
+                ResponseJson(PathResponse {
+                    success: true,
+                    message: format!("Request sent for path: {}", path),
+                }),
+            ))
         } else {
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
@@ -236,39 +326,34 @@ async fn handle_request(
 
 // 新的HTTP处理函数：获取路径数据（带cache检查）
 async fn handle_get(
-    axum::extract::State((recent, req_id, writer_mutex, response_channels)): axum::extract::State<(
-        Arc<Mutex<Option<String>>>,
-        Arc<Mutex<u64>>,
-        Arc<Mutex<TcpStream>>,
-        Arc<Mutex<HashMap<u64, oneshot::Sender<Response>>>>,
-    )>,
+    axum::extract::State((recent, req_id, writer_mutex, response_channels)): axum::extract::State<AppState>,
+    headers: HeaderMap,
     Json(payload): Json<PathRequest>,
-) -> Result<ResponseJson<GetPathResponse>, StatusCode> {
+) -> Result<impl IntoResponse, StatusCode> {
     let path = payload.path.trim().to_string();
     if path.is_empty() {
         return Err(StatusCode::BAD_REQUEST);
     }
+    let trace_id = trace_id_from(&headers);
 
     // 1. 先检查cache
     if let Some(entries) = get_path_from_cache(&path) {
         // 有cache，更新recent并返回
-        {
-            let mut r = recent.lock().unwrap();
-            *r = Some(path.clone());
-        }
-        return Ok(ResponseJson(GetPathResponse {
-            success: true,
-            path: path.clone(),
-            entries,
-            from_cache: true,
-        }));
+        recent.lock().unwrap().push(&path);
+        return Ok((
+            [(TRACE_ID_HEADER, trace_id)],
+            ResponseJson(GetPathResponse {
+                success: true,
+                path: path.clone(),
+                entries,
+                from_cache: true,
+            }),
+        ));
     }
+    println!("[{}] /get {}", trace_id, path);
 
     // 2. 没有cache，更新recent，发送请求并等待响应
-    {
-        let mut r = recent.lock().unwrap();
-        *r = Some(path.clone());
-    }
+    recent.lock().unwrap().push(&path);
 
     // 创建channel等待响应
     let (tx, rx) = oneshot::channel();
@@ -287,6 +372,7 @@ async fn handle_get(
         let req = Request {
             id: req_id,
             path: path.clone(),
+            trace_id: Some(trace_id.clone()),
         };
 
         if let Ok(mut writer) = writer_mutex.lock() {
@@ -339,13 +425,18 @@ async fn handle_get(
                         // 找到匹配的路径，转换FileInfo为FileEntry
                         // 使用权限字符串的第一个字符判断是否为目录（'d'表示目录）
                         found_entries = file_infos.iter().map(|fi| {
-                            let is_dir = fi.permissions.chars().next() == Some('d');
+                            let is_dir = fi.permissions.starts_with('d');
                             FileEntry {
                                 name: fi.name.clone(),
                                 is_dir,
+                                file_type: fi.file_type.clone(),
                                 size: fi.size,
+                                size_known: fi.size_known,
                                 permissions: fi.permissions.clone(),
                                 modified: fi.modified.clone(),
+                                setuid: fi.setuid,
+                                setgid: fi.setgid,
+                                sticky: fi.sticky,
                             }
                         }).collect();
                         response_path = abs_path.clone();
@@ -358,12 +449,15 @@ async fn handle_get(
             }
             
             // 如果没找到，返回空列表（可能路径不存在或不是目录）
-            Ok(ResponseJson(GetPathResponse {
-                success: true,
-                path: response_path,
-                entries: found_entries,
-                from_cache: false,
-            }))
+            Ok((
+                [(TRACE_ID_HEADER, trace_id)],
+                ResponseJson(GetPathResponse {
+                    success: true,
+                    path: response_path,
+                    entries: found_entries,
+                    from_cache: false,
+                }),
+            ))
         }
         Ok(Err(_)) => {
             // channel错误
@@ -379,3 +473,127 @@ async fn handle_get(
         }
     }
 }
+
+// 以 NDJSON 流式导出目录列表（recursive=true 时逐层遍历子目录）
+async fn handle_export(
+    axum::extract::State(mut rpc): axum::extract::State<LazySyncClient<Channel>>,
+    headers: HeaderMap,
+    Query(query): Query<ExportQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let path = query.path.trim().to_string();
+    if path.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let trace_id = trace_id_from(&headers);
+    println!("[{}] /export {}", trace_id, path);
+
+    // 先 stat 拿到服务器解析后的绝对路径，后续都用绝对路径请求
+    let stat = rpc
+        .stat(StatRequest { path })
+        .await
+        .map_err(|_| StatusCode::BAD_GATEWAY)?
+        .into_inner();
+    let root = match stat.info {
+        Some(info) if stat.exists => info.absolute_path,
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+
+    let (tx, rx) = mpsc::channel::<Result<String, std::io::Error>>(64);
+    tokio::spawn(async move {
+        let mut pending = VecDeque::from([root]);
+        while let Some(dir) = pending.pop_front() {
+            // 一个目录分多页取完：服务器的响应超过大小上限时会截断（truncated），
+            // 按名字排序保证各页顺序一致，从已收到的条目数继续
+            let mut offset = 0u32;
+            loop {
+                let resp = match rpc
+                    .get_path(GetPathRequest {
+                        path: dir.clone(),
+                        trace_id: trace_id.clone(),
+                        sort: "name".to_string(),
+                        offset,
+                        limit: EXPORT_PAGE_SIZE,
+                        ..Default::default()
+                    })
+                    .await
+                {
+                    Ok(resp) => resp.into_inner(),
+                    Err(status) => {
+                        let line = serde_json::json!({ "error": status.message(), "path": dir });
+                        let _ = tx.send(Ok(format!("{}\n", line))).await;
+                        return;
+                    }
+                };
+
+                let truncated = resp.truncated;
+                let listing = resp
+                    .entries
+                    .into_iter()
+                    .find(|d| d.absolute_path.trim_end_matches('/') == dir.trim_end_matches('/'));
+                let entries = listing.map(|d| d.entries).unwrap_or_default();
+                let received = entries.len() as u32;
+                for info in entries {
+                    // 只进入真正的目录，不跟随符号链接，避免循环
+                    if query.recursive && info.file_type == "dir" {
+                        pending.push_back(info.absolute_path.clone());
+                    }
+                    let line = ExportLine {
+                        path: info.absolute_path.clone(),
+                        entry: FileEntry::from(info),
+                    };
+                    let Ok(json) = serde_json::to_string(&line) else {
+                        continue;
+                    };
+                    if tx.send(Ok(format!("{}\n", json))).await.is_err() {
+                        return;
+                    }
+                }
+
+                if !truncated && received < EXPORT_PAGE_SIZE {
+                    break;
+                }
+                // 截断后一条都没拿到（单个条目就超过上限）：再请求也不会前进，
+                // 输出一行错误让调用方知道这个目录不完整
+                if received == 0 {
+                    let line = serde_json::json!({
+                        "error": "listing truncated by the server",
+                        "path": dir,
+                        "truncated": true,
+                    });
+                    let _ = tx.send(Ok(format!("{}\n", line))).await;
+                    break;
+                }
+                offset += received;
+            }
+        }
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(ReceiverStream::new(rx)),
+    ))
+}
+
+// 读取文件的一段字节并检测编码后以文本返回（长度上限 MAX_READ_TEXT）
+async fn handle_read_text(
+    axum::extract::State(mut rpc): axum::extract::State<LazySyncClient<Channel>>,
+    headers: HeaderMap,
+    Query(query): Query<ReadTextQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let trace_id = trace_id_from(&headers);
+    println!("[{}] /read-text {}", trace_id, query.path);
+
+    match read_text_with(&mut rpc, &query.path, query.offset, query.length).await {
+        Ok(decoded) => Ok(([(TRACE_ID_HEADER, trace_id)], ResponseJson(decoded))),
+        Err(ClientError::Rpc(status)) => {
+            let code = match status.code() {
+                tonic::Code::NotFound => StatusCode::NOT_FOUND,
+                tonic::Code::PermissionDenied => StatusCode::FORBIDDEN,
+                tonic::Code::InvalidArgument => StatusCode::BAD_REQUEST,
+                _ => StatusCode::BAD_GATEWAY,
+            };
+            Err((code, status.message().to_string()))
+        }
+        Err(e) => Err((StatusCode::BAD_GATEWAY, e.to_string())),
+    }
+}
@@ -1,12 +1,25 @@
 use std::{
+    collections::{hash_map::RandomState, HashSet},
     fs,
-    os::unix::fs::PermissionsExt,
+    hash::{BuildHasher, Hasher},
+    io::Read,
+    os::unix::fs::{MetadataExt, PermissionsExt},
     path::{Path, PathBuf},
-    time::SystemTime,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+use globset::{GlobBuilder, GlobMatcher};
+use prost::Message;
+use sha2::{Digest, Sha256};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tokio::sync::mpsc;
+use tokio::sync::{
+    broadcast,
+    mpsc::{self, error::SendTimeoutError},
+};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{transport::Server, Request, Response, Status};
 
@@ -16,37 +29,216 @@ pub mod lazysync {
 
 use lazysync::{
     lazy_sync_server::{LazySync, LazySyncServer},
-    DirEntries, FileInfo, GetPathRequest, GetPathResponse, HealthRequest, HealthResponse,
-    ReadFileChunk, ReadFileRequest, StatRequest, StatResponse, WriteFileChunk,
-    WriteFileResponse,
+    ArchiveEntry, ArchivePathRequest, ChecksumRequest, ChecksumResponse, CompareFilesRequest,
+    CompareFilesResponse, CopyProgress, CopyRequest, CreateFileRequest, CreateFileResponse,
+    CreateSymlinkRequest, CreateSymlinkResponse, DirEntries, ExistsBatchRequest,
+    ExistsBatchResponse, FileInfo, GetPathRequest, GetPathResponse, HealthRequest, HealthResponse,
+    Invalidation, ListArchiveRequest, ListArchiveResponse, ListMountsRequest, ListMountsResponse,
+    MountInfo, MoveRequest, MoveResponse, ReadArchiveEntryRequest, ReadFileChunk, ReadFileRequest,
+    ReadFilesChunk, ReadFilesRequest, ReadTailRequest, RealPathRequest, RealPathResponse,
+    SearchRequest, SearchResponse, SetOwnerRequest, SetOwnerResponse, SniffRequest, SniffResponse,
+    StatBatchRequest, StatBatchResponse, StatBatchResult, StatRequest, StatResponse,
+    SwapPathsRequest, SwapPathsResponse, SyncPathRequest, SyncPathResponse,
+    WatchInvalidationsRequest, WriteFileChunk, WriteFileResponse,
 };
 
 const READ_CHUNK_SIZE: usize = 64 * 1024;
+const TRACE_ID_HEADER: &str = "x-trace-id";
+// Chunks queued per read_file stream. 8 is the old hard-coded depth, kept
+// untuned: it has not been benchmarked on fast-LAN or high-latency links, so
+// measure before changing LAZYSYNC_READ_BUFFER for a deployment.
+const DEFAULT_READ_BUFFER: usize = 8;
+const MAX_READ_BUFFER: usize = 256;
+// Matches tonic's default decode limit, so a capped response is always receivable.
+const DEFAULT_MAX_RESPONSE: usize = 4 * 1024 * 1024;
+const DEFAULT_CONCURRENCY_PER_CONNECTION: usize = 32;
+const DEFAULT_MAX_CONCURRENT_STREAMS: u32 = 128;
+const DEFAULT_READ_STALL_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_READ_MAX_DURATION_SECS: u64 = 6 * 60 * 60;
+// Covers everything up to the handler returning, which includes a whole
+// write_file upload, so keep it generous.
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_TCP_KEEPALIVE_SECS: u64 = 60;
+const DEFAULT_SNIFF_BYTES: usize = 4 * 1024;
+const DEFAULT_TAIL_BYTES: u64 = 64 * 1024;
+// How far a line_aligned read_file may move either end of the range.
+const LINE_ALIGN_SLACK: u64 = 16 * 1024;
+const DEFAULT_SKIP_SORT_OVER: usize = 100_000;
+const MAX_SNIFF_BYTES: usize = 64 * 1024;
+const DEFAULT_SEARCH_RESULTS: usize = 1_000;
+const MAX_SEARCH_RESULTS: usize = 100_000;
 
-fn format_permissions(meta: &fs::Metadata) -> String {
-    let perms = meta.permissions();
-    let mode = perms.mode();
+// tonic::Status is large (~176 bytes), so helpers return it boxed to keep
+// their Results small; `?` in a handler turns it back into a Status.
+#[derive(Debug)]
+struct BoxedStatus(Box<Status>);
 
-    let mut result = String::with_capacity(10);
+impl From<Status> for BoxedStatus {
+    fn from(status: Status) -> Self {
+        Self(Box::new(status))
+    }
+}
 
-    let file_type = meta.file_type();
-    result.push(if file_type.is_symlink() {
+impl From<BoxedStatus> for Status {
+    fn from(status: BoxedStatus) -> Self {
+        *status.0
+    }
+}
+
+impl std::ops::Deref for BoxedStatus {
+    type Target = Status;
+
+    fn deref(&self) -> &Status {
+        &self.0
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+// Zero means "disabled".
+fn env_secs(name: &str, default: u64) -> Option<Duration> {
+    Some(env_or(name, default))
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|value| matches!(value.trim(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+static BASE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+// Relative request paths resolve against this; set once at startup.
+fn base_dir() -> &'static Path {
+    BASE_DIR.get_or_init(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
+}
+
+// How request paths are interpreted; see validate_request_path.
+#[derive(Clone, Copy, Default)]
+struct PathPolicy {
+    // Reject absolute paths and `..` components so requests stay under the base dir.
+    sandbox: bool,
+    // Expand a leading `~` and `$VAR` / `${VAR}` before anything else.
+    expand: bool,
+}
+
+struct ServerConfig {
+    read_buffer: usize,
+    base_dir: PathBuf,
+    read_only: bool,
+    no_follow_symlinks: bool,
+    paths: PathPolicy,
+    max_response: usize,
+    // Zero disables either read_file guard.
+    read_stall_timeout: Option<Duration>,
+    read_max_duration: Option<Duration>,
+    limits: ConnectionLimits,
+}
+
+// Transport-level limits; a zero timeout or keepalive disables it.
+struct ConnectionLimits {
+    concurrency_per_connection: usize,
+    max_concurrent_streams: u32,
+    request_timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+}
+
+impl ConnectionLimits {
+    fn from_env() -> Self {
+        Self {
+            concurrency_per_connection: env_or(
+                "LAZYSYNC_CONCURRENCY_PER_CONNECTION",
+                DEFAULT_CONCURRENCY_PER_CONNECTION,
+            )
+            .max(1),
+            max_concurrent_streams: env_or(
+                "LAZYSYNC_MAX_CONCURRENT_STREAMS",
+                DEFAULT_MAX_CONCURRENT_STREAMS,
+            )
+            .max(1),
+            request_timeout: env_secs(
+                "LAZYSYNC_REQUEST_TIMEOUT_SECS",
+                DEFAULT_REQUEST_TIMEOUT_SECS,
+            ),
+            tcp_keepalive: env_secs("LAZYSYNC_TCP_KEEPALIVE_SECS", DEFAULT_TCP_KEEPALIVE_SECS),
+        }
+    }
+}
+
+impl ServerConfig {
+    fn from_env() -> Self {
+        let base_dir = std::env::var_os("LAZYSYNC_BASE_DIR")
+            .map(PathBuf::from)
+            .or_else(|| std::env::current_dir().ok())
+            .unwrap_or_else(|| PathBuf::from("."));
+        Self {
+            base_dir: fs::canonicalize(&base_dir).unwrap_or(base_dir),
+            read_only: env_flag("LAZYSYNC_READONLY"),
+            no_follow_symlinks: env_flag("LAZYSYNC_NO_FOLLOW_SYMLINKS"),
+            paths: PathPolicy {
+                sandbox: env_flag("LAZYSYNC_SANDBOX_PATHS"),
+                expand: env_flag("LAZYSYNC_EXPAND_PATHS"),
+            },
+            max_response: env_or("LAZYSYNC_MAX_RESPONSE", DEFAULT_MAX_RESPONSE),
+            read_buffer: env_or("LAZYSYNC_READ_BUFFER", DEFAULT_READ_BUFFER)
+                .clamp(1, MAX_READ_BUFFER),
+            read_stall_timeout: env_secs(
+                "LAZYSYNC_READ_STALL_TIMEOUT_SECS",
+                DEFAULT_READ_STALL_TIMEOUT_SECS,
+            ),
+            read_max_duration: env_secs(
+                "LAZYSYNC_READ_MAX_DURATION_SECS",
+                DEFAULT_READ_MAX_DURATION_SECS,
+            ),
+            limits: ConnectionLimits::from_env(),
+        }
+    }
+}
+
+fn format_permissions(meta: &fs::Metadata) -> String {
+    let kind = if meta.file_type().is_symlink() {
         'l'
     } else if meta.is_dir() {
         'd'
     } else {
         '-'
-    });
+    };
+    format_mode(meta.permissions().mode(), kind)
+}
+
+// `kind` is the leading type character ('-', 'd' or 'l').
+fn format_mode(mode: u32, kind: char) -> String {
+    let mut result = String::with_capacity(10);
+    result.push(kind);
+
+    // Like ls: setuid/setgid/sticky take the execute slot, uppercase when the
+    // execute bit itself is clear.
+    let execute = |exec_bit: u32, special_bit: u32, special: char| {
+        let (exec, set) = (mode & exec_bit != 0, mode & special_bit != 0);
+        match (exec, set) {
+            (true, true) => special,
+            (false, true) => special.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        }
+    };
 
     result.push(if mode & 0o400 != 0 { 'r' } else { '-' });
     result.push(if mode & 0o200 != 0 { 'w' } else { '-' });
-    result.push(if mode & 0o100 != 0 { 'x' } else { '-' });
+    result.push(execute(0o100, 0o4000, 's'));
     result.push(if mode & 0o040 != 0 { 'r' } else { '-' });
     result.push(if mode & 0o020 != 0 { 'w' } else { '-' });
-    result.push(if mode & 0o010 != 0 { 'x' } else { '-' });
+    result.push(execute(0o010, 0o2000, 's'));
     result.push(if mode & 0o004 != 0 { 'r' } else { '-' });
     result.push(if mode & 0o002 != 0 { 'w' } else { '-' });
-    result.push(if mode & 0o001 != 0 { 'x' } else { '-' });
+    result.push(execute(0o001, 0o1000, 't'));
 
     result
 }
@@ -54,15 +246,7 @@ fn format_permissions(meta: &fs::Metadata) -> String {
 fn format_modified_time(meta: &fs::Metadata) -> String {
     if let Ok(modified) = meta.modified() {
         match modified.duration_since(SystemTime::UNIX_EPOCH) {
-            Ok(duration) => {
-                let secs = duration.as_secs();
-                if let Some(dt) = chrono::DateTime::from_timestamp(secs as i64, 0) {
-                    let local_dt = dt.with_timezone(&chrono::Local);
-                    local_dt.format("%Y-%m-%d %H:%M:%S").to_string()
-                } else {
-                    format!("{}", secs)
-                }
-            }
+            Ok(duration) => format_unix_time(duration.as_secs()),
             Err(_) => "N/A".to_string(),
         }
     } else {
@@ -70,14 +254,63 @@ fn format_modified_time(meta: &fs::Metadata) -> String {
     }
 }
 
+fn format_unix_time(secs: u64) -> String {
+    if let Some(dt) = chrono::DateTime::from_timestamp(secs as i64, 0) {
+        let local_dt = dt.with_timezone(&chrono::Local);
+        local_dt.format("%Y-%m-%d %H:%M:%S").to_string()
+    } else {
+        format!("{}", secs)
+    }
+}
+
+// Trace id for log lines: the request field wins, then the x-trace-id
+// metadata, else a random one so every handler invocation is still tagged.
+fn request_trace_id(metadata: &tonic::metadata::MetadataMap, field: &str) -> String {
+    if !field.is_empty() {
+        return field.to_string();
+    }
+    if let Some(value) = metadata
+        .get(TRACE_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+    {
+        return value.to_string();
+    }
+    format!("{:016x}", RandomState::new().build_hasher().finish())
+}
+
+// Opening a directory succeeds on some platforms and fails on others, and the
+// later read error is confusing either way, so reject it up front.
+fn ensure_not_directory(path: &Path) -> Result<(), BoxedStatus> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => {
+            Err(Status::failed_precondition(format!("is a directory: {}", path.display())).into())
+        }
+        _ => Ok(()),
+    }
+}
+
 fn to_absolute_path(path: &Path) -> PathBuf {
     if path.is_absolute() {
         path.to_path_buf()
     } else {
-        std::env::current_dir()
-            .unwrap_or_else(|_| PathBuf::from("."))
-            .join(path)
+        base_dir().join(path)
+    }
+}
+
+// Folds `.` and `..` without touching the filesystem, for paths that don't exist.
+fn lexical_normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
     }
+    normalized
 }
 
 fn file_type_string(meta: &fs::Metadata) -> String {
@@ -93,7 +326,7 @@ fn file_type_string(meta: &fs::Metadata) -> String {
     }
 }
 
-fn is_dir_or_symlink_dir(path: &Path) -> bool {
+fn is_dir_or_symlink_dir(path: &Path, no_follow: bool) -> bool {
     let meta = match fs::symlink_metadata(path) {
         Ok(m) => m,
         Err(_) => return false,
@@ -101,7 +334,7 @@ fn is_dir_or_symlink_dir(path: &Path) -> bool {
     if meta.is_dir() {
         return true;
     }
-    if meta.file_type().is_symlink() {
+    if meta.file_type().is_symlink() && !no_follow {
         if let Ok(target_meta) = fs::metadata(path) {
             return target_meta.is_dir();
         }
@@ -109,6 +342,73 @@ fn is_dir_or_symlink_dir(path: &Path) -> bool {
     false
 }
 
+// With no-follow enabled, refuse paths whose symlinks lead outside the base dir.
+fn ensure_within_root(path: &Path, no_follow: bool) -> Result<(), BoxedStatus> {
+    if !no_follow {
+        return Ok(());
+    }
+    let absolute: PathBuf = to_absolute_path(path).components().collect();
+    let resolved = match fs::canonicalize(&absolute) {
+        Ok(resolved) => resolved,
+        // Not created yet: check where the parent directory really is.
+        Err(_) if fs::symlink_metadata(&absolute).is_err() => {
+            let parent = absolute.parent().and_then(|p| fs::canonicalize(p).ok());
+            match (parent, absolute.file_name()) {
+                (Some(parent), Some(name)) => parent.join(name),
+                _ => return Ok(()),
+            }
+        }
+        Err(_) => {
+            return Err(Status::permission_denied(format!(
+                "{} is a dangling symlink",
+                path.display()
+            ))
+            .into())
+        }
+    };
+    if resolved != absolute && !resolved.starts_with(base_dir()) {
+        return Err(Status::permission_denied(format!(
+            "{} resolves outside {}",
+            path.display(),
+            base_dir().display()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn is_mount_point(path: &Path, meta: &fs::Metadata) -> bool {
+    if !meta.is_dir() {
+        return false;
+    }
+    match to_absolute_path(path).parent() {
+        Some(parent) => fs::metadata(parent)
+            .map(|parent_meta| parent_meta.dev() != meta.dev())
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+// The device id of a walk's root when it has to stay on one filesystem.
+fn root_device(root: &Path, stay_on_filesystem: bool) -> Option<u64> {
+    if stay_on_filesystem {
+        fs::metadata(root).ok().map(|m| m.dev())
+    } else {
+        None
+    }
+}
+
+fn same_device(root_dev: Option<u64>, dev: u64) -> bool {
+    root_dev.is_none_or(|root| root == dev)
+}
+
+fn on_root_device(path: &Path, root_dev: Option<u64>) -> bool {
+    match root_dev {
+        Some(_) => fs::metadata(path).is_ok_and(|m| same_device(root_dev, m.dev())),
+        None => true,
+    }
+}
+
 fn build_file_info(path: &Path, meta: &fs::Metadata) -> FileInfo {
     let name = path
         .file_name()
@@ -123,69 +423,484 @@ fn build_file_info(path: &Path, meta: &fs::Metadata) -> FileInfo {
         absolute_path,
         modified: format_modified_time(meta),
         size: meta.len(),
+        is_mount: is_mount_point(path, meta),
+        accessed_unix: meta.atime(),
+        changed_unix: meta.ctime(),
+        modified_unix: meta.mtime(),
+        modified_nanos: meta.mtime_nsec() as u32,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        // st_blocks is always in 512-byte units, whatever the filesystem block size.
+        blocks_size: meta.blocks() * 512,
+        // A symlink's len is its target string; devices and fifos report 0.
+        size_known: meta.is_file() || meta.is_dir(),
+        setuid: meta.mode() & 0o4000 != 0,
+        setgid: meta.mode() & 0o2000 != 0,
+        sticky: meta.mode() & 0o1000 != 0,
+    }
+}
+
+#[derive(Default, Clone)]
+struct ListFilter {
+    modified_after: u64,
+    modified_before: u64,
+    min_size: u64,
+    max_size: u64,
+    stay_on_filesystem: bool,
+    // Symlinked directories are listed but never expanded.
+    no_follow_symlinks: bool,
+    // None shows hidden entries.
+    hidden_patterns: Option<Vec<String>>,
+}
+
+impl ListFilter {
+    fn from_request(req: &GetPathRequest, no_follow_symlinks: bool) -> Self {
+        let hidden_patterns = match req.show_hidden {
+            Some(false) if req.hidden_patterns.is_empty() => Some(vec![".*".to_string()]),
+            Some(false) => Some(req.hidden_patterns.clone()),
+            _ => None,
+        };
+        Self {
+            modified_after: req.modified_after,
+            modified_before: req.modified_before,
+            min_size: req.min_size,
+            max_size: req.max_size,
+            stay_on_filesystem: req.stay_on_filesystem,
+            no_follow_symlinks,
+            hidden_patterns,
+        }
+    }
+
+    fn is_hidden(&self, name: &str) -> bool {
+        self.hidden_patterns
+            .as_ref()
+            .is_some_and(|patterns| patterns.iter().any(|pattern| glob_match(pattern, name)))
+    }
+
+    fn has_time_bounds(&self) -> bool {
+        self.modified_after != 0 || self.modified_before != 0
+    }
+
+    fn matches(&self, path: &Path, meta: &fs::Metadata, errors: &mut Vec<String>) -> bool {
+        if self.has_time_bounds() {
+            let modified = match meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            {
+                Some(duration) => duration.as_secs(),
+                None => {
+                    errors.push(format!("{}: modification time unavailable", path.display()));
+                    return false;
+                }
+            };
+            if self.modified_after != 0 && modified <= self.modified_after {
+                return false;
+            }
+            if self.modified_before != 0 && modified >= self.modified_before {
+                return false;
+            }
+        }
+        // Directories are exempt from the size range so the tree stays navigable.
+        if !meta.is_dir() {
+            let size = meta.len();
+            if self.min_size != 0 && size < self.min_size {
+                return false;
+            }
+            if self.max_size != 0 && size > self.max_size {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Minimal glob for entry names: `*` matches any run, `?` one character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name index it was tried at, for backtracking.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Clone, Copy)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+// Sorting and paging of the requested directory's listing.
+struct Page {
+    sort: Option<SortKey>,
+    descending: bool,
+    offset: usize,
+    limit: usize,
+    skip_sort_over: usize,
+}
+
+impl Page {
+    fn from_request(req: &GetPathRequest) -> Result<Option<Self>, BoxedStatus> {
+        let sort = match req.sort.as_str() {
+            "" => None,
+            "name" => Some(SortKey::Name),
+            "size" => Some(SortKey::Size),
+            "modified" => Some(SortKey::Modified),
+            other => {
+                return Err(Status::invalid_argument(format!("unknown sort: {}", other)).into())
+            }
+        };
+        if sort.is_none() && req.offset == 0 && req.limit == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            sort,
+            descending: req.descending,
+            offset: req.offset as usize,
+            limit: match req.limit {
+                0 => usize::MAX,
+                limit => limit as usize,
+            },
+            skip_sort_over: match req.skip_sort_over {
+                0 => DEFAULT_SKIP_SORT_OVER,
+                max => max as usize,
+            },
+        }))
+    }
+
+    // Sorts the whole listing, then cuts the window; only the window counts
+    // against the response budget.
+    fn apply(&self, mut entries: Vec<FileInfo>, state: &mut ListState) -> Vec<FileInfo> {
+        state.total_entries = entries.len() as u64;
+        if let Some(key) = self.sort {
+            if entries.len() > self.skip_sort_over {
+                state.sort_skipped = true;
+            } else {
+                entries.sort_by(|a, b| {
+                    let order = match key {
+                        SortKey::Name => std::cmp::Ordering::Equal,
+                        SortKey::Size => a.size.cmp(&b.size),
+                        SortKey::Modified => a.modified_unix.cmp(&b.modified_unix),
+                    }
+                    .then_with(|| a.name.cmp(&b.name));
+                    if self.descending {
+                        order.reverse()
+                    } else {
+                        order
+                    }
+                });
+            }
+        }
+
+        let mut window = Vec::new();
+        for info in entries.into_iter().skip(self.offset).take(self.limit) {
+            if !state.reserve(info.encoded_len()) {
+                break;
+            }
+            window.push(info);
+        }
+        window
+    }
+}
+
+struct ListState {
+    errors: Vec<String>,
+    remaining: usize,
+    truncated: bool,
+    deadline: Option<Instant>,
+    timed_out: bool,
+    entries_scanned: u64,
+    total_entries: u64,
+    sort_skipped: bool,
+}
+
+impl ListState {
+    fn new(max_response: usize, deadline: Option<Instant>) -> Self {
+        Self {
+            errors: Vec::new(),
+            remaining: max_response,
+            truncated: false,
+            deadline,
+            timed_out: false,
+            entries_scanned: 0,
+            total_entries: 0,
+            sort_skipped: false,
+        }
+    }
+
+    // True once the listing must stop growing, for either reason.
+    fn stopped(&mut self) -> bool {
+        if !self.timed_out {
+            self.timed_out = self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline);
+        }
+        self.truncated || self.timed_out
+    }
+
+    // Claims room for an encoded message in the response; once the budget is
+    // spent every later claim fails so the listing stops growing.
+    fn reserve(&mut self, message_len: usize) -> bool {
+        let needed = 1 + prost::length_delimiter_len(message_len) + message_len;
+        if self.truncated || needed > self.remaining {
+            self.truncated = true;
+            return false;
+        }
+        self.remaining -= needed;
+        true
     }
 }
 
-fn read_dir(path: &Path) -> Option<Vec<FileInfo>> {
+fn read_dir(
+    path: &Path,
+    filter: &ListFilter,
+    page: Option<&Page>,
+    state: &mut ListState,
+) -> Option<DirEntries> {
     let mut entries = Vec::new();
+    let mut hidden_count = 0;
+    if state.stopped() {
+        return None;
+    }
     let iter = fs::read_dir(path).ok()?;
+    let absolute_path = to_absolute_path(path).display().to_string();
+    // Room for the DirEntries header this listing ends up in: its absolute_path
+    // plus up to 6 bytes (tag and varint) for hidden_count.
+    if !state.reserve(absolute_path.len() + 6) {
+        return None;
+    }
 
     for e in iter.flatten() {
+        // Checked per entry: stat is the slow part on network filesystems.
+        if state.stopped() {
+            break;
+        }
+        state.entries_scanned += 1;
+        // Decided on the name alone, so hidden entries cost no stat.
+        if filter.is_hidden(&e.file_name().to_string_lossy()) {
+            hidden_count += 1;
+            continue;
+        }
         if let Ok(meta) = fs::symlink_metadata(e.path()) {
             let file_path = e.path();
-            entries.push(build_file_info(&file_path, &meta));
+            if !filter.matches(&file_path, &meta, &mut state.errors) {
+                continue;
+            }
+            let info = build_file_info(&file_path, &meta);
+            // A page is budgeted once the whole directory is read and cut.
+            if page.is_none() && !state.reserve(info.encoded_len()) {
+                break;
+            }
+            entries.push(info);
+        }
+    }
+    if let Some(page) = page {
+        entries = page.apply(entries, state);
+    }
+
+    Some(DirEntries {
+        absolute_path,
+        entries,
+        hidden_count,
+    })
+}
+
+// Collapses repeated separators and drops trailing ones (except for the root),
+// so `/foo/`, `/foo` and `//foo//` all produce the same listing.
+fn normalize_request_path(path: &str) -> String {
+    let mut normalized = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c == '/' && normalized.ends_with('/') {
+            continue;
+        }
+        normalized.push(c);
+    }
+    if normalized.len() > 1 && normalized.ends_with('/') {
+        normalized.pop();
+    }
+    normalized
+}
+
+// Shared by stat and stat_batch; a missing path is `exists: false`, not an error.
+fn stat_path(path: &Path) -> Result<StatResponse, BoxedStatus> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) => {
+            let (resolved_path, resolution_broken) = if meta.file_type().is_symlink() {
+                match fs::canonicalize(path) {
+                    Ok(resolved) => (resolved.display().to_string(), false),
+                    Err(_) => (String::new(), true),
+                }
+            } else {
+                (String::new(), false)
+            };
+            Ok(StatResponse {
+                exists: true,
+                info: Some(build_file_info(path, &meta)),
+                resolved_path,
+                resolution_broken,
+            })
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(StatResponse {
+            exists: false,
+            ..Default::default()
+        }),
+        Err(err) => Err(Status::internal(format!("stat failed: {}", err)).into()),
+    }
+}
+
+// `~` and `~/rest` use $HOME; `~user` is rejected rather than guessed at.
+// An unset variable is an error instead of silently expanding to nothing.
+fn expand_request_path(path: &str) -> Result<String, BoxedStatus> {
+    let home = || -> Result<String, BoxedStatus> {
+        std::env::var("HOME")
+            .map_err(|_| Status::invalid_argument("cannot expand ~: HOME is not set").into())
+    };
+    let (mut expanded, rest) = match path.strip_prefix('~') {
+        Some("") => (home()?, ""),
+        Some(rest) if rest.starts_with('/') => (home()?, rest),
+        Some(_) => {
+            return Err(Status::invalid_argument(format!(
+                "only ~ and ~/ can be expanded: {}",
+                path
+            ))
+            .into())
+        }
+        None => (String::new(), path),
+    };
+
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+        if braced && chars.next_if_eq(&'}').is_none() {
+            return Err(Status::invalid_argument(format!("unclosed ${{ in {}", path)).into());
         }
+        if name.is_empty() {
+            expanded.push('$');
+            if braced {
+                expanded.push_str("{}");
+            }
+            continue;
+        }
+        let value = std::env::var(&name).map_err(|_| {
+            Status::invalid_argument(format!("cannot expand ${}: variable is not set", name))
+        })?;
+        expanded.push_str(&value);
     }
+    Ok(expanded)
+}
 
-    Some(entries)
+// The single place request paths are checked: rejects empty paths, applies
+// the expansion and sandbox settings, and collapses repeated and trailing
+// separators. Sandbox checks run after expansion, so `~` and variables can't
+// be used to escape it.
+fn validate_request_path(path: &str, policy: PathPolicy) -> Result<PathBuf, BoxedStatus> {
+    if path.is_empty() {
+        return Err(Status::invalid_argument("path is required").into());
+    }
+    let expanded = if policy.expand {
+        expand_request_path(path)?
+    } else {
+        path.to_string()
+    };
+    let normalized = PathBuf::from(normalize_request_path(&expanded));
+    if policy.sandbox {
+        if normalized.is_absolute() {
+            return Err(Status::permission_denied(format!(
+                "absolute paths are not allowed: {}",
+                path
+            ))
+            .into());
+        }
+        if normalized
+            .components()
+            .any(|component| component == std::path::Component::ParentDir)
+        {
+            return Err(
+                Status::permission_denied(format!("paths may not contain ..: {}", path)).into(),
+            );
+        }
+    }
+    Ok(normalized)
 }
 
-fn build_entries_for_path(path: &str) -> Vec<DirEntries> {
+fn build_entries_for_path(
+    path: &str,
+    filter: &ListFilter,
+    page: Option<&Page>,
+    state: &mut ListState,
+) -> Vec<DirEntries> {
     let path_buf = PathBuf::from(path);
-    let request_path = to_absolute_path(&path_buf);
-    let is_dir_like = is_dir_or_symlink_dir(&path_buf);
+    let is_dir_like = is_dir_or_symlink_dir(&path_buf, filter.no_follow_symlinks);
+    let root_dev = root_device(&path_buf, filter.stay_on_filesystem);
     let mut data = Vec::new();
 
     if let Some(parent_path) = path_buf.parent() {
-        if let Some(entries) = read_dir(parent_path) {
-            let parent_abs_path = to_absolute_path(parent_path).display().to_string();
-            data.push(DirEntries {
-                absolute_path: parent_abs_path,
-                entries,
-            });
+        if let Some(listing) = read_dir(parent_path, filter, None, state) {
+            data.push(listing);
         }
     }
 
     if is_dir_like {
-        if let Some(entries) = read_dir(&path_buf) {
-            let current_abs_path = to_absolute_path(&request_path).display().to_string();
-            data.push(DirEntries {
-                absolute_path: current_abs_path.clone(),
-                entries: entries.clone(),
+        if let Some(listing) = read_dir(&path_buf, filter, page, state) {
+            // When paging, only directories on the page are expanded.
+            let on_page: Option<HashSet<String>> = page.map(|_| {
+                listing
+                    .entries
+                    .iter()
+                    .map(|info| info.name.clone())
+                    .collect()
             });
-
-            let normalized_original_path = path.trim_end_matches('/').to_string();
-            let normalized_original_abs = to_absolute_path(Path::new(&normalized_original_path))
-                .display()
-                .to_string();
-            if normalized_original_abs != current_abs_path {
-                data.push(DirEntries {
-                    absolute_path: normalized_original_abs,
-                    entries: entries.clone(),
-                });
-            }
+            data.push(listing);
 
             if let Ok(iter) = fs::read_dir(&path_buf) {
                 for entry in iter.flatten() {
+                    if state.stopped() {
+                        break;
+                    }
+                    // Hidden children aren't listed, so there is nothing to expand.
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if filter.is_hidden(&name) {
+                        continue;
+                    }
+                    if on_page.as_ref().is_some_and(|names| !names.contains(&name)) {
+                        continue;
+                    }
                     let child_path = entry.path();
-                    if is_dir_or_symlink_dir(&child_path) {
-                        if let Some(child_entries) = read_dir(&child_path) {
-                            let child_abs_path =
-                                to_absolute_path(&child_path).display().to_string();
-                            data.push(DirEntries {
-                                absolute_path: child_abs_path,
-                                entries: child_entries,
-                            });
+                    if is_dir_or_symlink_dir(&child_path, filter.no_follow_symlinks)
+                        && on_root_device(&child_path, root_dev)
+                    {
+                        if let Some(listing) = read_dir(&child_path, filter, None, state) {
+                            data.push(listing);
                         }
                     }
                 }
@@ -196,83 +911,1511 @@ fn build_entries_for_path(path: &str) -> Vec<DirEntries> {
     data
 }
 
-#[derive(Default)]
-struct LazySyncService;
+// By path rather than through an open handle: a copied file may be
+// unreadable (mode 000) and opening it just to set its times would fail.
+fn copy_times(path: &Path, meta: &fs::Metadata) -> std::io::Result<()> {
+    let accessed = filetime::FileTime::from_last_access_time(meta);
+    let modified = filetime::FileTime::from_last_modification_time(meta);
+    filetime::set_file_times(path, accessed, modified)
+}
 
-#[tonic::async_trait]
-impl LazySync for LazySyncService {
-    async fn health(
-        &self,
-        _request: Request<HealthRequest>,
-    ) -> Result<Response<HealthResponse>, Status> {
-        Ok(Response::new(HealthResponse {
-            status: "ok".to_string(),
-        }))
-    }
+struct CopyJob {
+    dereference: bool,
+    // Refuse to dereference links that lead outside the base dir (no-follow mode).
+    confined: bool,
+    // (dev, ino) of the directories on the current recursion path; with
+    // `dereference` a link back to one of them would recurse forever.
+    ancestors: Vec<(u64, u64)>,
+    bytes_copied: u64,
+    files_copied: u64,
+    copied: Vec<String>,
+    tx: Option<mpsc::Sender<Result<CopyProgress, Status>>>,
+}
 
-    async fn get_path(
-        &self,
-        request: Request<GetPathRequest>,
-    ) -> Result<Response<GetPathResponse>, Status> {
-        let req = request.into_inner();
-        if req.path.is_empty() {
-            return Err(Status::invalid_argument("path is required"));
+impl CopyJob {
+    fn new(dereference: bool, tx: Option<mpsc::Sender<Result<CopyProgress, Status>>>) -> Self {
+        Self {
+            dereference,
+            confined: false,
+            ancestors: Vec::new(),
+            bytes_copied: 0,
+            files_copied: 0,
+            copied: Vec::new(),
+            tx,
         }
+    }
 
-        let entries = build_entries_for_path(&req.path);
-        let reply = GetPathResponse {
-            path: req.path,
-            entries,
+    fn copy_entry(&mut self, from: &Path, to: &Path) -> std::io::Result<()> {
+        if self.confined {
+            ensure_within_root(from, true).map_err(|status| {
+                std::io::Error::new(std::io::ErrorKind::PermissionDenied, status.message())
+            })?;
+        }
+        let meta = if self.dereference {
+            fs::metadata(from)?
+        } else {
+            fs::symlink_metadata(from)?
         };
-        Ok(Response::new(reply))
-    }
 
-    async fn stat(
-        &self,
-        request: Request<StatRequest>,
-    ) -> Result<Response<StatResponse>, Status> {
-        let req = request.into_inner();
-        if req.path.is_empty() {
-            return Err(Status::invalid_argument("path is required"));
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(from)?;
+            std::os::unix::fs::symlink(&target, to)?;
+            self.record(to, 0)
+        } else if meta.is_dir() {
+            let key = (meta.dev(), meta.ino());
+            if self.ancestors.contains(&key) {
+                return Err(std::io::Error::other(format!(
+                    "cycle detected: {} is already being copied",
+                    from.display()
+                )));
+            }
+            fs::create_dir(to)?;
+            self.ancestors.push(key);
+            let children = self.copy_children(from, to);
+            self.ancestors.pop();
+            children?;
+            // Permissions and times go last so a read-only directory can still be filled.
+            fs::set_permissions(to, meta.permissions())?;
+            copy_times(to, &meta)
+        } else {
+            let bytes = fs::copy(from, to)?;
+            copy_times(to, &meta)?;
+            self.record(to, bytes)
         }
+    }
 
-        let path = PathBuf::from(&req.path);
-        match fs::symlink_metadata(&path) {
-            Ok(meta) => Ok(Response::new(StatResponse {
-                exists: true,
-                info: Some(build_file_info(&path, &meta)),
-            })),
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Response::new(
-                StatResponse {
-                    exists: false,
-                    info: None,
-                },
-            )),
-            Err(err) => Err(Status::internal(format!("stat failed: {}", err))),
+    fn copy_children(&mut self, from: &Path, to: &Path) -> std::io::Result<()> {
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            self.copy_entry(&entry.path(), &to.join(entry.file_name()))?;
         }
+        Ok(())
     }
 
-    type ReadFileStream = ReceiverStream<Result<ReadFileChunk, Status>>;
+    fn record(&mut self, path: &Path, bytes: u64) -> std::io::Result<()> {
+        self.bytes_copied += bytes;
+        self.files_copied += 1;
+        self.copied.push(path.display().to_string());
+        let Some(tx) = &self.tx else {
+            return Ok(());
+        };
+        let progress = CopyProgress {
+            current_path: path.display().to_string(),
+            bytes_copied: self.bytes_copied,
+            files_copied: self.files_copied,
+            ..Default::default()
+        };
+        tx.blocking_send(Ok(progress))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "client went away"))
+    }
 
-    async fn read_file(
-        &self,
-        request: Request<ReadFileRequest>,
-    ) -> Result<Response<Self::ReadFileStream>, Status> {
-        let req = request.into_inner();
-        if req.path.is_empty() {
-            return Err(Status::invalid_argument("path is required"));
+    fn finish(self, error: Option<String>) {
+        let Some(tx) = self.tx else {
+            return;
+        };
+        let progress = CopyProgress {
+            current_path: String::new(),
+            bytes_copied: self.bytes_copied,
+            files_copied: self.files_copied,
+            done: true,
+            error: error.unwrap_or_default(),
+            copied: self.copied,
+        };
+        let _ = tx.blocking_send(Ok(progress));
+    }
+}
+
+fn remove_path(path: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+fn move_path(from: &Path, to: &Path) -> std::io::Result<MoveResponse> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(MoveResponse {
+            cross_device: false,
+            files_moved: 1,
+        }),
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            move_across_devices(from, to)
         }
+        Err(err) => Err(err),
+    }
+}
 
-        let (tx, rx) = mpsc::channel(8);
-        let path = req.path.clone();
-        let offset = req.offset;
-        let length = req.length;
+// rename(2) can't cross filesystems: copy the whole tree first and only
+// remove the source once every entry made it, so a failure leaves it intact.
+fn move_across_devices(from: &Path, to: &Path) -> std::io::Result<MoveResponse> {
+    let mut job = CopyJob::new(false, None);
+    if let Err(err) = job.copy_entry(from, to) {
+        let _ = remove_path(to);
+        return Err(err);
+    }
+    remove_path(from)?;
+    Ok(MoveResponse {
+        cross_device: true,
+        files_moved: job.files_copied,
+    })
+}
 
-        tokio::spawn(async move {
+fn read_head(path: &Path, max_bytes: usize) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut head = Vec::with_capacity(max_bytes);
+    fs::File::open(path)?
+        .take(max_bytes as u64)
+        .read_to_end(&mut head)?;
+    Ok(head)
+}
+
+// NUL bytes mean binary. Otherwise valid UTF-8 (a character cut off at the
+// end of the sample is fine) is text, and anything else is text only if
+// control characters are rare, which keeps Latin-1 and friends as text.
+fn looks_binary(head: &[u8]) -> bool {
+    if head.contains(&0) {
+        return true;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => return false,
+        Err(err) if err.error_len().is_none() => return false,
+        Err(_) => {}
+    }
+    let is_control =
+        |b: u8| (b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b)) || b == 0x7f;
+    let control = head.iter().filter(|&&b| is_control(b)).count();
+    control * 10 > head.len()
+}
+
+fn sniff_head(head: &[u8]) -> SniffResponse {
+    if head.is_empty() {
+        return SniffResponse {
+            detected_kind: "empty".to_string(),
+            ..Default::default()
+        };
+    }
+    // UTF-16 text is full of NULs, so check its BOM before looking for binary.
+    if head.starts_with(&[0xff, 0xfe]) || head.starts_with(&[0xfe, 0xff]) {
+        return SniffResponse {
+            detected_kind: "utf16".to_string(),
+            ..Default::default()
+        };
+    }
+    if looks_binary(head) {
+        return SniffResponse {
+            detected_kind: "binary".to_string(),
+            is_binary: true,
+            ..Default::default()
+        };
+    }
+
+    let text = head.strip_prefix(&[0xef, 0xbb, 0xbf]).unwrap_or(head);
+    let line = text.split(|&b| b == b'\n').next().unwrap_or_default();
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let start = text.trim_ascii_start();
+    let lower = |n: usize| start[..start.len().min(n)].to_ascii_lowercase();
+    let kind = if text.starts_with(b"#!") {
+        "script"
+    } else if start.starts_with(b"{") || start.starts_with(b"[") {
+        "json"
+    } else if lower(5) == b"<?xml" {
+        "xml"
+    } else if lower(14) == b"<!doctype html" || lower(5) == b"<html" {
+        "html"
+    } else if start.starts_with(b"<") {
+        "xml"
+    } else {
+        "text"
+    };
+    SniffResponse {
+        first_line: String::from_utf8_lossy(line).into_owned(),
+        detected_kind: kind.to_string(),
+        is_binary: false,
+    }
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Deterministic fingerprint of a listing, as documented on
+// GetPathResponse.since_token.
+fn listing_fingerprint(listings: &[DirEntries]) -> String {
+    let mut dirs: Vec<&DirEntries> = listings.iter().collect();
+    dirs.sort_by(|a, b| a.absolute_path.cmp(&b.absolute_path));
+    let mut hasher = Sha256::new();
+    for dir in dirs {
+        hasher.update(dir.absolute_path.as_bytes());
+        hasher.update([0u8]);
+        let mut entries: Vec<&FileInfo> = dir.entries.iter().collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        for entry in entries {
+            hasher.update(entry.name.as_bytes());
+            hasher.update([0u8]);
+            hasher.update(entry.size.to_string().as_bytes());
+            hasher.update([0u8]);
+            hasher.update(entry.modified_unix.to_string().as_bytes());
+            hasher.update([0u8]);
+            hasher.update(entry.modified_nanos.to_string().as_bytes());
+            hasher.update([0u8]);
+        }
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+// Reads until `buffer` is full or the file ends, so both sides of a comparison
+// always line up on the same offsets.
+fn fill_block(file: &mut fs::File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+// Returns the offset of the first differing byte, or None when the contents match.
+fn first_difference(a: &Path, b: &Path) -> std::io::Result<Option<u64>> {
+    let mut file_a = fs::File::open(a)?;
+    let mut file_b = fs::File::open(b)?;
+    let mut block_a = vec![0u8; READ_CHUNK_SIZE];
+    let mut block_b = vec![0u8; READ_CHUNK_SIZE];
+    let mut offset = 0u64;
+    loop {
+        let n_a = fill_block(&mut file_a, &mut block_a)?;
+        let n_b = fill_block(&mut file_b, &mut block_b)?;
+        let common = n_a.min(n_b);
+        if let Some(pos) = block_a[..common]
+            .iter()
+            .zip(&block_b[..common])
+            .position(|(x, y)| x != y)
+        {
+            return Ok(Some(offset + pos as u64));
+        }
+        if n_a != n_b {
+            return Ok(Some(offset + common as u64));
+        }
+        if n_a == 0 {
+            return Ok(None);
+        }
+        offset += n_a as u64;
+    }
+}
+
+// renameat2(RENAME_EXCHANGE) swaps both names in one step, so readers
+// never see either path missing.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn swap_paths(a: &Path, b: &Path) -> Result<bool, BoxedStatus> {
+    use nix::{
+        errno::Errno,
+        fcntl::{renameat2, RenameFlags, AT_FDCWD},
+    };
+    match renameat2(AT_FDCWD, a, AT_FDCWD, b, RenameFlags::RENAME_EXCHANGE) {
+        Ok(()) => Ok(true),
+        Err(Errno::EXDEV) => {
+            Err(Status::failed_precondition("paths are on different filesystems").into())
+        }
+        Err(Errno::EINVAL | Errno::ENOSYS | Errno::EOPNOTSUPP) => {
+            Err(Status::unimplemented("atomic exchange is not supported for these paths").into())
+        }
+        Err(err) => Err(Status::internal(format!("swap failed: {}", err)).into()),
+    }
+}
+
+// No atomic exchange here: rename through a temporary sibling of `a`, undoing
+// earlier steps on failure. Refused across filesystems, where a half-done swap
+// could not be rolled back with renames.
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn swap_paths(a: &Path, b: &Path) -> Result<bool, BoxedStatus> {
+    let dev = |path: &Path| {
+        fs::symlink_metadata(path)
+            .map(|meta| meta.dev())
+            .map_err(|err| Status::internal(format!("stat failed: {}", err)))
+    };
+    if dev(a)? != dev(b)? {
+        return Err(
+            Status::unimplemented("no atomic swap across filesystems on this platform").into(),
+        );
+    }
+
+    let name = a.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = a.with_file_name(format!(".{}.swap-{}", name, std::process::id()));
+    let failed = |err: std::io::Error| Status::internal(format!("swap failed: {}", err));
+    fs::rename(a, &tmp).map_err(failed)?;
+    if let Err(err) = fs::rename(b, a) {
+        let _ = fs::rename(&tmp, a);
+        return Err(failed(err));
+    }
+    if let Err(err) = fs::rename(&tmp, b) {
+        let _ = fs::rename(a, b);
+        let _ = fs::rename(&tmp, a);
+        return Err(failed(err));
+    }
+    Ok(false)
+}
+
+// Filesystem types with nothing on disk behind them, hidden from ListMounts
+// unless asked for.
+const PSEUDO_FILESYSTEMS: &[&str] = &[
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "proc",
+    "pstore",
+    "ramfs",
+    "rpc_pipefs",
+    "securityfs",
+    "selinuxfs",
+    "sysfs",
+    "tmpfs",
+    "tracefs",
+];
+
+// /proc/mounts writes space, tab, newline and backslash as octal escapes.
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| bytes[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match octal {
+            Some(digits) => {
+                out.push(digits.iter().fold(0u8, |acc, d| (acc << 3) | (d - b'0')));
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// fsblkcnt_t and c_ulong are only u64 on 64-bit targets.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[allow(clippy::unnecessary_cast)]
+fn list_mounts(include_pseudo: bool) -> Result<ListMountsResponse, BoxedStatus> {
+    use nix::sys::statvfs::statvfs;
+    let content = fs::read_to_string("/proc/mounts")
+        .map_err(|err| Status::internal(format!("read /proc/mounts failed: {}", err)))?;
+
+    let mut mounts = Vec::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [device, mount_point, fs_type, options, ..] = fields[..] else {
+            continue;
+        };
+        if !include_pseudo && PSEUDO_FILESYSTEMS.contains(&fs_type) {
+            continue;
+        }
+        let mount_point = unescape_mount_field(mount_point);
+        // Mounts we aren't allowed to query are still listed, without sizes.
+        let (total_bytes, free_bytes) = match statvfs(mount_point.as_str()) {
+            Ok(stat) => {
+                let fragment = stat.fragment_size() as u64;
+                let total = stat.blocks() as u64 * fragment;
+                (total, stat.blocks_available() as u64 * fragment)
+            }
+            Err(_) => (0, 0),
+        };
+        mounts.push(MountInfo {
+            device: unescape_mount_field(device),
+            mount_point,
+            fs_type: fs_type.to_string(),
+            total_bytes,
+            free_bytes,
+            read_only: options.split(',').any(|option| option == "ro"),
+        });
+    }
+    Ok(ListMountsResponse { mounts })
+}
+
+// (effective uid, effective gid, supplementary groups)
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn server_identity() -> Option<(u32, u32, Vec<u32>)> {
+    use nix::unistd::{getegid, geteuid, getgroups};
+    let groups = getgroups().unwrap_or_default();
+    Some((
+        geteuid().as_raw(),
+        getegid().as_raw(),
+        groups.into_iter().map(|gid| gid.as_raw()).collect(),
+    ))
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn server_identity() -> Option<(u32, u32, Vec<u32>)> {
+    None
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn list_mounts(_include_pseudo: bool) -> Result<ListMountsResponse, BoxedStatus> {
+    Err(Status::unimplemented("listing mounts is not supported on this platform").into())
+}
+
+// Reserve real blocks for the whole file, not just its length.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn allocate_file(file: &fs::File, size: u64) -> Result<(), BoxedStatus> {
+    use nix::{
+        errno::Errno,
+        fcntl::{fallocate, FallocateFlags},
+    };
+    let len = i64::try_from(size)
+        .map_err(|_| Status::invalid_argument(format!("size too large: {}", size)))?;
+    match fallocate(file, FallocateFlags::empty(), 0, len) {
+        Ok(()) => Ok(()),
+        Err(Errno::EOPNOTSUPP) => {
+            Err(Status::unimplemented("preallocation is not supported on this filesystem").into())
+        }
+        Err(Errno::ENOSPC) => Err(Status::resource_exhausted("no space left on device").into()),
+        Err(err) => Err(Status::internal(format!("fallocate failed: {}", err)).into()),
+    }
+}
+
+// Without fallocate, writing zeros is the only portable way to get real blocks.
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn allocate_file(file: &fs::File, size: u64) -> Result<(), BoxedStatus> {
+    use std::io::Write;
+    let zeros = vec![0u8; READ_CHUNK_SIZE];
+    let mut writer = std::io::BufWriter::new(file);
+    let mut remaining = size;
+    while remaining > 0 {
+        let n = remaining.min(zeros.len() as u64) as usize;
+        writer
+            .write_all(&zeros[..n])
+            .map_err(|err| Status::internal(format!("write failed: {}", err)))?;
+        remaining -= n as u64;
+    }
+    writer
+        .flush()
+        .map_err(|err| Status::internal(format!("write failed: {}", err)))
+}
+
+// Exclusive opens use O_EXCL, so two racing writers can't both win.
+async fn open_for_write(
+    path: &Path,
+    exclusive: bool,
+    truncate: bool,
+) -> Result<tokio::fs::File, BoxedStatus> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true);
+    if exclusive {
+        options.create_new(true);
+    } else {
+        options.create(true).truncate(truncate);
+    }
+    let file = options.open(path).await.map_err(|err| match err.kind() {
+        std::io::ErrorKind::AlreadyExists => {
+            Status::already_exists(format!("already exists: {}", path.display()))
+        }
+        _ => Status::internal(format!("open file failed: {}", err)),
+    })?;
+    Ok(file)
+}
+
+// Files are synced as the walk reaches them, directories afterwards, deepest
+// first. Entries that disappear mid-walk are skipped, and with `root_dev` set
+// directories on other devices are synced but not descended into.
+fn sync_tree(
+    root: &Path,
+    recursive: bool,
+    root_dev: Option<u64>,
+) -> Result<SyncPathResponse, BoxedStatus> {
+    let sync = |path: &Path| -> Result<bool, BoxedStatus> {
+        match fs::File::open(path).and_then(|file| file.sync_all()) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => {
+                Err(Status::internal(format!("sync failed for {}: {}", path.display(), err)).into())
+            }
+        }
+    };
+
+    let mut synced = SyncPathResponse::default();
+    // Creating `root` added an entry to its parent.
+    let mut dirs: Vec<PathBuf> = root
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .into_iter()
+        .collect();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && path != root => continue,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Status::not_found(format!("not found: {}", path.display())).into());
+            }
+            Err(err) => return Err(Status::internal(format!("stat failed: {}", err)).into()),
+        };
+        if meta.is_file() {
+            synced.files_synced += u64::from(sync(&path)?);
+        } else if meta.is_dir() {
+            if recursive || path == root {
+                dirs.push(path.clone());
+            }
+            if recursive && same_device(root_dev, meta.dev()) {
+                let entries = match fs::read_dir(&path) {
+                    Ok(entries) => entries,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(err) => {
+                        return Err(Status::internal(format!("read_dir failed: {}", err)).into())
+                    }
+                };
+                pending.extend(entries.filter_map(|entry| Some(entry.ok()?.path())));
+            }
+        }
+    }
+
+    for dir in dirs.iter().rev() {
+        synced.dirs_synced += u64::from(sync(dir)?);
+    }
+    Ok(synced)
+}
+
+// One segment of a search pattern, used only to prune the walk; the full
+// pattern still decides what matches.
+enum SearchSegment {
+    AnyDepth,
+    Name(GlobMatcher),
+}
+
+fn glob_matcher(pattern: &str) -> Result<GlobMatcher, globset::Error> {
+    Ok(GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher())
+}
+
+fn search_segments(pattern: &str) -> Vec<SearchSegment> {
+    pattern
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| match segment {
+            "**" => SearchSegment::AnyDepth,
+            // Alternatives may contain a separator, so they can't be split up.
+            _ if segment.contains('{') => SearchSegment::AnyDepth,
+            _ => glob_matcher(segment).map_or(SearchSegment::AnyDepth, SearchSegment::Name),
+        })
+        .collect()
+}
+
+// Whether anything below the directory at `dir` (relative to the search root)
+// can still match.
+fn may_contain_match(segments: &[SearchSegment], dir: &[&str]) -> bool {
+    match (segments.split_first(), dir.split_first()) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some((SearchSegment::AnyDepth, _)), Some(_)) => true,
+        (Some((SearchSegment::Name(matcher), rest)), Some((name, dir))) => {
+            matcher.is_match(name) && may_contain_match(rest, dir)
+        }
+    }
+}
+
+// With `root_dev` set, directories on other devices can match but are not
+// descended into.
+fn search_tree(
+    root: &Path,
+    pattern: &str,
+    max_results: usize,
+    root_dev: Option<u64>,
+) -> Result<SearchResponse, BoxedStatus> {
+    let matcher = glob_matcher(pattern)
+        .map_err(|err| Status::invalid_argument(format!("invalid pattern: {}", err)))?;
+    let segments = search_segments(pattern);
+
+    let mut found = SearchResponse::default();
+    let mut pending = vec![String::new()];
+    while let Some(relative) = pending.pop() {
+        let dir = root.join(&relative);
+        let mut entries: Vec<(String, fs::Metadata)> = match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| {
+                    let entry = entry.ok()?;
+                    let meta = entry.metadata().ok()?;
+                    Some((entry.file_name().to_string_lossy().into_owned(), meta))
+                })
+                .collect(),
+            Err(err) if relative.is_empty() => {
+                return Err(Status::internal(format!("read_dir failed: {}", err)).into());
+            }
+            // Unreadable or vanished subdirectories are skipped.
+            Err(_) => continue,
+        };
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut subdirs = Vec::new();
+        for (name, meta) in entries {
+            let path = if relative.is_empty() {
+                name
+            } else {
+                format!("{}/{}", relative, name)
+            };
+            if matcher.is_match(&path) {
+                if found.matches.len() == max_results {
+                    found.truncated = true;
+                    return Ok(found);
+                }
+                let info = build_file_info(&root.join(&path), &meta);
+                found.matches.push(info);
+            }
+            if meta.is_dir()
+                && same_device(root_dev, meta.dev())
+                && may_contain_match(&segments, &path.split('/').collect::<Vec<_>>())
+            {
+                subdirs.push(path);
+            }
+        }
+        pending.extend(subdirs.into_iter().rev());
+    }
+    Ok(found)
+}
+
+fn create_file(path: &Path, size: u64, sparse: bool, overwrite: bool) -> Result<(), BoxedStatus> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let file = options.open(path).map_err(|err| match err.kind() {
+        std::io::ErrorKind::AlreadyExists => {
+            Status::already_exists(format!("already exists: {}", path.display()))
+        }
+        std::io::ErrorKind::NotFound => {
+            Status::not_found(format!("parent directory not found: {}", path.display()))
+        }
+        std::io::ErrorKind::PermissionDenied => {
+            Status::permission_denied(format!("create failed: {}", err))
+        }
+        _ => Status::internal(format!("create failed: {}", err)),
+    })?;
+    if size == 0 {
+        return Ok(());
+    }
+
+    let sized = if sparse {
+        file.set_len(size)
+            .map_err(|err| Status::internal(format!("set_len failed: {}", err)).into())
+    } else {
+        allocate_file(&file, size)
+    };
+    // Don't leave a half-sized file behind for a client to resume into.
+    if sized.is_err() {
+        drop(file);
+        let _ = fs::remove_file(path);
+    }
+    sized
+}
+
+struct StreamGuard(Arc<AtomicU64>);
+
+impl StreamGuard {
+    fn new(counter: &Arc<AtomicU64>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(Arc::clone(counter))
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Streams `length` bytes (0 = to EOF) from the file's current position,
+// which the caller has already seeked to `offset`.
+async fn stream_file(file: &mut tokio::fs::File, sender: &ReadSender, offset: u64, length: u64) {
+    let mut remaining = if length == 0 { None } else { Some(length) };
+    let mut current_offset = offset;
+    let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+
+    loop {
+        let read_len = match remaining {
+            Some(0) => {
+                sender.send(Ok(eof_chunk(current_offset))).await;
+                break;
+            }
+            Some(left) => std::cmp::min(left as usize, buffer.len()),
+            None => buffer.len(),
+        };
+
+        let bytes_read = match file.read(&mut buffer[..read_len]).await {
+            Ok(0) => {
+                sender.send(Ok(eof_chunk(current_offset))).await;
+                break;
+            }
+            Ok(n) => n,
+            Err(err) => {
+                sender
+                    .send(Err(Status::internal(format!("read failed: {}", err))))
+                    .await;
+                break;
+            }
+        };
+
+        let chunk = ReadFileChunk {
+            data: buffer[..bytes_read].to_vec(),
+            offset: current_offset,
+            eof: false,
+        };
+        if !sender.send(Ok(chunk)).await {
+            break;
+        }
+
+        current_offset += bytes_read as u64;
+        if let Some(left) = remaining.as_mut() {
+            *left -= bytes_read as u64;
+        }
+    }
+}
+
+// Seeks to the start of the first whole line within the last `max_bytes`
+// and returns that offset. A window without any line break starts at
+// `len - max_bytes`, mid-line, rather than returning nothing.
+async fn tail_start(file: &mut tokio::fs::File, max_bytes: u64) -> std::io::Result<u64> {
+    let len = file.metadata().await?.len();
+    if len <= max_bytes {
+        return Ok(0);
+    }
+
+    // Scan from the byte just before the window: if that is a newline the
+    // window already starts on a line.
+    let window_start = len - max_bytes;
+    let mut scanned = window_start - 1;
+    file.seek(std::io::SeekFrom::Start(scanned)).await?;
+    let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+    let start = loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break window_start;
+        }
+        if let Some(pos) = buffer[..n].iter().position(|&b| b == b'\n') {
+            break scanned + pos as u64 + 1;
+        }
+        scanned += n as u64;
+    };
+    // Nothing after the last line break: that line is the tail.
+    let start = if start == len { window_start } else { start };
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    Ok(start)
+}
+
+// Widens [offset, offset + length) to whole lines for line_aligned reads:
+// the start moves back to just after the previous newline and the end
+// forward to just past the next one, each by at most LINE_ALIGN_SLACK. An end
+// that finds no newline within the slack stays put (or stops at end of file),
+// as does a start, unless the slack reaches the start of the file. Returns
+// the new (offset, length); length 0 still means "to the end".
+async fn line_aligned_range(
+    file: &mut tokio::fs::File,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<(u64, u64)> {
+    let len = file.metadata().await?.len();
+    let offset = offset.min(len);
+
+    let start = if offset == 0 {
+        0
+    } else {
+        let window_start = offset.saturating_sub(LINE_ALIGN_SLACK);
+        let mut window = vec![0u8; (offset - window_start) as usize];
+        file.seek(std::io::SeekFrom::Start(window_start)).await?;
+        file.read_exact(&mut window).await?;
+        match window.iter().rposition(|&b| b == b'\n') {
+            Some(pos) => window_start + pos as u64 + 1,
+            None if window_start == 0 => 0,
+            None => offset,
+        }
+    };
+
+    if length == 0 {
+        return Ok((start, 0));
+    }
+    let end = offset.saturating_add(length).min(len);
+    let end = if end == len {
+        len
+    } else {
+        // Check from the last byte in range: if it is a newline the range
+        // already ends on a line.
+        let mut window = vec![0u8; LINE_ALIGN_SLACK.min(len - end + 1) as usize];
+        file.seek(std::io::SeekFrom::Start(end - 1)).await?;
+        file.read_exact(&mut window).await?;
+        match window.iter().position(|&b| b == b'\n') {
+            Some(pos) => end + pos as u64,
+            None if end - 1 + window.len() as u64 == len => len,
+            None => end,
+        }
+    };
+    Ok((start, end - start))
+}
+
+fn eof_chunk(offset: u64) -> ReadFileChunk {
+    ReadFileChunk {
+        data: Vec::new(),
+        offset,
+        eof: true,
+    }
+}
+
+// Sends read_file chunks, giving up on clients that stop pulling or outlive
+// the deadline so the task and its file handle don't linger.
+struct ReadSender<T = ReadFileChunk> {
+    tx: mpsc::Sender<Result<T, Status>>,
+    path: String,
+    stall_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl<T> ReadSender<T> {
+    // Returns false once the stream should stop.
+    async fn send(&self, item: Result<T, Status>) -> bool {
+        let left = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let wait = match (self.stall_timeout, left) {
+            (Some(stall), Some(left)) => Some(stall.min(left)),
+            (stall, left) => stall.or(left),
+        };
+        let result = match wait {
+            Some(wait) => self.tx.send_timeout(item, wait).await,
+            None => self
+                .tx
+                .send(item)
+                .await
+                .map_err(|err| SendTimeoutError::Closed(err.0)),
+        };
+        match result {
+            Ok(()) => true,
+            Err(SendTimeoutError::Closed(_)) => false,
+            Err(SendTimeoutError::Timeout(_)) => {
+                let expired = self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline);
+                let reason = if expired {
+                    "exceeded the maximum read duration"
+                } else {
+                    "client stopped reading"
+                };
+                eprintln!("read_file {}: aborted, {}", self.path, reason);
+                false
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+// Sniffs the archive format from the first block instead of trusting the extension.
+fn archive_kind(path: &Path) -> Result<ArchiveKind, BoxedStatus> {
+    let mut header = Vec::with_capacity(512);
+    fs::File::open(path)
+        .and_then(|file| file.take(512).read_to_end(&mut header))
+        .map_err(|err| Status::not_found(format!("open archive failed: {}", err)))?;
+    if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+        Ok(ArchiveKind::Zip)
+    } else if header.starts_with(&[0x1f, 0x8b]) {
+        Ok(ArchiveKind::TarGz)
+    } else if header.get(257..262) == Some(b"ustar") {
+        Ok(ArchiveKind::Tar)
+    } else {
+        Err(
+            Status::invalid_argument(format!("not a zip or tar archive: {}", path.display()))
+                .into(),
+        )
+    }
+}
+
+fn invalid_archive(err: impl std::fmt::Display) -> Status {
+    Status::invalid_argument(format!("invalid archive: {}", err))
+}
+
+// Archive member names may start with "./" or "/"; directories end with "/".
+fn archive_member_path(raw: &str) -> String {
+    raw.trim_start_matches("./").trim_matches('/').to_string()
+}
+
+// Every member of an archive, in archive order, with paths cleaned up.
+fn scan_archive(path: &Path) -> Result<Vec<ArchiveEntry>, BoxedStatus> {
+    let file = fs::File::open(path)
+        .map_err(|err| Status::not_found(format!("open archive failed: {}", err)))?;
+    match archive_kind(path)? {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(file).map_err(invalid_archive)?;
+            let mut members = Vec::with_capacity(archive.len());
+            for index in 0..archive.len() {
+                let member = archive.by_index_raw(index).map_err(invalid_archive)?;
+                let modified = member
+                    .last_modified()
+                    .map(|t| {
+                        format!(
+                            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                            t.year(),
+                            t.month(),
+                            t.day(),
+                            t.hour(),
+                            t.minute(),
+                            t.second()
+                        )
+                    })
+                    .unwrap_or_else(|| "N/A".to_string());
+                members.push(archive_entry(
+                    member.name(),
+                    member.size(),
+                    member.is_dir(),
+                    modified,
+                ));
+            }
+            Ok(members)
+        }
+        ArchiveKind::Tar => scan_tar(tar::Archive::new(file)),
+        ArchiveKind::TarGz => scan_tar(tar::Archive::new(flate2::read::GzDecoder::new(file))),
+    }
+}
+
+fn scan_tar<R: std::io::Read>(
+    mut archive: tar::Archive<R>,
+) -> Result<Vec<ArchiveEntry>, BoxedStatus> {
+    let mut members = Vec::new();
+    for member in archive.entries().map_err(invalid_archive)? {
+        let member = member.map_err(invalid_archive)?;
+        let header = member.header();
+        let name = member.path().map_err(invalid_archive)?;
+        members.push(archive_entry(
+            &name.to_string_lossy(),
+            header.size().unwrap_or(0),
+            header.entry_type().is_dir(),
+            header
+                .mtime()
+                .map(format_unix_time)
+                .unwrap_or_else(|_| "N/A".to_string()),
+        ));
+    }
+    Ok(members)
+}
+
+fn archive_entry(raw_path: &str, size: u64, is_dir: bool, modified: String) -> ArchiveEntry {
+    let path = archive_member_path(raw_path);
+    ArchiveEntry {
+        name: path.rsplit('/').next().unwrap_or_default().to_string(),
+        path,
+        size: if is_dir { 0 } else { size },
+        is_dir,
+        modified,
+    }
+}
+
+// Direct children of `inner` (already cleaned), adding directories that are only
+// implied by deeper member paths. None when `inner` isn't a directory in the archive.
+fn archive_children(members: Vec<ArchiveEntry>, inner: &str) -> Option<Vec<ArchiveEntry>> {
+    let mut children: std::collections::BTreeMap<String, ArchiveEntry> = Default::default();
+    let mut inner_exists = inner.is_empty();
+    for member in members {
+        let rest = if inner.is_empty() {
+            member.path.as_str()
+        } else if member.path == inner {
+            inner_exists |= member.is_dir;
+            continue;
+        } else {
+            match member
+                .path
+                .strip_prefix(inner)
+                .and_then(|rest| rest.strip_prefix('/'))
+            {
+                Some(rest) => rest,
+                None => continue,
+            }
+        };
+        inner_exists = true;
+        match rest.split_once('/') {
+            Some((dir, _)) => {
+                let path = if inner.is_empty() {
+                    dir.to_string()
+                } else {
+                    format!("{}/{}", inner, dir)
+                };
+                children
+                    .entry(dir.to_string())
+                    .or_insert_with(|| ArchiveEntry {
+                        name: dir.to_string(),
+                        path,
+                        size: 0,
+                        is_dir: true,
+                        modified: "N/A".to_string(),
+                    });
+            }
+            None if !rest.is_empty() => {
+                children.insert(rest.to_string(), member);
+            }
+            None => {}
+        }
+    }
+    inner_exists.then(|| children.into_values().collect())
+}
+
+// Streams one file member of an archive, decompressing as it goes.
+fn stream_archive_entry(
+    path: &Path,
+    entry_path: &str,
+    sender: &ReadSender,
+    runtime: &tokio::runtime::Handle,
+) -> Result<(), BoxedStatus> {
+    let file = fs::File::open(path)
+        .map_err(|err| Status::not_found(format!("open archive failed: {}", err)))?;
+    let kind = archive_kind(path)?;
+    let is_wanted = |name: &str, is_dir: bool| !is_dir && archive_member_path(name) == entry_path;
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(file).map_err(invalid_archive)?;
+            for index in 0..archive.len() {
+                let member = archive.by_index_raw(index).map_err(invalid_archive)?;
+                if !is_wanted(member.name(), member.is_dir()) {
+                    continue;
+                }
+                drop(member);
+                let member = archive.by_index(index).map_err(invalid_archive)?;
+                return send_reader(member, sender, runtime);
+            }
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let reader: Box<dyn Read> = match kind {
+                ArchiveKind::TarGz => Box::new(flate2::read::GzDecoder::new(file)),
+                _ => Box::new(file),
+            };
+            let mut archive = tar::Archive::new(reader);
+            for member in archive.entries().map_err(invalid_archive)? {
+                let member = member.map_err(invalid_archive)?;
+                let name = member.path().map_err(invalid_archive)?;
+                if is_wanted(
+                    &name.to_string_lossy(),
+                    member.header().entry_type().is_dir(),
+                ) {
+                    return send_reader(member, sender, runtime);
+                }
+            }
+        }
+    }
+    Err(Status::not_found(format!("no entry {} in archive", entry_path)).into())
+}
+
+fn send_reader(
+    mut reader: impl Read,
+    sender: &ReadSender,
+    runtime: &tokio::runtime::Handle,
+) -> Result<(), BoxedStatus> {
+    let mut offset = 0u64;
+    loop {
+        // Decoders hand out small reads; fill whole chunks to keep messages few.
+        let mut data = Vec::with_capacity(READ_CHUNK_SIZE);
+        (&mut reader)
+            .take(READ_CHUNK_SIZE as u64)
+            .read_to_end(&mut data)
+            .map_err(|err| Status::internal(format!("read failed: {}", err)))?;
+        if data.is_empty() {
+            runtime.block_on(sender.send(Ok(eof_chunk(offset))));
+            return Ok(());
+        }
+        let len = data.len() as u64;
+        let chunk = ReadFileChunk {
+            data,
+            offset,
+            eof: false,
+        };
+        if !runtime.block_on(sender.send(Ok(chunk))) {
+            return Ok(());
+        }
+        offset += len;
+    }
+}
+
+// Streams the head of one file for read_files. Problems with this file become
+// an error marker so the remaining files still go out; returns false once the
+// stream itself is gone.
+async fn send_file_head(
+    sender: &ReadSender<ReadFilesChunk>,
+    file_index: u32,
+    path: &str,
+    policy: PathPolicy,
+    no_follow: bool,
+    max_bytes: u64,
+) -> bool {
+    let marker = |offset: u64, error: String| ReadFilesChunk {
+        file_index,
+        offset,
+        eof: true,
+        error,
+        ..Default::default()
+    };
+    let checked = validate_request_path(path, policy).and_then(|path| {
+        ensure_within_root(&path, no_follow)?;
+        ensure_not_directory(&path)?;
+        Ok(path)
+    });
+    let opened = match checked {
+        Ok(path) => tokio::fs::File::open(path)
+            .await
+            .map_err(|err| format!("open file failed: {}", err)),
+        Err(status) => Err(status.message().to_string()),
+    };
+    let mut file = match opened {
+        Ok(file) => file,
+        Err(error) => return sender.send(Ok(marker(0, error))).await,
+    };
+
+    let mut offset = 0u64;
+    let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+    loop {
+        let read_len = match max_bytes {
+            0 => buffer.len(),
+            max => (max - offset).min(buffer.len() as u64) as usize,
+        };
+        if read_len == 0 {
+            return sender.send(Ok(marker(offset, String::new()))).await;
+        }
+        let bytes_read = match file.read(&mut buffer[..read_len]).await {
+            Ok(0) => return sender.send(Ok(marker(offset, String::new()))).await,
+            Ok(n) => n,
+            Err(err) => {
+                return sender
+                    .send(Ok(marker(offset, format!("read failed: {}", err))))
+                    .await
+            }
+        };
+        let chunk = ReadFilesChunk {
+            file_index,
+            data: buffer[..bytes_read].to_vec(),
+            offset,
+            ..Default::default()
+        };
+        if !sender.send(Ok(chunk)).await {
+            return false;
+        }
+        offset += bytes_read as u64;
+    }
+}
+
+// Cuts the archive byte stream into chunks as the builder writes it, so memory
+// stays at the channel depth no matter how large the tree is. A closed or
+// stalled stream surfaces as a write error, which stops the builder.
+struct ChunkWriter<'a> {
+    sender: &'a ReadSender,
+    runtime: &'a tokio::runtime::Handle,
+    buffer: Vec<u8>,
+    offset: u64,
+}
+
+impl<'a> ChunkWriter<'a> {
+    fn new(sender: &'a ReadSender, runtime: &'a tokio::runtime::Handle) -> Self {
+        Self {
+            sender,
+            runtime,
+            buffer: Vec::with_capacity(READ_CHUNK_SIZE),
+            offset: 0,
+        }
+    }
+
+    fn send_buffer(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::replace(&mut self.buffer, Vec::with_capacity(READ_CHUNK_SIZE));
+        let len = data.len() as u64;
+        let chunk = ReadFileChunk {
+            data,
+            offset: self.offset,
+            eof: false,
+        };
+        if !self.runtime.block_on(self.sender.send(Ok(chunk))) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "stream closed",
+            ));
+        }
+        self.offset += len;
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        self.send_buffer()?;
+        self.runtime
+            .block_on(self.sender.send(Ok(eof_chunk(self.offset))));
+        Ok(())
+    }
+}
+
+impl std::io::Write for ChunkWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let take = buf.len().min(READ_CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        if self.buffer.len() == READ_CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+fn stream_path_archive(
+    path: &Path,
+    gzip: bool,
+    sender: &ReadSender,
+    runtime: &tokio::runtime::Handle,
+) -> Result<(), BoxedStatus> {
+    let name = path
+        .file_name()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let append = |writer: &mut dyn std::io::Write| {
+        let mut builder = tar::Builder::new(writer);
+        // Symlinks are stored as links; following them could loop or leave the tree.
+        builder.follow_symlinks(false);
+        if path.is_dir() {
+            builder.append_dir_all(&name, path)?;
+        } else {
+            builder.append_path_with_name(path, &name)?;
+        }
+        builder.finish()
+    };
+
+    let mut writer = ChunkWriter::new(sender, runtime);
+    let result = if gzip {
+        let mut encoder =
+            flate2::write::GzEncoder::new(&mut writer, flate2::Compression::default());
+        append(&mut encoder).and_then(|()| encoder.finish().map(drop))
+    } else {
+        append(&mut writer)
+    };
+    match result.and_then(|()| writer.finish()) {
+        Ok(()) => Ok(()),
+        // The client went away; ReadSender already logged why.
+        Err(err) if err.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        Err(err) => Err(Status::internal(format!("archive failed: {}", err)).into()),
+    }
+}
+
+// `path` was created, written, removed or replaced: its directory's listing
+// changed, and so did its own if it is (or was) a directory.
+fn notify_changed(invalidations: &broadcast::Sender<PathBuf>, path: &Path) {
+    let path = to_absolute_path(path);
+    if let Some(parent) = path.parent() {
+        // Fails only when nobody is subscribed.
+        let _ = invalidations.send(parent.to_path_buf());
+    }
+    let _ = invalidations.send(path);
+}
+
+// Changes buffered per watch_invalidations subscriber before it is told it lagged.
+const INVALIDATION_BACKLOG: usize = 1024;
+
+struct LazySyncService {
+    config: ServerConfig,
+    started_at: Instant,
+    active_streams: Arc<AtomicU64>,
+    // Absolute paths whose listing a mutating RPC just changed.
+    invalidations: broadcast::Sender<PathBuf>,
+}
+
+impl LazySyncService {
+    fn new(config: ServerConfig) -> Self {
+        Self {
+            config,
+            started_at: Instant::now(),
+            active_streams: Arc::new(AtomicU64::new(0)),
+            invalidations: broadcast::channel(INVALIDATION_BACKLOG).0,
+        }
+    }
+
+    fn notify_changed(&self, path: &Path) {
+        notify_changed(&self.invalidations, path);
+    }
+
+    fn ensure_writable(&self) -> Result<(), BoxedStatus> {
+        if self.config.read_only {
+            Err(Status::permission_denied("server is in read-only mode").into())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_buffer(&self, requested: u32) -> usize {
+        if requested == 0 {
+            self.config.read_buffer
+        } else {
+            (requested as usize).min(MAX_READ_BUFFER)
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl LazySync for LazySyncService {
+    async fn health(
+        &self,
+        _request: Request<HealthRequest>,
+    ) -> Result<Response<HealthResponse>, Status> {
+        let identity = server_identity();
+        let (uid, gid, groups) = identity.clone().unwrap_or_default();
+        Ok(Response::new(HealthResponse {
+            status: "ok".to_string(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            active_streams: self.active_streams.load(Ordering::Relaxed),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            read_only: self.config.read_only,
+            no_follow_symlinks: self.config.no_follow_symlinks,
+            identity_known: identity.is_some(),
+            uid,
+            gid,
+            groups,
+        }))
+    }
+
+    async fn get_path(
+        &self,
+        request: Request<GetPathRequest>,
+    ) -> Result<Response<GetPathResponse>, Status> {
+        let started = Instant::now();
+        let trace_id = request_trace_id(request.metadata(), &request.get_ref().trace_id);
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?
+            .to_string_lossy()
+            .into_owned();
+        let filter = ListFilter::from_request(&req, self.config.no_follow_symlinks);
+        let page = Page::from_request(&req)?;
+        let deadline =
+            (req.deadline_ms > 0).then(|| Instant::now() + Duration::from_millis(req.deadline_ms));
+        let mut state = ListState::new(self.config.max_response, deadline);
+        let entries = build_entries_for_path(&path, &filter, page.as_ref(), &mut state);
+        let since_token = if state.truncated || state.timed_out {
+            String::new()
+        } else {
+            listing_fingerprint(&entries)
+        };
+        let unchanged = !since_token.is_empty() && since_token == req.since_token;
+        println!(
+            "[{}] get_path {}: {} dirs, {} entries scanned in {} ms{}",
+            trace_id,
+            path,
+            entries.len(),
+            state.entries_scanned,
+            started.elapsed().as_millis(),
+            if unchanged { " (unchanged)" } else { "" }
+        );
+        let reply = GetPathResponse {
+            path,
+            entries: if unchanged { Vec::new() } else { entries },
+            errors: state.errors,
+            truncated: state.truncated,
+            timed_out: state.timed_out,
+            entries_scanned: state.entries_scanned,
+            total_entries: state.total_entries,
+            sort_skipped: state.sort_skipped,
+            since_token,
+            unchanged,
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn stat(
+        &self,
+        request: Request<StatRequest>,
+    ) -> Result<Response<StatResponse>, Status> {
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        Ok(Response::new(stat_path(&path)?))
+    }
+
+    async fn stat_batch(
+        &self,
+        request: Request<StatBatchRequest>,
+    ) -> Result<Response<StatBatchResponse>, Status> {
+        let req = request.into_inner();
+        let results = req
+            .paths
+            .iter()
+            .map(|path| {
+                let stat = validate_request_path(path, self.config.paths)
+                    .and_then(|path| stat_path(&path));
+                match stat {
+                    Ok(stat) => StatBatchResult {
+                        stat: Some(stat),
+                        error: String::new(),
+                    },
+                    Err(status) => StatBatchResult {
+                        stat: None,
+                        error: status.message().to_string(),
+                    },
+                }
+            })
+            .collect();
+        Ok(Response::new(StatBatchResponse { results }))
+    }
+
+    type ReadFileStream = ReceiverStream<Result<ReadFileChunk, Status>>;
+
+    async fn read_file(
+        &self,
+        request: Request<ReadFileRequest>,
+    ) -> Result<Response<Self::ReadFileStream>, Status> {
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+        ensure_not_directory(&path)?;
+
+        let (tx, rx) = mpsc::channel(self.read_buffer(req.buffer_depth));
+        let (offset, length, line_aligned) = (req.offset, req.length, req.line_aligned);
+        let guard = StreamGuard::new(&self.active_streams);
+        let sender = ReadSender {
+            tx,
+            path: path.display().to_string(),
+            stall_timeout: self.config.read_stall_timeout,
+            deadline: self
+                .config
+                .read_max_duration
+                .map(|max| Instant::now() + max),
+        };
+
+        tokio::spawn(async move {
+            let _guard = guard;
             let mut file = match tokio::fs::File::open(&path).await {
                 Ok(f) => f,
                 Err(err) => {
-                    let _ = tx
+                    sender
                         .send(Err(Status::not_found(format!(
                             "open file failed: {}",
                             err
@@ -282,141 +2425,2185 @@ impl LazySync for LazySyncService {
                 }
             };
 
-            if let Err(err) = file.seek(std::io::SeekFrom::Start(offset)).await {
-                let _ = tx
-                    .send(Err(Status::internal(format!(
-                        "seek failed: {}",
-                        err
-                    ))))
-                    .await;
-                return;
+            let (offset, length) = if line_aligned {
+                match line_aligned_range(&mut file, offset, length).await {
+                    Ok(range) => range,
+                    Err(err) => {
+                        sender
+                            .send(Err(Status::internal(format!("read failed: {}", err))))
+                            .await;
+                        return;
+                    }
+                }
+            } else {
+                (offset, length)
+            };
+
+            if let Err(err) = file.seek(std::io::SeekFrom::Start(offset)).await {
+                sender
+                    .send(Err(Status::internal(format!("seek failed: {}", err))))
+                    .await;
+                return;
+            }
+
+            stream_file(&mut file, &sender, offset, length).await;
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ReadTailStream = ReceiverStream<Result<ReadFileChunk, Status>>;
+
+    async fn read_tail(
+        &self,
+        request: Request<ReadTailRequest>,
+    ) -> Result<Response<Self::ReadTailStream>, Status> {
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+        ensure_not_directory(&path)?;
+
+        let (tx, rx) = mpsc::channel(self.read_buffer(req.buffer_depth));
+        let max_bytes = match req.max_bytes {
+            0 => DEFAULT_TAIL_BYTES,
+            n => n,
+        };
+        let guard = StreamGuard::new(&self.active_streams);
+        let sender = ReadSender {
+            tx,
+            path: path.display().to_string(),
+            stall_timeout: self.config.read_stall_timeout,
+            deadline: self
+                .config
+                .read_max_duration
+                .map(|max| Instant::now() + max),
+        };
+
+        tokio::spawn(async move {
+            let _guard = guard;
+            let mut file = match tokio::fs::File::open(&path).await {
+                Ok(f) => f,
+                Err(err) => {
+                    sender
+                        .send(Err(Status::not_found(format!("open file failed: {}", err))))
+                        .await;
+                    return;
+                }
+            };
+
+            let start = match tail_start(&mut file, max_bytes).await {
+                Ok(start) => start,
+                Err(err) => {
+                    sender
+                        .send(Err(Status::internal(format!("seek failed: {}", err))))
+                        .await;
+                    return;
+                }
+            };
+            stream_file(&mut file, &sender, start, 0).await;
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ReadFilesStream = ReceiverStream<Result<ReadFilesChunk, Status>>;
+
+    async fn read_files(
+        &self,
+        request: Request<ReadFilesRequest>,
+    ) -> Result<Response<Self::ReadFilesStream>, Status> {
+        let req = request.into_inner();
+        if req.paths.is_empty() {
+            return Err(Status::invalid_argument("paths are required"));
+        }
+
+        let (tx, rx) = mpsc::channel(self.read_buffer(req.buffer_depth));
+        let (policy, no_follow) = (self.config.paths, self.config.no_follow_symlinks);
+        let guard = StreamGuard::new(&self.active_streams);
+        let sender = ReadSender {
+            tx,
+            path: format!("{} files", req.paths.len()),
+            stall_timeout: self.config.read_stall_timeout,
+            deadline: self
+                .config
+                .read_max_duration
+                .map(|max| Instant::now() + max),
+        };
+
+        tokio::spawn(async move {
+            let _guard = guard;
+            for (index, path) in req.paths.iter().enumerate() {
+                if !send_file_head(
+                    &sender,
+                    index as u32,
+                    path,
+                    policy,
+                    no_follow,
+                    req.max_bytes_each,
+                )
+                .await
+                {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn write_file(
+        &self,
+        request: Request<tonic::Streaming<WriteFileChunk>>,
+    ) -> Result<Response<WriteFileResponse>, Status> {
+        self.ensure_writable()?;
+        let _guard = StreamGuard::new(&self.active_streams);
+        let mut stream = request.into_inner();
+        let mut path: Option<String> = None;
+        let mut exclusive = false;
+        let mut truncate = false;
+        let mut file: Option<tokio::fs::File> = None;
+        // The validated path, set once the file is open.
+        let mut created: Option<PathBuf> = None;
+        let mut bytes_written = 0u64;
+
+        let received: Result<(), Status> = async {
+            while let Some(chunk) = stream.message().await? {
+                let chunk_path = if !chunk.path.is_empty() {
+                    Some(chunk.path.clone())
+                } else {
+                    path.clone()
+                };
+
+                if path.is_none() {
+                    path = chunk_path;
+                    exclusive = chunk.exclusive;
+                    truncate = chunk.truncate;
+                }
+
+                if file.is_none() {
+                    let target_path =
+                        validate_request_path(path.as_deref().unwrap_or(""), self.config.paths)?;
+                    ensure_within_root(&target_path, self.config.no_follow_symlinks)?;
+                    ensure_not_directory(&target_path)?;
+                    let opened = open_for_write(&target_path, exclusive, truncate).await?;
+                    file = Some(opened);
+                    created = Some(target_path);
+                }
+
+                if let Some(f) = file.as_mut() {
+                    f.seek(std::io::SeekFrom::Start(chunk.offset))
+                        .await
+                        .map_err(|err| Status::internal(format!("seek failed: {}", err)))?;
+                    f.write_all(&chunk.data)
+                        .await
+                        .map_err(|err| Status::internal(format!("write failed: {}", err)))?;
+                    bytes_written += chunk.data.len() as u64;
+                }
+
+                if chunk.eof {
+                    break;
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        // A half-written exclusive file would make every retry fail with
+        // ALREADY_EXISTS, so drop the file this call created.
+        if received.is_err() {
+            if let (true, Some(target)) = (exclusive, &created) {
+                let _ = tokio::fs::remove_file(target).await;
+            }
+        }
+        if let Some(target) = &created {
+            self.notify_changed(target);
+        }
+        received?;
+
+        Ok(Response::new(WriteFileResponse { bytes_written }))
+    }
+
+    type CopyPathStream = ReceiverStream<Result<CopyProgress, Status>>;
+
+    async fn copy_path(
+        &self,
+        request: Request<CopyRequest>,
+    ) -> Result<Response<Self::CopyPathStream>, Status> {
+        self.ensure_writable()?;
+        let req = request.into_inner();
+        if req.from.is_empty() || req.to.is_empty() {
+            return Err(Status::invalid_argument("from and to are required"));
+        }
+
+        let from = validate_request_path(&req.from, self.config.paths)?;
+        let to = validate_request_path(&req.to, self.config.paths)?;
+        for path in [&from, &to] {
+            ensure_within_root(path, self.config.no_follow_symlinks)?;
+        }
+        let meta = if req.dereference {
+            fs::metadata(&from)
+        } else {
+            fs::symlink_metadata(&from)
+        }
+        .map_err(|err| Status::not_found(format!("stat failed: {}", err)))?;
+
+        if meta.is_dir() {
+            if !req.recursive {
+                return Err(Status::invalid_argument(
+                    "source is a directory; set recursive to copy it",
+                ));
+            }
+            if to_absolute_path(&to).starts_with(to_absolute_path(&from)) {
+                return Err(Status::invalid_argument(
+                    "cannot copy a directory into itself",
+                ));
+            }
+        }
+        if fs::symlink_metadata(&to).is_ok() {
+            return Err(Status::already_exists(format!(
+                "destination exists: {}",
+                req.to
+            )));
+        }
+
+        let (tx, rx) = mpsc::channel(8);
+        let mut job = CopyJob::new(req.dereference, Some(tx));
+        job.confined = req.dereference && self.config.no_follow_symlinks;
+
+        let guard = StreamGuard::new(&self.active_streams);
+        let invalidations = self.invalidations.clone();
+        tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            let error = job
+                .copy_entry(&from, &to)
+                .err()
+                .map(|err| format!("copy failed: {}", err));
+            // A failed copy may still have left part of the tree behind.
+            notify_changed(&invalidations, &to);
+            job.finish(error);
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn move_path(
+        &self,
+        request: Request<MoveRequest>,
+    ) -> Result<Response<MoveResponse>, Status> {
+        self.ensure_writable()?;
+        let req = request.into_inner();
+        if req.from.is_empty() || req.to.is_empty() {
+            return Err(Status::invalid_argument("from and to are required"));
+        }
+
+        let from = validate_request_path(&req.from, self.config.paths)?;
+        let to = validate_request_path(&req.to, self.config.paths)?;
+        for path in [&from, &to] {
+            ensure_within_root(path, self.config.no_follow_symlinks)?;
+        }
+        if fs::symlink_metadata(&from).is_err() {
+            return Err(Status::not_found(format!("source not found: {}", req.from)));
+        }
+        if fs::symlink_metadata(&to).is_ok() {
+            return Err(Status::already_exists(format!(
+                "destination exists: {}",
+                req.to
+            )));
+        }
+
+        let (source, target) = (from.clone(), to.clone());
+        let reply = tokio::task::spawn_blocking(move || move_path(&source, &target))
+            .await
+            .map_err(|err| Status::internal(format!("move task failed: {}", err)))?
+            .map_err(|err| Status::internal(format!("move failed: {}", err)))?;
+        self.notify_changed(&from);
+        self.notify_changed(&to);
+        Ok(Response::new(reply))
+    }
+
+    async fn checksum(
+        &self,
+        request: Request<ChecksumRequest>,
+    ) -> Result<Response<ChecksumResponse>, Status> {
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+        let meta = match fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Status::not_found(format!("not found: {}", req.path)));
+            }
+            Err(err) => return Err(Status::internal(format!("stat failed: {}", err))),
+        };
+        if !meta.is_file() {
+            return Err(Status::invalid_argument("checksum requires a regular file"));
+        }
+
+        let guard = StreamGuard::new(&self.active_streams);
+        let sha256 = tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            sha256_file(&path)
+        })
+        .await
+        .map_err(|err| Status::internal(format!("checksum task failed: {}", err)))?
+        .map_err(|err| Status::internal(format!("checksum failed: {}", err)))?;
+
+        Ok(Response::new(ChecksumResponse {
+            sha256,
+            size: meta.len(),
+            modified_unix: meta.mtime(),
+        }))
+    }
+
+    async fn sniff(
+        &self,
+        request: Request<SniffRequest>,
+    ) -> Result<Response<SniffResponse>, Status> {
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+        match fs::metadata(&path) {
+            Ok(meta) if meta.is_file() => {}
+            Ok(_) => return Err(Status::invalid_argument("sniff requires a regular file")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Status::not_found(format!("not found: {}", req.path)));
+            }
+            Err(err) => return Err(Status::internal(format!("stat failed: {}", err))),
+        }
+
+        let max_bytes = match req.max_bytes as usize {
+            0 => DEFAULT_SNIFF_BYTES,
+            n => n.min(MAX_SNIFF_BYTES),
+        };
+        let head = tokio::task::spawn_blocking(move || read_head(&path, max_bytes))
+            .await
+            .map_err(|err| Status::internal(format!("sniff task failed: {}", err)))?
+            .map_err(|err| Status::internal(format!("read failed: {}", err)))?;
+        Ok(Response::new(sniff_head(&head)))
+    }
+
+    async fn compare_files(
+        &self,
+        request: Request<CompareFilesRequest>,
+    ) -> Result<Response<CompareFilesResponse>, Status> {
+        let req = request.into_inner();
+        if req.path_a.is_empty() || req.path_b.is_empty() {
+            return Err(Status::invalid_argument("path_a and path_b are required"));
+        }
+
+        let a = validate_request_path(&req.path_a, self.config.paths)?;
+        let b = validate_request_path(&req.path_b, self.config.paths)?;
+        for path in [&a, &b] {
+            ensure_within_root(path, self.config.no_follow_symlinks)?;
+            match fs::metadata(path) {
+                Ok(meta) if meta.is_file() => {}
+                Ok(_) => {
+                    return Err(Status::invalid_argument(format!(
+                        "not a regular file: {}",
+                        path.display()
+                    )))
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(Status::not_found(format!("not found: {}", path.display())));
+                }
+                Err(err) => return Err(Status::internal(format!("stat failed: {}", err))),
+            }
+        }
+
+        let guard = StreamGuard::new(&self.active_streams);
+        let diff = tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            first_difference(&a, &b)
+        })
+        .await
+        .map_err(|err| Status::internal(format!("compare task failed: {}", err)))?
+        .map_err(|err| Status::internal(format!("compare failed: {}", err)))?;
+
+        Ok(Response::new(CompareFilesResponse {
+            identical: diff.is_none(),
+            first_diff_offset: diff.unwrap_or_default(),
+        }))
+    }
+
+    async fn swap_paths(
+        &self,
+        request: Request<SwapPathsRequest>,
+    ) -> Result<Response<SwapPathsResponse>, Status> {
+        self.ensure_writable()?;
+        let req = request.into_inner();
+        if req.a.is_empty() || req.b.is_empty() {
+            return Err(Status::invalid_argument("a and b are required"));
+        }
+
+        let a = validate_request_path(&req.a, self.config.paths)?;
+        let b = validate_request_path(&req.b, self.config.paths)?;
+        for path in [&a, &b] {
+            ensure_within_root(path, self.config.no_follow_symlinks)?;
+            if fs::symlink_metadata(path).is_err() {
+                return Err(Status::not_found(format!("not found: {}", path.display())));
+            }
+        }
+        if to_absolute_path(&a) == to_absolute_path(&b) {
+            return Err(Status::invalid_argument("a and b are the same path"));
+        }
+
+        let (first, second) = (a.clone(), b.clone());
+        let atomic = tokio::task::spawn_blocking(move || swap_paths(&first, &second))
+            .await
+            .map_err(|err| Status::internal(format!("swap task failed: {}", err)))??;
+        self.notify_changed(&a);
+        self.notify_changed(&b);
+        Ok(Response::new(SwapPathsResponse { atomic }))
+    }
+
+    async fn exists_batch(
+        &self,
+        request: Request<ExistsBatchRequest>,
+    ) -> Result<Response<ExistsBatchResponse>, Status> {
+        let req = request.into_inner();
+        let mut results = Vec::with_capacity(req.paths.len());
+        for path in &req.paths {
+            let path = validate_request_path(path, self.config.paths)?;
+            ensure_within_root(&path, self.config.no_follow_symlinks)?;
+            match fs::symlink_metadata(&path) {
+                Ok(_) => results.push(true),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => results.push(false),
+                Err(err) => {
+                    return Err(Status::internal(format!(
+                        "stat {} failed: {}",
+                        path.display(),
+                        err
+                    )));
+                }
+            }
+        }
+        Ok(Response::new(ExistsBatchResponse { results }))
+    }
+
+    async fn create_symlink(
+        &self,
+        request: Request<CreateSymlinkRequest>,
+    ) -> Result<Response<CreateSymlinkResponse>, Status> {
+        self.ensure_writable()?;
+        let req = request.into_inner();
+        if req.path.is_empty() || req.target.is_empty() {
+            return Err(Status::invalid_argument("path and target are required"));
+        }
+
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+        // The target is relative to the link's directory, not the base dir.
+        let target_path = path.parent().unwrap_or(Path::new("")).join(&req.target);
+        ensure_within_root(&target_path, self.config.no_follow_symlinks)?;
+        if let Ok(existing) = fs::symlink_metadata(&path) {
+            if !req.overwrite {
+                return Err(Status::already_exists(format!("path exists: {}", req.path)));
+            }
+            if existing.is_dir() {
+                return Err(Status::failed_precondition(format!(
+                    "refusing to replace directory: {}",
+                    req.path
+                )));
+            }
+            fs::remove_file(&path)
+                .map_err(|err| Status::internal(format!("unlink failed: {}", err)))?;
+        }
+
+        std::os::unix::fs::symlink(&req.target, &path)
+            .map_err(|err| Status::internal(format!("symlink failed: {}", err)))?;
+        self.notify_changed(&path);
+
+        let target = fs::read_link(&path)
+            .map_err(|err| Status::internal(format!("read_link failed: {}", err)))?;
+        let meta = fs::symlink_metadata(&path)
+            .map_err(|err| Status::internal(format!("stat failed: {}", err)))?;
+        Ok(Response::new(CreateSymlinkResponse {
+            target: target.display().to_string(),
+            target_resolves: fs::metadata(&path).is_ok(),
+            info: Some(build_file_info(&path, &meta)),
+        }))
+    }
+
+    async fn set_owner(
+        &self,
+        request: Request<SetOwnerRequest>,
+    ) -> Result<Response<SetOwnerResponse>, Status> {
+        self.ensure_writable()?;
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        let id = |value: i64, what: &str| -> Result<Option<u32>, BoxedStatus> {
+            match value {
+                -1 => Ok(None),
+                value => u32::try_from(value).map(Some).map_err(|_| {
+                    Status::invalid_argument(format!("invalid {}: {}", what, value)).into()
+                }),
+            }
+        };
+        let uid = id(req.uid, "uid")?;
+        let gid = id(req.gid, "gid")?;
+
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+        // In no-follow mode a symlink's own ownership changes, not its target's.
+        let changed = if self.config.no_follow_symlinks {
+            std::os::unix::fs::lchown(&path, uid, gid)
+        } else {
+            std::os::unix::fs::chown(&path, uid, gid)
+        };
+        changed.map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => Status::not_found(format!("not found: {}", req.path)),
+            std::io::ErrorKind::PermissionDenied => {
+                Status::permission_denied(format!("chown failed: {}", err))
+            }
+            _ => Status::internal(format!("chown failed: {}", err)),
+        })?;
+        self.notify_changed(&path);
+
+        let meta = fs::symlink_metadata(&path)
+            .map_err(|err| Status::internal(format!("stat failed: {}", err)))?;
+        Ok(Response::new(SetOwnerResponse {
+            info: Some(build_file_info(&path, &meta)),
+        }))
+    }
+
+    async fn create_file(
+        &self,
+        request: Request<CreateFileRequest>,
+    ) -> Result<Response<CreateFileResponse>, Status> {
+        self.ensure_writable()?;
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+        ensure_not_directory(&path)?;
+
+        let created = path.clone();
+        tokio::task::spawn_blocking(move || {
+            create_file(&created, req.size, req.sparse, req.overwrite)
+        })
+        .await
+        .map_err(|err| Status::internal(format!("create task failed: {}", err)))??;
+        self.notify_changed(&path);
+
+        let meta = fs::symlink_metadata(&path)
+            .map_err(|err| Status::internal(format!("stat failed: {}", err)))?;
+        Ok(Response::new(CreateFileResponse {
+            info: Some(build_file_info(&path, &meta)),
+        }))
+    }
+
+    async fn sync_path(
+        &self,
+        request: Request<SyncPathRequest>,
+    ) -> Result<Response<SyncPathResponse>, Status> {
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+
+        let root_dev = root_device(&path, req.stay_on_filesystem);
+        let synced = tokio::task::spawn_blocking(move || sync_tree(&path, req.recursive, root_dev))
+            .await
+            .map_err(|err| Status::internal(format!("sync task failed: {}", err)))??;
+        Ok(Response::new(synced))
+    }
+
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<SearchResponse>, Status> {
+        let req = request.into_inner();
+        if req.root.is_empty() || req.pattern.is_empty() {
+            return Err(Status::invalid_argument("root and pattern are required"));
+        }
+        if req.pattern.starts_with('/') {
+            return Err(Status::invalid_argument("pattern must be relative to root"));
+        }
+
+        let root = validate_request_path(&req.root, self.config.paths)?;
+        ensure_within_root(&root, self.config.no_follow_symlinks)?;
+        match fs::metadata(&root) {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => return Err(Status::invalid_argument("search root must be a directory")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Status::not_found(format!("not found: {}", req.root)));
+            }
+            Err(err) => return Err(Status::internal(format!("stat failed: {}", err))),
+        }
+
+        let max_results = match req.max_results as usize {
+            0 => DEFAULT_SEARCH_RESULTS,
+            n => n.min(MAX_SEARCH_RESULTS),
+        };
+        let pattern = req.pattern;
+        let root_dev = root_device(&root, req.stay_on_filesystem);
+        let found = tokio::task::spawn_blocking(move || {
+            search_tree(&root, &pattern, max_results, root_dev)
+        })
+        .await
+        .map_err(|err| Status::internal(format!("search task failed: {}", err)))??;
+        Ok(Response::new(found))
+    }
+
+    async fn list_archive(
+        &self,
+        request: Request<ListArchiveRequest>,
+    ) -> Result<Response<ListArchiveResponse>, Status> {
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+
+        let inner_path = archive_member_path(&req.inner_path);
+        let members = tokio::task::spawn_blocking(move || scan_archive(&path))
+            .await
+            .map_err(|err| Status::internal(format!("archive task failed: {}", err)))??;
+        let entries = archive_children(members, &inner_path)
+            .ok_or_else(|| Status::not_found(format!("no directory {} in archive", inner_path)))?;
+        Ok(Response::new(ListArchiveResponse {
+            inner_path,
+            entries,
+        }))
+    }
+
+    type ReadArchiveEntryStream = ReceiverStream<Result<ReadFileChunk, Status>>;
+
+    async fn read_archive_entry(
+        &self,
+        request: Request<ReadArchiveEntryRequest>,
+    ) -> Result<Response<Self::ReadArchiveEntryStream>, Status> {
+        let req = request.into_inner();
+        if req.archive_path.is_empty() || req.entry_path.is_empty() {
+            return Err(Status::invalid_argument(
+                "archive_path and entry_path are required",
+            ));
+        }
+        let path = validate_request_path(&req.archive_path, self.config.paths)?;
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+
+        let (tx, rx) = mpsc::channel(self.config.read_buffer);
+        let entry_path = archive_member_path(&req.entry_path);
+        let guard = StreamGuard::new(&self.active_streams);
+        let sender = ReadSender {
+            tx,
+            path: format!("{}:{}", req.archive_path, entry_path),
+            stall_timeout: self.config.read_stall_timeout,
+            deadline: self
+                .config
+                .read_max_duration
+                .map(|max| Instant::now() + max),
+        };
+        let runtime = tokio::runtime::Handle::current();
+
+        tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            if let Err(status) = stream_archive_entry(&path, &entry_path, &sender, &runtime) {
+                runtime.block_on(sender.send(Err(status.into())));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ArchivePathStream = ReceiverStream<Result<ReadFileChunk, Status>>;
+
+    async fn archive_path(
+        &self,
+        request: Request<ArchivePathRequest>,
+    ) -> Result<Response<Self::ArchivePathStream>, Status> {
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        let gzip = match req.format.as_str() {
+            "" | "tar" => false,
+            "tar.gz" | "tgz" => true,
+            "zip" => {
+                return Err(Status::unimplemented(
+                    "zip cannot be streamed; use tar or tar.gz",
+                ))
+            }
+            other => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown archive format: {}",
+                    other
+                )))
+            }
+        };
+        ensure_within_root(&path, self.config.no_follow_symlinks)?;
+        if fs::symlink_metadata(&path).is_err() {
+            return Err(Status::not_found(format!("not found: {}", req.path)));
+        }
+
+        let (tx, rx) = mpsc::channel(self.read_buffer(req.buffer_depth));
+        let guard = StreamGuard::new(&self.active_streams);
+        let sender = ReadSender {
+            tx,
+            path: req.path.clone(),
+            stall_timeout: self.config.read_stall_timeout,
+            deadline: self
+                .config
+                .read_max_duration
+                .map(|max| Instant::now() + max),
+        };
+        let runtime = tokio::runtime::Handle::current();
+
+        tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            if let Err(status) = stream_path_archive(&path, gzip, &sender, &runtime) {
+                runtime.block_on(sender.send(Err(status.into())));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn real_path(
+        &self,
+        request: Request<RealPathRequest>,
+    ) -> Result<Response<RealPathResponse>, Status> {
+        let req = request.into_inner();
+        let path = validate_request_path(&req.path, self.config.paths)?;
+        let absolute = to_absolute_path(&path);
+        let (canonical, exists) = match fs::canonicalize(&absolute) {
+            Ok(canonical) => (canonical, true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                (lexical_normalize(&absolute), false)
             }
+            Err(err) => return Err(Status::internal(format!("canonicalize failed: {}", err))),
+        };
+        Ok(Response::new(RealPathResponse {
+            canonical: canonical.display().to_string(),
+            exists,
+        }))
+    }
+
+    async fn list_mounts(
+        &self,
+        request: Request<ListMountsRequest>,
+    ) -> Result<Response<ListMountsResponse>, Status> {
+        let include_pseudo = request.into_inner().include_pseudo;
+        // statvfs can block for a long time on an unresponsive network mount.
+        let mounts = tokio::task::spawn_blocking(move || list_mounts(include_pseudo))
+            .await
+            .map_err(|err| Status::internal(format!("list mounts task failed: {}", err)))??;
+        Ok(Response::new(mounts))
+    }
+    type WatchInvalidationsStream = ReceiverStream<Result<Invalidation, Status>>;
 
-            let mut remaining = if length == 0 { None } else { Some(length) };
-            let mut current_offset = offset;
-            let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+    // Only changes made through this server's own RPCs are reported; the
+    // stream ends when the client goes away.
+    async fn watch_invalidations(
+        &self,
+        request: Request<WatchInvalidationsRequest>,
+    ) -> Result<Response<Self::WatchInvalidationsStream>, Status> {
+        let req = request.into_inner();
+        let root = if req.root.is_empty() {
+            None
+        } else {
+            let root = validate_request_path(&req.root, self.config.paths)?;
+            ensure_within_root(&root, self.config.no_follow_symlinks)?;
+            Some(to_absolute_path(&root))
+        };
 
+        // Subscribe before replying so nothing after the response is missed.
+        let mut changes = self.invalidations.subscribe();
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
             loop {
-                let read_len = match remaining {
-                    Some(left) => {
-                        if left == 0 {
-                            let _ = tx
-                                .send(Ok(ReadFileChunk {
-                                    data: Vec::new(),
-                                    offset: current_offset,
-                                    eof: true,
-                                }))
-                                .await;
-                            break;
-                        }
-                        std::cmp::min(left as usize, buffer.len())
-                    }
-                    None => buffer.len(),
+                let changed = tokio::select! {
+                    changed = changes.recv() => changed,
+                    _ = tx.closed() => break,
                 };
-
-                let bytes_read = match file.read(&mut buffer[..read_len]).await {
-                    Ok(0) => {
-                        let _ = tx
-                            .send(Ok(ReadFileChunk {
-                                data: Vec::new(),
-                                offset: current_offset,
-                                eof: true,
-                            }))
-                            .await;
-                        break;
-                    }
-                    Ok(n) => n,
-                    Err(err) => {
-                        let _ = tx
-                            .send(Err(Status::internal(format!(
-                                "read failed: {}",
-                                err
-                            ))))
-                            .await;
-                        break;
+                let event = match changed {
+                    Ok(path) => {
+                        if root.as_ref().is_some_and(|root| !path.starts_with(root)) {
+                            continue;
+                        }
+                        Invalidation {
+                            path: path.display().to_string(),
+                            lagged: false,
+                        }
                     }
+                    Err(broadcast::error::RecvError::Lagged(_)) => Invalidation {
+                        path: String::new(),
+                        lagged: true,
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break,
                 };
-
-                let chunk = ReadFileChunk {
-                    data: buffer[..bytes_read].to_vec(),
-                    offset: current_offset,
-                    eof: false,
-                };
-                if tx.send(Ok(chunk)).await.is_err() {
+                if tx.send(Ok(event)).await.is_err() {
                     break;
                 }
-
-                current_offset += bytes_read as u64;
-                if let Some(left) = remaining.as_mut() {
-                    *left -= bytes_read as u64;
-                }
             }
         });
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+}
 
-    async fn write_file(
-        &self,
-        request: Request<tonic::Streaming<WriteFileChunk>>,
-    ) -> Result<Response<WriteFileResponse>, Status> {
-        let mut stream = request.into_inner();
-        let mut path: Option<String> = None;
-        let mut file: Option<tokio::fs::File> = None;
-        let mut bytes_written = 0u64;
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let addr = "127.0.0.1:9000".parse()?;
+    let config = ServerConfig::from_env();
+    // Relative paths handed straight to fs calls must agree with to_absolute_path.
+    std::env::set_current_dir(&config.base_dir)?;
+    BASE_DIR
+        .set(config.base_dir.clone())
+        .map_err(|_| "base dir already initialized")?;
+    println!("gRPC server listening on {}", addr);
+    println!("base dir: {}", config.base_dir.display());
+    println!("read_file buffer depth: {}", config.read_buffer);
+    if config.read_only {
+        println!("read-only mode: mutating RPCs are disabled");
+    }
+    if config.no_follow_symlinks {
+        println!("no-follow mode: symlinks are listed but never followed");
+    }
+    if config.paths.sandbox {
+        println!("sandboxed paths: absolute paths and .. are rejected");
+    }
+    if config.paths.expand {
+        println!("path expansion: ~ and $VAR are expanded in request paths");
+    }
+    let limits = &config.limits;
+    println!(
+        "limits: {} concurrent requests per connection, {} streams per connection, timeout {:?}, keepalive {:?}",
+        limits.concurrency_per_connection,
+        limits.max_concurrent_streams,
+        limits.request_timeout,
+        limits.tcp_keepalive
+    );
+    let mut server = Server::builder()
+        .concurrency_limit_per_connection(limits.concurrency_per_connection)
+        .max_concurrent_streams(limits.max_concurrent_streams)
+        .tcp_keepalive(limits.tcp_keepalive);
+    if let Some(timeout) = limits.request_timeout {
+        server = server.timeout(timeout);
+    }
+    server
+        .add_service(LazySyncServer::new(LazySyncService::new(config)))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
 
-        while let Some(chunk) = stream.message().await? {
-            let chunk_path = if !chunk.path.is_empty() {
-                Some(chunk.path.clone())
-            } else {
-                path.clone()
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazysync::lazy_sync_client::LazySyncClient;
+    use proptest::prelude::*;
+    use tonic::Code;
 
-            if path.is_none() {
-                path = chunk_path;
-            }
+    const SANDBOX: PathPolicy = PathPolicy {
+        sandbox: true,
+        expand: false,
+    };
 
-            let target_path = match &path {
-                Some(p) if !p.is_empty() => p.clone(),
-                _ => return Err(Status::invalid_argument("path is required")),
-            };
+    fn test_config(paths: PathPolicy) -> ServerConfig {
+        ServerConfig {
+            read_buffer: DEFAULT_READ_BUFFER,
+            base_dir: base_dir().to_path_buf(),
+            read_only: false,
+            no_follow_symlinks: false,
+            paths,
+            max_response: DEFAULT_MAX_RESPONSE,
+            read_stall_timeout: None,
+            read_max_duration: None,
+            limits: ConnectionLimits {
+                concurrency_per_connection: DEFAULT_CONCURRENCY_PER_CONNECTION,
+                max_concurrent_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+                request_timeout: None,
+                tcp_keepalive: None,
+            },
+        }
+    }
 
-            if file.is_none() {
-                let opened = tokio::fs::OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(&target_path)
-                    .await
-                    .map_err(|err| Status::internal(format!("open file failed: {}", err)))?;
-                file = Some(opened);
-            }
+    // Serves `service` on an ephemeral port and returns its address.
+    async fn serve(service: LazySyncService) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(LazySyncServer::new(service))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        addr
+    }
 
-            if let Some(f) = file.as_mut() {
-                f.seek(std::io::SeekFrom::Start(chunk.offset))
-                    .await
-                    .map_err(|err| Status::internal(format!("seek failed: {}", err)))?;
-                f.write_all(&chunk.data)
+    // For RPCs that take a client stream.
+    async fn connect(service: LazySyncService) -> LazySyncClient<tonic::transport::Channel> {
+        let addr = serve(service).await;
+        LazySyncClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap()
+    }
+
+    fn code<T>(result: Result<T, BoxedStatus>) -> Code {
+        match result {
+            Ok(_) => Code::Ok,
+            Err(status) => status.code(),
+        }
+    }
+
+    fn assert_denied<T>(rpc: &str, result: Result<Response<T>, Status>) {
+        match result {
+            Ok(_) => panic!("{} accepted a path outside the sandbox", rpc),
+            Err(status) => assert_eq!(status.code(), Code::PermissionDenied, "{}", rpc),
+        }
+    }
+
+    #[test]
+    fn validate_request_path_rejects_empty() {
+        let policy = PathPolicy::default();
+        assert_eq!(
+            code(validate_request_path("", policy)),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            code(validate_request_path("", SANDBOX)),
+            Code::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn validate_request_path_normalizes_relative_paths() {
+        for policy in [PathPolicy::default(), SANDBOX] {
+            let path = validate_request_path("docs//notes/", policy).unwrap();
+            assert_eq!(path, PathBuf::from("docs/notes"));
+        }
+    }
+
+    #[test]
+    fn validate_request_path_rejects_traversal_only_in_sandbox() {
+        for path in ["../etc", "docs/../../etc", "/etc/passwd"] {
+            assert_eq!(
+                code(validate_request_path(path, SANDBOX)),
+                Code::PermissionDenied
+            );
+            assert!(validate_request_path(path, PathPolicy::default()).is_ok());
+        }
+    }
+
+    #[test]
+    fn validate_request_path_expands_tilde() {
+        let home = std::env::var("HOME").unwrap();
+        let expand = PathPolicy {
+            sandbox: false,
+            expand: true,
+        };
+        assert_eq!(
+            validate_request_path("~/notes", expand).unwrap(),
+            PathBuf::from(&home).join("notes")
+        );
+        assert_eq!(
+            validate_request_path("~", expand).unwrap(),
+            PathBuf::from(normalize_request_path(&home))
+        );
+        assert_eq!(
+            code(validate_request_path("~root/x", expand)),
+            Code::InvalidArgument
+        );
+        // Without expansion `~` is just a directory name.
+        assert_eq!(
+            validate_request_path("~/notes", PathPolicy::default()).unwrap(),
+            PathBuf::from("~/notes")
+        );
+        // Expansion runs before the sandbox check, so it can't be used to escape.
+        let both = PathPolicy {
+            sandbox: true,
+            expand: true,
+        };
+        assert_eq!(
+            code(validate_request_path("~/notes", both)),
+            Code::PermissionDenied
+        );
+    }
+
+    #[tokio::test]
+    async fn every_rpc_rejects_paths_outside_the_sandbox() {
+        let service = LazySyncService::new(test_config(SANDBOX));
+        let out = "../outside".to_string();
+        let ok = "inside".to_string();
+
+        assert_denied(
+            "get_path",
+            service
+                .get_path(Request::new(GetPathRequest {
+                    path: out.clone(),
+                    ..Default::default()
+                }))
+                .await,
+        );
+        assert_denied(
+            "stat",
+            service
+                .stat(Request::new(StatRequest { path: out.clone() }))
+                .await,
+        );
+        assert_denied(
+            "read_file",
+            service
+                .read_file(Request::new(ReadFileRequest {
+                    path: out.clone(),
+                    ..Default::default()
+                }))
+                .await,
+        );
+        assert_denied(
+            "read_tail",
+            service
+                .read_tail(Request::new(ReadTailRequest {
+                    path: out.clone(),
+                    ..Default::default()
+                }))
+                .await,
+        );
+        for (from, to) in [(&out, &ok), (&ok, &out)] {
+            assert_denied(
+                "copy_path",
+                service
+                    .copy_path(Request::new(CopyRequest {
+                        from: from.clone(),
+                        to: to.clone(),
+                        ..Default::default()
+                    }))
+                    .await,
+            );
+            assert_denied(
+                "move_path",
+                service
+                    .move_path(Request::new(MoveRequest {
+                        from: from.clone(),
+                        to: to.clone(),
+                    }))
+                    .await,
+            );
+            assert_denied(
+                "compare_files",
+                service
+                    .compare_files(Request::new(CompareFilesRequest {
+                        path_a: from.clone(),
+                        path_b: to.clone(),
+                    }))
+                    .await,
+            );
+            assert_denied(
+                "swap_paths",
+                service
+                    .swap_paths(Request::new(SwapPathsRequest {
+                        a: from.clone(),
+                        b: to.clone(),
+                    }))
+                    .await,
+            );
+        }
+        assert_denied(
+            "checksum",
+            service
+                .checksum(Request::new(ChecksumRequest { path: out.clone() }))
+                .await,
+        );
+        assert_denied(
+            "sniff",
+            service
+                .sniff(Request::new(SniffRequest {
+                    path: out.clone(),
+                    ..Default::default()
+                }))
+                .await,
+        );
+        assert_denied(
+            "exists_batch",
+            service
+                .exists_batch(Request::new(ExistsBatchRequest {
+                    paths: vec![ok.clone(), out.clone()],
+                }))
+                .await,
+        );
+        assert_denied(
+            "create_symlink",
+            service
+                .create_symlink(Request::new(CreateSymlinkRequest {
+                    path: out.clone(),
+                    target: ok.clone(),
+                    ..Default::default()
+                }))
+                .await,
+        );
+        assert_denied(
+            "set_owner",
+            service
+                .set_owner(Request::new(SetOwnerRequest {
+                    path: out.clone(),
+                    uid: -1,
+                    gid: -1,
+                }))
+                .await,
+        );
+        assert_denied(
+            "create_file",
+            service
+                .create_file(Request::new(CreateFileRequest {
+                    path: out.clone(),
+                    ..Default::default()
+                }))
+                .await,
+        );
+        assert_denied(
+            "sync_path",
+            service
+                .sync_path(Request::new(SyncPathRequest {
+                    path: out.clone(),
+                    ..Default::default()
+                }))
+                .await,
+        );
+        assert_denied(
+            "search",
+            service
+                .search(Request::new(SearchRequest {
+                    root: out.clone(),
+                    pattern: "*".to_string(),
+                    ..Default::default()
+                }))
+                .await,
+        );
+        assert_denied(
+            "watch_invalidations",
+            service
+                .watch_invalidations(Request::new(WatchInvalidationsRequest {
+                    root: out.clone(),
+                }))
+                .await,
+        );
+        assert_denied(
+            "list_archive",
+            service
+                .list_archive(Request::new(ListArchiveRequest {
+                    path: out.clone(),
+                    ..Default::default()
+                }))
+                .await,
+        );
+        assert_denied(
+            "read_archive_entry",
+            service
+                .read_archive_entry(Request::new(ReadArchiveEntryRequest {
+                    archive_path: out.clone(),
+                    entry_path: "entry".to_string(),
+                }))
+                .await,
+        );
+        assert_denied(
+            "archive_path",
+            service
+                .archive_path(Request::new(ArchivePathRequest {
+                    path: out.clone(),
+                    ..Default::default()
+                }))
+                .await,
+        );
+        assert_denied(
+            "real_path",
+            service
+                .real_path(Request::new(RealPathRequest { path: out.clone() }))
+                .await,
+        );
+
+        // The batch RPCs report per-path errors instead of failing outright.
+        let stats = service
+            .stat_batch(Request::new(StatBatchRequest {
+                paths: vec![out.clone()],
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(stats.results[0].error.contains(".."));
+        let mut heads = service
+            .read_files(Request::new(ReadFilesRequest {
+                paths: vec![out.clone()],
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let head = tokio_stream::StreamExt::next(&mut heads)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(head.error.contains(".."), "{}", head.error);
+
+        let mut client = connect(LazySyncService::new(test_config(SANDBOX))).await;
+        let chunk = WriteFileChunk {
+            path: out.clone(),
+            data: b"escape".to_vec(),
+            eof: true,
+            ..Default::default()
+        };
+        let status = client
+            .write_file(tokio_stream::iter(vec![chunk]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+
+    fn no_follow_service() -> LazySyncService {
+        let mut config = test_config(PathPolicy::default());
+        config.no_follow_symlinks = true;
+        LazySyncService::new(config)
+    }
+
+    fn listed_dirs(response: GetPathResponse) -> Vec<String> {
+        response
+            .entries
+            .into_iter()
+            .map(|dir| dir.absolute_path)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn symlinked_dirs_are_not_descended_into_in_no_follow_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("real")).unwrap();
+        fs::write(dir.path().join("real/inner.txt"), b"x").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("real"), dir.path().join("link")).unwrap();
+        let request = || {
+            Request::new(GetPathRequest {
+                path: dir.path().display().to_string(),
+                ..Default::default()
+            })
+        };
+        let link = dir.path().join("link").display().to_string();
+
+        let following = LazySyncService::new(test_config(PathPolicy::default()));
+        let response = following.get_path(request()).await.unwrap().into_inner();
+        assert!(listed_dirs(response).contains(&link));
+
+        let response = no_follow_service()
+            .get_path(request())
+            .await
+            .unwrap()
+            .into_inner();
+        let listing = response
+            .entries
+            .iter()
+            .find(|entries| entries.absolute_path == dir.path().display().to_string())
+            .unwrap();
+        // The link itself is still listed, just not expanded.
+        let entry = listing.entries.iter().find(|e| e.name == "link").unwrap();
+        assert_eq!(entry.file_type, "symlink");
+        assert!(!listed_dirs(response).contains(&link));
+    }
+
+    #[tokio::test]
+    async fn no_follow_mode_rejects_both_ends_that_escape_the_root() {
+        // The temp dir is outside the base dir, so every link in it escapes.
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret"), b"x").unwrap();
+        let escape = dir.path().join("escape");
+        std::os::unix::fs::symlink(outside.path(), &escape).unwrap();
+        fs::write(dir.path().join("plain"), b"x").unwrap();
+        let path = |name: &str| dir.path().join(name).display().to_string();
+        let service = no_follow_service();
+
+        let copy = |from: String, to: String| CopyRequest {
+            from,
+            to,
+            recursive: true,
+            dereference: true,
+        };
+        assert_denied(
+            "copy_path from",
+            service
+                .copy_path(Request::new(copy(path("escape"), path("copy"))))
+                .await,
+        );
+        assert_denied(
+            "copy_path to",
+            service
+                .copy_path(Request::new(copy(path("plain"), path("escape/plain"))))
+                .await,
+        );
+        assert_denied(
+            "move_path from",
+            service
+                .move_path(Request::new(MoveRequest {
+                    from: path("escape/secret"),
+                    to: path("moved"),
+                }))
+                .await,
+        );
+        assert_denied(
+            "move_path to",
+            service
+                .move_path(Request::new(MoveRequest {
+                    from: path("plain"),
+                    to: path("escape/plain"),
+                }))
+                .await,
+        );
+        assert_denied(
+            "swap_paths",
+            service
+                .swap_paths(Request::new(SwapPathsRequest {
+                    a: path("plain"),
+                    b: path("escape/secret"),
+                }))
+                .await,
+        );
+        assert_denied(
+            "exists_batch",
+            service
+                .exists_batch(Request::new(ExistsBatchRequest {
+                    paths: vec![path("escape/secret")],
+                }))
+                .await,
+        );
+        assert_denied(
+            "create_symlink path",
+            service
+                .create_symlink(Request::new(CreateSymlinkRequest {
+                    path: path("escape/link"),
+                    target: "plain".to_string(),
+                    overwrite: false,
+                }))
+                .await,
+        );
+        assert_denied(
+            "create_symlink target",
+            service
+                .create_symlink(Request::new(CreateSymlinkRequest {
+                    path: path("link"),
+                    target: "escape/secret".to_string(),
+                    overwrite: false,
+                }))
+                .await,
+        );
+        assert!(dir.path().join("plain").exists());
+        assert!(outside.path().join("secret").exists());
+    }
+
+    #[test]
+    fn confined_copy_refuses_nested_links_that_escape() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("tree")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("tree/escape")).unwrap();
+
+        let mut job = CopyJob::new(true, None);
+        job.confined = true;
+        let err = job
+            .copy_entry(&dir.path().join("tree"), &dir.path().join("copy"))
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn dereferencing_copy_fails_on_link_cycles() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        fs::write(tree.join("file"), b"x").unwrap();
+        std::os::unix::fs::symlink(".", tree.join("l1")).unwrap();
+        std::os::unix::fs::symlink(".", tree.join("l2")).unwrap();
+
+        let err = CopyJob::new(true, None)
+            .copy_entry(&tree, &dir.path().join("copy"))
+            .unwrap_err();
+        assert!(err.to_string().contains("cycle detected"), "{}", err);
+
+        // Without dereferencing the links are copied as links.
+        let copy = dir.path().join("plain");
+        CopyJob::new(false, None).copy_entry(&tree, &copy).unwrap();
+        assert_eq!(fs::read_link(copy.join("l1")).unwrap(), Path::new("."));
+    }
+
+    #[test]
+    fn dereferencing_copy_allows_the_same_directory_twice_outside_a_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir_all(tree.join("shared")).unwrap();
+        fs::write(tree.join("shared/file"), b"x").unwrap();
+        std::os::unix::fs::symlink("shared", tree.join("alias")).unwrap();
+
+        let copy = dir.path().join("copy");
+        CopyJob::new(true, None).copy_entry(&tree, &copy).unwrap();
+        assert_eq!(fs::read(copy.join("alias/file")).unwrap(), b"x");
+        assert_eq!(fs::read(copy.join("shared/file")).unwrap(), b"x");
+    }
+
+    #[test]
+    fn copy_keeps_times_of_unreadable_files_and_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        let locked = tree.join("locked");
+        fs::write(&locked, b"x").unwrap();
+        let then = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_times(&locked, then, then).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        filetime::set_file_times(&tree, then, then).unwrap();
+
+        let copy = dir.path().join("copy");
+        CopyJob::new(false, None).copy_entry(&tree, &copy).unwrap();
+        for path in [copy.clone(), copy.join("locked")] {
+            let meta = fs::symlink_metadata(&path).unwrap();
+            assert_eq!(filetime::FileTime::from_last_modification_time(&meta), then);
+        }
+        assert_eq!(
+            fs::symlink_metadata(copy.join("locked"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0
+        );
+    }
+
+    #[test]
+    fn same_device_only_compares_when_a_root_device_is_set() {
+        assert!(same_device(None, 1));
+        assert!(same_device(Some(7), 7));
+        assert!(!same_device(Some(7), 8));
+    }
+
+    #[test]
+    fn stay_on_filesystem_walks_stop_at_device_boundaries() {
+        // /proc is its own filesystem wherever it is mounted.
+        let (root, proc) = (Path::new("/"), Path::new("/proc"));
+        let root_dev = root_device(root, true);
+        if root_device(proc, true) == root_dev {
+            return;
+        }
+        assert!(!on_root_device(proc, root_dev));
+        let found = search_tree(root, "proc/1/status", 10, root_dev).unwrap();
+        assert!(found.matches.is_empty());
+        let found = search_tree(root, "proc/1/status", 10, None).unwrap();
+        assert_eq!(found.matches.len(), 1);
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/b/file"), b"x").unwrap();
+        let dev = root_device(dir.path(), true);
+        let found = search_tree(dir.path(), "**/file", 10, dev).unwrap();
+        assert_eq!(found.matches.len(), 1);
+        let synced = sync_tree(dir.path(), true, dev).unwrap();
+        assert_eq!(synced.files_synced, 1);
+    }
+
+    #[test]
+    fn cross_device_move_copies_the_tree_then_removes_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        fs::create_dir_all(from.join("sub")).unwrap();
+        fs::write(from.join("sub/file"), b"contents").unwrap();
+        fs::set_permissions(from.join("sub/file"), fs::Permissions::from_mode(0o640)).unwrap();
+        let then = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_times(from.join("sub/file"), then, then).unwrap();
+        std::os::unix::fs::symlink("sub/file", from.join("link")).unwrap();
+
+        // rename(2) never fails with EXDEV inside one temp dir, so call the
+        // fallback directly.
+        let to = dir.path().join("to");
+        let moved = move_across_devices(&from, &to).unwrap();
+        assert!(moved.cross_device);
+        assert_eq!(moved.files_moved, 2);
+        assert!(!from.exists());
+        assert_eq!(fs::read(to.join("sub/file")).unwrap(), b"contents");
+        let meta = fs::metadata(to.join("sub/file")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+        assert_eq!(filetime::FileTime::from_last_modification_time(&meta), then);
+        assert_eq!(
+            fs::read_link(to.join("link")).unwrap(),
+            Path::new("sub/file")
+        );
+    }
+
+    #[test]
+    fn failed_cross_device_move_leaves_the_source_intact() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        fs::create_dir(&from).unwrap();
+        fs::write(from.join("file"), b"contents").unwrap();
+
+        let to = dir.path().join("missing/to");
+        assert!(move_across_devices(&from, &to).is_err());
+        assert_eq!(fs::read(from.join("file")).unwrap(), b"contents");
+        assert!(!to.exists());
+    }
+
+    #[tokio::test]
+    async fn trailing_slashes_do_not_change_get_path_responses() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/file"), b"x").unwrap();
+        fs::write(dir.path().join("file"), b"x").unwrap();
+        let service = LazySyncService::new(test_config(PathPolicy::default()));
+        let encoded = |path: String| {
+            let service = &service;
+            async move {
+                service
+                    .get_path(Request::new(GetPathRequest {
+                        path,
+                        ..Default::default()
+                    }))
                     .await
-                    .map_err(|err| Status::internal(format!("write failed: {}", err)))?;
-                bytes_written += chunk.data.len() as u64;
+                    .unwrap()
+                    .into_inner()
+                    .encode_to_vec()
             }
+        };
+        let plain = dir.path().display().to_string();
+        // The first listing may bump access times; compare the ones after it.
+        encoded(plain.clone()).await;
+
+        let expected = encoded(plain.clone()).await;
+        let response = GetPathResponse::decode(expected.as_slice()).unwrap();
+        let dirs = listed_dirs(response);
+        let unique: HashSet<&String> = dirs.iter().collect();
+        assert_eq!(unique.len(), dirs.len(), "duplicate listings: {:?}", dirs);
+        for path in [format!("{}/", plain), format!("{}//", plain)] {
+            assert!(encoded(path.clone()).await == expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn size_is_unknown_for_symlinks_but_known_for_empty_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty");
+        fs::write(&empty, b"").unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink("empty", &link).unwrap();
+
+        let info = build_file_info(&empty, &fs::symlink_metadata(&empty).unwrap());
+        assert!(info.size_known);
+        assert_eq!(info.size, 0);
+        let info = build_file_info(&link, &fs::symlink_metadata(&link).unwrap());
+        assert_eq!(info.file_type, "symlink");
+        assert!(!info.size_known);
+    }
+
+    #[tokio::test]
+    async fn swap_paths_exchanges_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, b"first").unwrap();
+        fs::create_dir(&b).unwrap();
+        fs::write(b.join("inner"), b"second").unwrap();
+
+        let service = LazySyncService::new(test_config(PathPolicy::default()));
+        service
+            .swap_paths(Request::new(SwapPathsRequest {
+                a: a.display().to_string(),
+                b: b.display().to_string(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(fs::read(a.join("inner")).unwrap(), b"second");
+        assert_eq!(fs::read(&b).unwrap(), b"first");
+        assert_eq!(dir.path().read_dir().unwrap().count(), 2);
+
+        let same = service
+            .swap_paths(Request::new(SwapPathsRequest {
+                a: a.display().to_string(),
+                b: format!("{}/", a.display()),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(same.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn archive_path_streams_with_bounded_buffering() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        for index in 0..4 {
+            fs::write(
+                tree.join(format!("file{}", index)),
+                vec![index as u8; 1 << 20],
+            )
+            .unwrap();
+        }
+
+        let service = LazySyncService::new(test_config(PathPolicy::default()));
+        let mut rx = service
+            .archive_path(Request::new(ArchivePathRequest {
+                path: tree.display().to_string(),
+                buffer_depth: 2,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .into_inner();
 
+        // Nobody is reading yet: the writer must block on the full channel
+        // instead of building the archive in memory.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(rx.len() <= 2);
+        assert_eq!(service.active_streams.load(Ordering::Relaxed), 1);
+
+        let mut archive = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            let chunk = chunk.unwrap();
+            assert_eq!(chunk.offset, archive.len() as u64);
+            archive.extend_from_slice(&chunk.data);
             if chunk.eof {
                 break;
             }
         }
+        assert!(archive.len() > 4 << 20);
+        let mut names: Vec<String> = tar::Archive::new(archive.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "tree/",
+                "tree/file0",
+                "tree/file1",
+                "tree/file2",
+                "tree/file3"
+            ]
+        );
+    }
 
-        Ok(Response::new(WriteFileResponse { bytes_written }))
+    fn upload(
+        path: &Path,
+        data: &[u8],
+        exclusive: bool,
+    ) -> impl tokio_stream::Stream<Item = WriteFileChunk> {
+        tokio_stream::iter(vec![WriteFileChunk {
+            path: path.display().to_string(),
+            data: data.to_vec(),
+            eof: true,
+            exclusive,
+            ..Default::default()
+        }])
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "127.0.0.1:9000".parse()?;
-    println!("gRPC server listening on {}", addr);
-    Server::builder()
-        .add_service(LazySyncServer::new(LazySyncService::default()))
-        .serve(addr)
-        .await?;
-    Ok(())
+    #[tokio::test]
+    async fn reading_or_writing_a_directory_fails_up_front() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = LazySyncService::new(test_config(PathPolicy::default()));
+        let status = service
+            .read_file(Request::new(ReadFileRequest {
+                path: dir.path().display().to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert!(status.message().contains("is a directory"));
+
+        let mut client = connect(LazySyncService::new(test_config(PathPolicy::default()))).await;
+        let status = client
+            .write_file(upload(dir.path(), b"data", false))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert!(status.message().contains("is a directory"));
+        assert!(dir.path().is_dir());
+    }
+
+    #[tokio::test]
+    async fn exclusive_write_fails_when_the_target_exists() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("lock");
+        let mut client = connect(LazySyncService::new(test_config(PathPolicy::default()))).await;
+
+        let written = client
+            .write_file(upload(&target, b"first", true))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(written.bytes_written, 5);
+        let status = client
+            .write_file(upload(&target, b"second", true))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(fs::read(&target).unwrap(), b"first");
+
+        // Without the flag the same write replaces the file.
+        client
+            .write_file(upload(&target, b"second", false))
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"second");
+    }
+
+    #[tokio::test]
+    async fn set_owner_changes_owner_when_running_as_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, b"x").unwrap();
+        let service = LazySyncService::new(test_config(PathPolicy::default()));
+        let request = |uid: i64, gid: i64| {
+            Request::new(SetOwnerRequest {
+                path: file.display().to_string(),
+                uid,
+                gid,
+            })
+        };
+
+        let status = service.set_owner(request(-5, -1)).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        if !matches!(server_identity(), Some((0, _, _))) {
+            return;
+        }
+        let info = service
+            .set_owner(request(1234, 5678))
+            .await
+            .unwrap()
+            .into_inner()
+            .info
+            .unwrap();
+        assert_eq!((info.uid, info.gid), (1234, 5678));
+        // -1 leaves that id alone.
+        let info = service
+            .set_owner(request(4321, -1))
+            .await
+            .unwrap()
+            .into_inner()
+            .info
+            .unwrap();
+        assert_eq!((info.uid, info.gid), (4321, 5678));
+        let meta = fs::metadata(&file).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (4321, 5678));
+    }
+
+    #[tokio::test]
+    async fn create_file_makes_a_sparse_file_without_allocating_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sparse");
+        let service = LazySyncService::new(test_config(PathPolicy::default()));
+        let request = || {
+            Request::new(CreateFileRequest {
+                path: path.display().to_string(),
+                size: 1 << 30,
+                sparse: true,
+                overwrite: false,
+            })
+        };
+
+        let info = service
+            .create_file(request())
+            .await
+            .unwrap()
+            .into_inner()
+            .info
+            .unwrap();
+        assert_eq!(info.size, 1 << 30);
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.len(), 1 << 30);
+        assert!(meta.blocks() * 512 < 1 << 20, "{} blocks", meta.blocks());
+
+        let status = service.create_file(request()).await.unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn sorted_pages_follow_the_global_order() {
+        let dir = tempfile::tempdir().unwrap();
+        // Creation order differs from both name and size order.
+        for index in [
+            7, 3, 22, 0, 15, 9, 18, 1, 24, 12, 5, 20, 2, 16, 11, 8, 23, 4, 19, 13, 6, 21, 10, 17,
+            14,
+        ] {
+            let size = (index * 37) % 25;
+            fs::write(dir.path().join(format!("f{:02}", index)), vec![0u8; size]).unwrap();
+        }
+        let service = LazySyncService::new(test_config(PathPolicy::default()));
+        let page = |sort: &str, descending: bool, offset: u32, skip_sort_over: u32| {
+            let request = Request::new(GetPathRequest {
+                path: dir.path().display().to_string(),
+                sort: sort.to_string(),
+                descending,
+                offset,
+                limit: 10,
+                skip_sort_over,
+                ..Default::default()
+            });
+            let service = &service;
+            let root = dir.path().display().to_string();
+            async move {
+                let response = service.get_path(request).await.unwrap().into_inner();
+                let listing = response
+                    .entries
+                    .into_iter()
+                    .find(|entries| entries.absolute_path == root)
+                    .unwrap();
+                (
+                    listing.entries,
+                    response.total_entries,
+                    response.sort_skipped,
+                )
+            }
+        };
+
+        for (sort, descending) in [("name", false), ("name", true), ("size", false)] {
+            let mut seen = Vec::new();
+            for offset in [0, 10, 20] {
+                let (entries, total, skipped) = page(sort, descending, offset, 0).await;
+                assert_eq!(total, 25);
+                assert!(!skipped);
+                seen.extend(entries);
+            }
+            let mut expected = seen.clone();
+            expected.sort_by(|a, b| match sort {
+                "size" => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+                _ => a.name.cmp(&b.name),
+            });
+            if descending {
+                expected.reverse();
+            }
+            let names = |entries: &[FileInfo]| -> Vec<String> {
+                entries.iter().map(|info| info.name.clone()).collect()
+            };
+            assert_eq!(seen.len(), 25);
+            assert_eq!(
+                names(&seen),
+                names(&expected),
+                "{} descending={}",
+                sort,
+                descending
+            );
+        }
+
+        let (entries, _, skipped) = page("name", false, 0, 5).await;
+        assert!(skipped);
+        assert_eq!(entries.len(), 10);
+    }
+
+    #[test]
+    fn search_globs_support_recursive_and_single_segment_wildcards() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in [
+            "main.rs",
+            "src/lib.rs",
+            "src/a.rs",
+            "src/b.txt",
+            "src/net/tcp.rs",
+            "src/net/deep/udp.rs",
+            "docs/a1.md",
+            "docs/b2.md",
+            "docs/ab.md",
+        ] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let search = |pattern: &str| -> Vec<String> {
+            let found = search_tree(dir.path(), pattern, 100, None).unwrap();
+            assert!(!found.truncated);
+            found
+                .matches
+                .into_iter()
+                .map(|info| {
+                    Path::new(&info.absolute_path)
+                        .strip_prefix(dir.path())
+                        .unwrap()
+                        .display()
+                        .to_string()
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            search("src/**/*.rs"),
+            [
+                "src/a.rs",
+                "src/lib.rs",
+                "src/net/tcp.rs",
+                "src/net/deep/udp.rs"
+            ]
+        );
+        assert_eq!(search("**/udp.rs"), ["src/net/deep/udp.rs"]);
+        // `*` stays within one segment.
+        assert_eq!(search("*.rs"), ["main.rs"]);
+        assert_eq!(search("src/*.rs"), ["src/a.rs", "src/lib.rs"]);
+        assert_eq!(search("src/?.rs"), ["src/a.rs"]);
+        assert_eq!(search("docs/[ab][0-9].md"), ["docs/a1.md", "docs/b2.md"]);
+        assert_eq!(search("docs/[!a]*.md"), ["docs/b2.md"]);
+        assert!(search("nothing/**").is_empty());
+
+        let found = search_tree(dir.path(), "**/*.rs", 2, None).unwrap();
+        assert_eq!(found.matches.len(), 2);
+        assert!(found.truncated);
+    }
+
+    #[tokio::test]
+    async fn client_write_file_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = serve(LazySyncService::new(test_config(PathPolicy::default()))).await;
+        let client = rfb_client::Client::builder()
+            .no_cache()
+            .connect(&addr.to_string())
+            .unwrap();
+
+        // Several chunks, with a short one at the end.
+        let data: Vec<u8> = (0..3 * 64 * 1024 + 123).map(|i| (i % 251) as u8).collect();
+        let path = dir.path().join("upload.bin").display().to_string();
+        assert_eq!(
+            client.write_file(&path, data.as_slice()).await.unwrap(),
+            data.len() as u64
+        );
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert_eq!(client.read_file_to_vec(&path, 0, 0).await.unwrap(), data);
+
+        // Rewriting with shorter data leaves nothing of the old tail.
+        assert_eq!(client.write_file(&path, &b"short"[..]).await.unwrap(), 5);
+        assert_eq!(fs::read(&path).unwrap(), b"short");
+
+        // write_stream at an offset keeps what is already there.
+        assert_eq!(client.write_stream(&path, &b"!"[..], 5).await.unwrap(), 1);
+        assert_eq!(fs::read(&path).unwrap(), b"short!");
+
+        // Upload-queue jobs rewrite the whole file too.
+        let local = dir.path().join("local.txt");
+        fs::write(&local, b"tiny").unwrap();
+        client.enqueue_upload(&local, &path);
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while client.upload_status().completed == 0 {
+            assert!(client.upload_status().failed.is_empty());
+            assert!(
+                std::time::Instant::now() < deadline,
+                "upload never finished"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"tiny");
+
+        let empty = dir.path().join("empty").display().to_string();
+        assert_eq!(client.write_file(&empty, &b""[..]).await.unwrap(), 0);
+        assert_eq!(fs::metadata(&empty).unwrap().len(), 0);
+        client.close();
+    }
+
+    #[tokio::test]
+    async fn pushed_invalidations_make_get_path_fetch_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let addr = serve(LazySyncService::new(test_config(PathPolicy::default()))).await;
+        let client = rfb_client::ClientBuilder::new()
+            .cache_dir(cache.path())
+            .connect_grpc(&addr.to_string())
+            .unwrap();
+        let mut writer = LazySyncClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let root = dir.path().display().to_string();
+        let names = |entries: Vec<rfb_client::FileEntry>| -> Vec<String> {
+            let mut names: Vec<String> = entries.into_iter().map(|entry| entry.name).collect();
+            names.sort();
+            names
+        };
+
+        fs::write(dir.path().join("a"), b"a").unwrap();
+        assert_eq!(names(client.get_path(&root).await.unwrap()), ["a"]);
+
+        // Unsubscribed, the cached listing is served as is.
+        let b = dir.path().join("b");
+        writer.write_file(upload(&b, b"b", false)).await.unwrap();
+        assert_eq!(names(client.get_path(&root).await.unwrap()), ["a"]);
+
+        let subscription = client.subscribe_invalidations(&root).await.unwrap();
+        let cached = client.cache_len();
+        let c = dir.path().join("c");
+        writer.write_file(upload(&c, b"c", false)).await.unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while client.cache_len() == cached {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "no invalidation arrived"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(
+            names(client.get_path(&root).await.unwrap()),
+            ["a", "b", "c"]
+        );
+
+        subscription.abort();
+        client.close();
+    }
+
+    fn listed_paths(path: &str) -> Vec<String> {
+        let filter = ListFilter::from_request(&GetPathRequest::default(), false);
+        let mut state = ListState::new(DEFAULT_MAX_RESPONSE, None);
+        build_entries_for_path(path, &filter, None, &mut state)
+            .into_iter()
+            .map(|dir| dir.absolute_path)
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn normalize_request_path_ignores_trailing_slashes(
+            path in "/?([a-z.]{1,3}/{1,3}){0,4}[a-z.]{0,3}",
+            slashes in "/{1,3}",
+        ) {
+            let once = normalize_request_path(&path);
+            prop_assert_eq!(normalize_request_path(&once), once.clone());
+            prop_assume!(!path.is_empty());
+            prop_assert_eq!(normalize_request_path(&format!("{}{}", path, slashes)), once);
+        }
+
+        #[test]
+        fn validate_request_path_ignores_trailing_slashes(
+            path in "[~$]?/?([a-z.]{1,3}/{1,2}){0,3}[a-z]{0,3}",
+            slashes in "/{1,3}",
+            sandbox: bool,
+        ) {
+            prop_assume!(!path.is_empty());
+            let policy = PathPolicy { sandbox, expand: false };
+            let plain = validate_request_path(&path, policy).map_err(|status| status.code());
+            let slashed = validate_request_path(&format!("{}{}", path, slashes), policy)
+                .map_err(|status| status.code());
+            prop_assert_eq!(plain, slashed);
+        }
+    }
+
+    #[test]
+    fn listings_ignore_trailing_slashes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+        fs::write(dir.path().join("a/b/file"), b"x").unwrap();
+        let root = dir.path().display().to_string();
+
+        proptest!(|(parts in proptest::collection::vec("[abc]|\\.", 0..4), slashes in "/{0,3}")| {
+            let path = format!("{}/{}", root, parts.join("/"));
+            let requested = format!("{}{}", path, slashes);
+            let validated = validate_request_path(&requested, PathPolicy::default()).unwrap();
+            let validated = validated.to_string_lossy();
+            prop_assert_eq!(listed_paths(&validated), listed_paths(&normalize_request_path(&path)));
+            // The requested directory is reported exactly as the client will look it up.
+            if Path::new(validated.as_ref()).is_dir() {
+                prop_assert!(
+                    listed_paths(&validated).iter().any(|listed| *listed == validated),
+                    "{} missing from {:?}", validated, listed_paths(&validated)
+                );
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn get_path_within_reports_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..200 {
+            fs::write(dir.path().join(format!("file-{:03}", i)), b"").unwrap();
+        }
+        let config = ServerConfig {
+            max_response: 4096,
+            ..test_config(PathPolicy::default())
+        };
+        let addr = serve(LazySyncService::new(config)).await;
+        let client = rfb_client::Client::builder()
+            .no_cache()
+            .connect(&addr.to_string())
+            .unwrap();
+
+        let path = dir.path().display().to_string();
+        let listing = client
+            .get_path_within(&path, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(listing.truncated);
+        assert!(!listing.timed_out);
+        assert!(listing.entries.len() < 200);
+        client.close();
+    }
+
+    #[test]
+    fn listing_fingerprint_changes_within_the_same_second() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("log");
+        let second = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+        let token = || {
+            let path = dir.path().display().to_string();
+            let listing = read_dir(
+                dir.path(),
+                &ListFilter::default(),
+                None,
+                &mut ListState::new(DEFAULT_MAX_RESPONSE, None),
+            )
+            .unwrap();
+            assert_eq!(listing.absolute_path, path);
+            listing_fingerprint(&[listing])
+        };
+
+        fs::write(&file, b"one").unwrap();
+        filetime::set_file_mtime(&file, second).unwrap();
+        let before = token();
+        assert_eq!(token(), before);
+
+        // An append in the same second: only the size tells the two apart.
+        fs::write(&file, b"one two").unwrap();
+        filetime::set_file_mtime(&file, second).unwrap();
+        let appended = token();
+        assert_ne!(appended, before);
+
+        // A same-size rewrite later in that second: only the nanoseconds differ.
+        fs::write(&file, b"ONE TWO").unwrap();
+        filetime::set_file_mtime(
+            &file,
+            filetime::FileTime::from_unix_time(1_700_000_000, 500),
+        )
+        .unwrap();
+        assert_ne!(token(), appended);
+    }
+
+    #[test]
+    fn format_mode_renders_every_special_bit_combination() {
+        let cases = [
+            (0o0644, "-rw-r--r--"),
+            (0o0755, "-rwxr-xr-x"),
+            (0o4644, "-rwSr--r--"),
+            (0o4755, "-rwsr-xr-x"),
+            (0o2644, "-rw-r-Sr--"),
+            (0o2755, "-rwxr-sr-x"),
+            (0o1644, "-rw-r--r-T"),
+            (0o1755, "-rwxr-xr-t"),
+            (0o6644, "-rwSr-Sr--"),
+            (0o6755, "-rwsr-sr-x"),
+            (0o5644, "-rwSr--r-T"),
+            (0o5755, "-rwsr-xr-t"),
+            (0o3644, "-rw-r-Sr-T"),
+            (0o3755, "-rwxr-sr-t"),
+            (0o7644, "-rwSr-Sr-T"),
+            (0o7755, "-rwsr-sr-t"),
+            (0o7000, "---S--S--T"),
+            (0o7111, "---s--s--t"),
+        ];
+        for (mode, expected) in cases {
+            assert_eq!(format_mode(mode, '-'), expected, "{:o}", mode);
+        }
+        assert_eq!(format_mode(0o1777, 'd'), "drwxrwxrwt");
+    }
+
+    #[test]
+    fn file_info_reports_special_bits() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"").unwrap();
+        for special in [0, 0o1000, 0o2000, 0o4000, 0o3000, 0o5000, 0o6000, 0o7000] {
+            for exec in [0o644, 0o755] {
+                let mode = special | exec;
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+                let info = build_file_info(&path, &fs::symlink_metadata(&path).unwrap());
+                assert_eq!(info.permissions, format_mode(mode, '-'), "{:o}", mode);
+                assert_eq!(
+                    (info.setuid, info.setgid, info.sticky),
+                    (
+                        special & 0o4000 != 0,
+                        special & 0o2000 != 0,
+                        special & 0o1000 != 0
+                    ),
+                    "{:o}",
+                    mode
+                );
+            }
+        }
+    }
 }